@@ -0,0 +1,555 @@
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+    response::Json as ResponseJson,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    auth_user::FirstPartyUser,
+    clients::{
+        create_client as register, validate_backchannel_logout_uri, validate_redirect_uris, ClientRegistrationRequest,
+        ClientRegistrationResponse,
+    },
+    crypto::generate_token,
+    database::Database,
+    deletion::{erase_user, schedule_deletion},
+    error::AppError,
+    models::{OAuthClient, User},
+    oauth::{revoke_client_tokens, revoke_user_tokens},
+    profile::nullable,
+    roles::{is_known_role, Permission, ROLE_ADMIN},
+    sessions::{active_sessions, revoke_all_sessions, revoke_session, SessionResponse},
+    AppState,
+};
+
+// Account and client administration for operators. Every handler requires
+// a first-party token and checks the caller's permissions.
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct UserSearchQuery {
+    pub q: Option<String>, // Matches username, email or exact id
+    pub role: Option<String>,
+    pub is_active: Option<bool>,
+    #[serde(default)]
+    pub offset: i64,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminUserResponse {
+    pub id: String,
+    pub username: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub did: Option<String>,
+    pub role: String,
+    pub permissions: &'static [Permission],
+    pub is_active: bool,
+    pub deletion_scheduled_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<User> for AdminUserResponse {
+    fn from(user: User) -> Self {
+        AdminUserResponse {
+            permissions: user.permissions(),
+            id: user.id,
+            username: user.username,
+            email: user.email,
+            email_verified: user.email_verified_at.is_some(),
+            did: user.did,
+            role: user.role,
+            is_active: user.is_active,
+            deletion_scheduled_at: user.deletion_scheduled_at,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserPage {
+    pub users: Vec<AdminUserResponse>,
+    pub total: i64,
+    pub offset: i64,
+    pub limit: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteUserRequest {
+    #[serde(default)]
+    pub immediate: bool, // Skip the grace period, e.g. for an erasure request
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoleRequest {
+    pub role: String,
+}
+
+// Client secrets are only shown when created or rotated
+#[derive(Debug, Serialize)]
+pub struct AdminClientResponse {
+    pub id: String,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub scopes: String,
+    pub is_active: bool,
+    pub application_type: String,
+    pub token_endpoint_auth_method: String,
+    pub access_token_format: String,
+    pub access_token_lifetime_seconds: Option<i64>,
+    pub id_token_lifetime_seconds: Option<i64>,
+    pub refresh_token_idle_seconds: Option<i64>,
+    pub refresh_token_absolute_seconds: Option<i64>,
+    pub backchannel_logout_uri: Option<String>,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+
+impl TryFrom<OAuthClient> for AdminClientResponse {
+    type Error = AppError;
+
+    fn try_from(client: OAuthClient) -> Result<Self, Self::Error> {
+        let redirect_uris = serde_json::from_str(&client.redirect_uris)
+            .map_err(|_| AppError::Internal("Invalid redirect URIs format".to_string()))?;
+
+        Ok(AdminClientResponse {
+            id: client.id,
+            name: client.name,
+            redirect_uris,
+            scopes: client.scopes,
+            is_active: client.is_active,
+            application_type: client.application_type,
+            token_endpoint_auth_method: client.token_endpoint_auth_method,
+            access_token_format: client.access_token_format,
+            access_token_lifetime_seconds: client.access_token_lifetime_seconds,
+            id_token_lifetime_seconds: client.id_token_lifetime_seconds,
+            refresh_token_idle_seconds: client.refresh_token_idle_seconds,
+            refresh_token_absolute_seconds: client.refresh_token_absolute_seconds,
+            backchannel_logout_uri: client.backchannel_logout_uri,
+            created_at: client.created_at,
+            client_secret: None,
+        })
+    }
+}
+
+// Fields left out are unchanged
+#[derive(Debug, Deserialize)]
+pub struct ClientUpdateRequest {
+    pub name: Option<String>,
+    pub redirect_uris: Option<Vec<String>>,
+    pub scope: Option<String>,
+    pub is_active: Option<bool>,
+    pub access_token_format: Option<String>,
+    pub access_token_lifetime_seconds: Option<i64>,
+    pub id_token_lifetime_seconds: Option<i64>,
+    pub refresh_token_idle_seconds: Option<i64>,
+    pub refresh_token_absolute_seconds: Option<i64>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub backchannel_logout_uri: Option<Option<String>>, // null removes it
+}
+
+// Give the configured operators the admin role, so the first admin does not
+// have to be created with SQL. Only verified addresses count, or whoever
+// registered a listed address first would become admin.
+pub async fn grant_configured_admins(database: &Database, emails: &[String]) -> Result<(), AppError> {
+    for email in emails {
+        let result = sqlx::query(
+            "UPDATE users SET role = ?, updated_at = ? WHERE LOWER(email) = ? AND role != ? AND email_verified_at IS NOT NULL"
+        )
+        .bind(ROLE_ADMIN)
+        .bind(Utc::now())
+        .bind(email)
+        .bind(ROLE_ADMIN)
+        .execute(database.pool())
+        .await?;
+
+        if result.rows_affected() > 0 {
+            info!("Granted the admin role to {}", email);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn list_users(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Query(query): Query<UserSearchQuery>,
+) -> Result<ResponseJson<UserPage>, AppError> {
+    auth.require_permission(Permission::ViewUsers)?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let offset = query.offset.max(0);
+    let search = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let pattern = search.map(|q| {
+        let escaped = q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+        format!("%{}%", escaped)
+    });
+
+    // Unset filters match everything
+    const FILTER: &str = r#"
+        WHERE (? IS NULL OR username LIKE ? ESCAPE '\' OR email LIKE ? ESCAPE '\' OR id = ?)
+          AND (? IS NULL OR role = ?)
+          AND (? IS NULL OR is_active = ?)
+    "#;
+
+    let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM users {}", FILTER))
+        .bind(&pattern)
+        .bind(&pattern)
+        .bind(&pattern)
+        .bind(search)
+        .bind(&query.role)
+        .bind(&query.role)
+        .bind(query.is_active)
+        .bind(query.is_active)
+        .fetch_one(state.database.pool())
+        .await?;
+
+    let users = sqlx::query_as::<_, User>(&format!(
+        "SELECT * FROM users {} ORDER BY created_at DESC LIMIT ? OFFSET ?",
+        FILTER
+    ))
+    .bind(&pattern)
+    .bind(&pattern)
+    .bind(&pattern)
+    .bind(search)
+    .bind(&query.role)
+    .bind(&query.role)
+    .bind(query.is_active)
+    .bind(query.is_active)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(state.database.pool())
+    .await?;
+
+    Ok(ResponseJson(UserPage {
+        users: users.into_iter().map(Into::into).collect(),
+        total,
+        offset,
+        limit,
+    }))
+}
+
+pub async fn get_user(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+) -> Result<ResponseJson<AdminUserResponse>, AppError> {
+    auth.require_permission(Permission::ViewUsers)?;
+
+    let user = find_user(&state, &id).await?;
+    Ok(ResponseJson(user.into()))
+}
+
+// A disabled account cannot sign in, and everything it was signed in to ends
+pub async fn disable_user(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+) -> Result<ResponseJson<AdminUserResponse>, AppError> {
+    auth.require_permission(Permission::ManageUsers)?;
+    if id == auth.user.id {
+        return Err(AppError::Validation("You cannot disable your own account".to_string()));
+    }
+
+    let user = set_active(&state, &id, false).await?;
+    revoke_all_sessions(&state, &user.id).await?;
+    revoke_user_tokens(&state, &user.id).await?;
+
+    info!("User {} disabled by {}", user.id, auth.user.id);
+    Ok(ResponseJson(user.into()))
+}
+
+pub async fn enable_user(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+) -> Result<ResponseJson<AdminUserResponse>, AppError> {
+    auth.require_permission(Permission::ManageUsers)?;
+
+    let user = set_active(&state, &id, true).await?;
+
+    info!("User {} enabled by {}", user.id, auth.user.id);
+    Ok(ResponseJson(user.into()))
+}
+
+// Same as the user deleting their account, or erasure right away
+pub async fn delete_user(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+    payload: Option<Json<DeleteUserRequest>>,
+) -> Result<StatusCode, AppError> {
+    auth.require_permission(Permission::ManageUsers)?;
+    if id == auth.user.id {
+        return Err(AppError::Validation("Delete your own account from your profile".to_string()));
+    }
+
+    let user = find_user(&state, &id).await?;
+    if payload.is_some_and(|Json(payload)| payload.immediate) {
+        erase_user(&state, &user.id).await?;
+        info!("User {} erased by {}", user.id, auth.user.id);
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    schedule_deletion(&state, &user).await?;
+    info!("User {} scheduled for deletion by {}", user.id, auth.user.id);
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn set_role(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+    Json(payload): Json<RoleRequest>,
+) -> Result<ResponseJson<AdminUserResponse>, AppError> {
+    auth.require_permission(Permission::ManageRoles)?;
+    if !is_known_role(&payload.role) {
+        return Err(AppError::Validation("Unknown role".to_string()));
+    }
+    // Keeps the last admin from locking everyone out
+    if id == auth.user.id {
+        return Err(AppError::Validation("You cannot change your own role".to_string()));
+    }
+
+    let user = sqlx::query_as::<_, User>("UPDATE users SET role = ?, updated_at = ? WHERE id = ? RETURNING *")
+        .bind(&payload.role)
+        .bind(Utc::now())
+        .bind(&id)
+        .fetch_optional(state.database.pool())
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    info!("User {} given the {} role by {}", user.id, user.role, auth.user.id);
+    Ok(ResponseJson(user.into()))
+}
+
+pub async fn list_user_sessions(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+) -> Result<ResponseJson<Vec<SessionResponse>>, AppError> {
+    auth.require_permission(Permission::ViewSessions)?;
+
+    let user = find_user(&state, &id).await?;
+    let sessions = active_sessions(&state, &user.id)
+        .await?
+        .into_iter()
+        .map(|session| SessionResponse::new(session, false))
+        .collect();
+
+    Ok(ResponseJson(sessions))
+}
+
+pub async fn delete_user_session(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path((id, session_id)): Path<(String, String)>,
+) -> Result<ResponseJson<serde_json::Value>, AppError> {
+    auth.require_permission(Permission::RevokeSessions)?;
+
+    if !revoke_session(&state, &id, &session_id).await? {
+        return Err(AppError::NotFound("Session not found".to_string()));
+    }
+
+    info!("Session {} of user {} revoked by {}", session_id, id, auth.user.id);
+    Ok(ResponseJson(serde_json::json!({ "revoked": 1 })))
+}
+
+pub async fn delete_user_sessions(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+) -> Result<ResponseJson<serde_json::Value>, AppError> {
+    auth.require_permission(Permission::RevokeSessions)?;
+
+    let user = find_user(&state, &id).await?;
+    let revoked = revoke_all_sessions(&state, &user.id).await?;
+
+    info!("All sessions of user {} revoked by {}", user.id, auth.user.id);
+    Ok(ResponseJson(serde_json::json!({ "revoked": revoked })))
+}
+
+pub async fn list_clients(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+) -> Result<ResponseJson<Vec<AdminClientResponse>>, AppError> {
+    auth.require_permission(Permission::ManageClients)?;
+
+    let clients = sqlx::query_as::<_, OAuthClient>("SELECT * FROM oauth_clients ORDER BY created_at DESC")
+        .fetch_all(state.database.pool())
+        .await?
+        .into_iter()
+        .map(AdminClientResponse::try_from)
+        .collect::<Result<_, _>>()?;
+
+    Ok(ResponseJson(clients))
+}
+
+pub async fn get_client(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+) -> Result<ResponseJson<AdminClientResponse>, AppError> {
+    auth.require_permission(Permission::ManageClients)?;
+
+    let client = find_client(&state, &id).await?;
+    Ok(ResponseJson(client.try_into()?))
+}
+
+// Same request as dynamic registration, available even when that is disabled
+pub async fn create_client(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Json(payload): Json<ClientRegistrationRequest>,
+) -> Result<(StatusCode, ResponseJson<ClientRegistrationResponse>), AppError> {
+    auth.require_permission(Permission::ManageClients)?;
+
+    let response = register(&state, payload).await?;
+
+    info!("Client {} created by {}", response.client_id, auth.user.id);
+    Ok((StatusCode::CREATED, ResponseJson(response)))
+}
+
+pub async fn update_client(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+    Json(payload): Json<ClientUpdateRequest>,
+) -> Result<ResponseJson<AdminClientResponse>, AppError> {
+    auth.require_permission(Permission::ManageClients)?;
+
+    let client = find_client(&state, &id).await?;
+
+    if payload.name.as_deref().is_some_and(|name| name.trim().is_empty()) {
+        return Err(AppError::Validation("name cannot be empty".to_string()));
+    }
+    let redirect_uris = match &payload.redirect_uris {
+        Some(redirect_uris) => {
+            validate_redirect_uris(&client.application_type, redirect_uris)?;
+            Some(
+                serde_json::to_string(redirect_uris)
+                    .map_err(|e| AppError::Internal(format!("Redirect URI serialization failed: {}", e)))?,
+            )
+        }
+        None => None,
+    };
+    if payload
+        .access_token_format
+        .as_deref()
+        .is_some_and(|format| !matches!(format, "jwt" | "opaque"))
+    {
+        return Err(AppError::Validation("access_token_format must be jwt or opaque".to_string()));
+    }
+    let lifetimes = [
+        payload.access_token_lifetime_seconds,
+        payload.id_token_lifetime_seconds,
+        payload.refresh_token_idle_seconds,
+        payload.refresh_token_absolute_seconds,
+    ];
+    if lifetimes.iter().flatten().any(|&seconds| seconds <= 0) {
+        return Err(AppError::Validation("Token lifetimes must be positive".to_string()));
+    }
+    if let Some(Some(uri)) = &payload.backchannel_logout_uri {
+        validate_backchannel_logout_uri(uri)?;
+    }
+
+    let client = sqlx::query_as::<_, OAuthClient>(
+        r#"
+        UPDATE oauth_clients
+        SET name = COALESCE(?, name),
+            redirect_uris = COALESCE(?, redirect_uris),
+            scopes = COALESCE(?, scopes),
+            is_active = COALESCE(?, is_active),
+            access_token_format = COALESCE(?, access_token_format),
+            access_token_lifetime_seconds = COALESCE(?, access_token_lifetime_seconds),
+            id_token_lifetime_seconds = COALESCE(?, id_token_lifetime_seconds),
+            refresh_token_idle_seconds = COALESCE(?, refresh_token_idle_seconds),
+            refresh_token_absolute_seconds = COALESCE(?, refresh_token_absolute_seconds),
+            backchannel_logout_uri = CASE WHEN ? THEN ? ELSE backchannel_logout_uri END
+        WHERE id = ?
+        RETURNING *
+        "#
+    )
+    .bind(&payload.name)
+    .bind(&redirect_uris)
+    .bind(&payload.scope)
+    .bind(payload.is_active)
+    .bind(&payload.access_token_format)
+    .bind(payload.access_token_lifetime_seconds)
+    .bind(payload.id_token_lifetime_seconds)
+    .bind(payload.refresh_token_idle_seconds)
+    .bind(payload.refresh_token_absolute_seconds)
+    .bind(payload.backchannel_logout_uri.is_some())
+    .bind(payload.backchannel_logout_uri.flatten())
+    .bind(&client.id)
+    .fetch_one(state.database.pool())
+    .await?;
+
+    if !client.is_active {
+        revoke_client_tokens(&state, &client.id).await?;
+    }
+
+    info!("Client {} updated by {}", client.id, auth.user.id);
+    Ok(ResponseJson(client.try_into()?))
+}
+
+// The old secret stops working immediately
+pub async fn rotate_client_secret(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+) -> Result<ResponseJson<AdminClientResponse>, AppError> {
+    auth.require_permission(Permission::ManageClients)?;
+
+    let client = find_client(&state, &id).await?;
+    if client.is_public() {
+        return Err(AppError::Validation("Public clients have no secret".to_string()));
+    }
+
+    let client_secret = generate_token();
+    sqlx::query("UPDATE oauth_clients SET client_secret = ? WHERE id = ?")
+        .bind(&client_secret)
+        .bind(&client.id)
+        .execute(state.database.pool())
+        .await?;
+
+    info!("Secret of client {} rotated by {}", client.id, auth.user.id);
+    let mut response = AdminClientResponse::try_from(client)?;
+    response.client_secret = Some(client_secret);
+    Ok(ResponseJson(response))
+}
+
+async fn find_user(state: &AppState, id: &str) -> Result<User, AppError> {
+    sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_optional(state.database.pool())
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))
+}
+
+async fn find_client(state: &AppState, id: &str) -> Result<OAuthClient, AppError> {
+    sqlx::query_as::<_, OAuthClient>("SELECT * FROM oauth_clients WHERE id = ?")
+        .bind(id)
+        .fetch_optional(state.database.pool())
+        .await?
+        .ok_or_else(|| AppError::NotFound("Client not found".to_string()))
+}
+
+async fn set_active(state: &AppState, id: &str, is_active: bool) -> Result<User, AppError> {
+    sqlx::query_as::<_, User>("UPDATE users SET is_active = ?, updated_at = ? WHERE id = ? RETURNING *")
+        .bind(is_active)
+        .bind(Utc::now())
+        .bind(id)
+        .fetch_optional(state.database.pool())
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))
+}
@@ -1,20 +1,31 @@
 use axum::{
-    extract::{Json, State},
-    http::StatusCode,
+    extract::{ConnectInfo, Json, State},
+    http::{HeaderMap, StatusCode},
     response::Json as ResponseJson,
 };
-use bcrypt::{hash, verify, DEFAULT_COST};
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tracing::{info, warn};
 use uuid::Uuid;
-use chrono::Utc;
+use chrono::{Duration, Utc};
 
 use crate::{
+    auth_user::FirstPartyUser,
+    crypto::{generate_token, hash_token},
     error::AppError,
-    jwt::{create_access_token, create_refresh_token},
-    models::{CreateUserRequest, LoginRequest, LoginResponse, User, UserResponse},
+    jwt::create_access_token,
+    lockout::LoginAttempt,
+    mailer::{self, Email},
+    mfa,
+    models::{CreateUserRequest, LoginOutcome, LoginRequest, LoginResponse, User, UserResponse},
+    oauth::find_active_user,
+    sessions::{create_session, revoke_session, rotate_session, SessionMetadata},
+    verification::send_verification_email,
     AppState,
 };
 
+const MAGIC_LINK_EXPIRY_MINUTES: i64 = 15;
+
 pub async fn register(
     State(state): State<AppState>,
     Json(payload): Json<CreateUserRequest>,
@@ -24,9 +35,7 @@ pub async fn register(
         return Err(AppError::Validation("All fields are required".to_string()));
     }
 
-    if payload.password.len() < 8 {
-        return Err(AppError::Validation("Password must be at least 8 characters".to_string()));
-    }
+    validate_password(&payload.password)?;
 
     // Check if user already exists
     let existing_user = sqlx::query_as::<_, User>(
@@ -42,8 +51,7 @@ pub async fn register(
     }
 
     // Hash password
-    let password_hash = hash(payload.password, DEFAULT_COST)
-        .map_err(|e| AppError::Internal(format!("Password hashing failed: {}", e)))?;
+    let password_hash = state.password_hasher.hash(&payload.password).await?;
 
     // Create user
     let user_id = Uuid::new_v4().to_string();
@@ -59,8 +67,8 @@ pub async fn register(
     .bind(&payload.username)
     .bind(&payload.email)
     .bind(&password_hash)
-    .bind(&now)
-    .bind(&now)
+    .bind(now)
+    .bind(now)
     .execute(state.database.pool())
     .await?;
 
@@ -70,45 +78,301 @@ pub async fn register(
         .fetch_one(state.database.pool())
         .await?;
 
+    // The account stays unverified until the link is opened. A failed send
+    // does not fail registration, the user can ask for another email.
+    if let Err(e) = send_verification_email(&state, &user).await {
+        warn!("Verification email for user {} failed: {}", user.id, e);
+    }
+
     Ok(ResponseJson(user.into()))
 }
 
+pub fn validate_password(password: &str) -> Result<(), AppError> {
+    if password.len() < 8 {
+        return Err(AppError::Validation("Password must be at least 8 characters".to_string()));
+    }
+    // Bounds the work a single request can cause
+    if password.len() > 1024 {
+        return Err(AppError::Validation("Password must be at most 1024 characters".to_string()));
+    }
+    Ok(())
+}
+
 pub async fn login(
     State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(payload): Json<LoginRequest>,
-) -> Result<ResponseJson<LoginResponse>, AppError> {
+) -> Result<ResponseJson<LoginOutcome>, AppError> {
+    let mut attempt = LoginAttempt::new(&payload.email, addr.ip());
+    attempt.begin(&state).await?;
+
     // Find user by email
     let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
         .bind(&payload.email)
         .fetch_optional(state.database.pool())
-        .await?
-        .ok_or_else(|| AppError::Authentication("Invalid credentials".to_string()))?;
+        .await?;
+
+    // Verify password. Unknown emails, and OPAQUE accounts which have no
+    // password hash, are checked against a dummy hash so they take as long
+    // as wrong passwords.
+    let verification = match user.as_ref().filter(|user| !user.password_hash.is_empty()) {
+        Some(user) => Some(state.password_hasher.verify(&payload.password, &user.password_hash).await?),
+        None => {
+            state.password_hasher.verify_dummy(&payload.password).await?;
+            None
+        }
+    };
+    let (user, verification) = match (user, verification) {
+        (Some(user), Some(verification)) if verification.valid => (user, verification),
+        (user, _) => {
+            attempt.record_failure(&state, user.as_ref()).await?;
+            return Err(AppError::Authentication("Invalid credentials".to_string()));
+        }
+    };
+    attempt.record_success(&state).await?;
+
+    // Upgrade hashes made under an older policy while the password is at hand
+    if verification.needs_rehash {
+        let password_hash = state.password_hasher.hash(&payload.password).await?;
+        sqlx::query("UPDATE users SET password_hash = ? WHERE id = ?")
+            .bind(&password_hash)
+            .bind(&user.id)
+            .execute(state.database.pool())
+            .await?;
+    }
+
+    let metadata = SessionMetadata::from_request(&headers, addr, payload.device_name);
+    let outcome = finish_first_factor(&state, user, &metadata).await?;
+
+    Ok(ResponseJson(outcome))
+}
 
-    // Verify password
-    let is_valid = verify(&payload.password, &user.password_hash)
-        .map_err(|e| AppError::Internal(format!("Password verification failed: {}", e)))?;
+// Continue a login whose first factor (password, magic link or OPAQUE) checked out
+pub async fn finish_first_factor(
+    state: &AppState,
+    user: User,
+    metadata: &SessionMetadata,
+) -> Result<LoginOutcome, AppError> {
+    check_can_sign_in(&user)?;
 
-    if !is_valid {
-        return Err(AppError::Authentication("Invalid credentials".to_string()));
+    // Users with a second factor get an intermediate token instead of a session
+    let second_factors = mfa::second_factors(state, &user.id).await?;
+    if !second_factors.is_empty() {
+        let response = mfa::mfa_required(state, &user, second_factors)?;
+        return Ok(LoginOutcome::MfaRequired(response));
     }
 
+    let response = complete_login(state, user, metadata).await?;
+    Ok(LoginOutcome::Session(response))
+}
+
+// Account state required by every way of signing in, including passkeys
+pub fn check_can_sign_in(user: &User) -> Result<(), AppError> {
     if !user.is_active {
         return Err(AppError::Authentication("Account is disabled".to_string()));
     }
 
-    // Create tokens
-    let access_token = create_access_token(&user.id, &state.config.jwt_secret, state.config.token_expiration_minutes)?;
-    let refresh_token = create_refresh_token(&user.id, &state.config.jwt_secret)?;
+    // Deletion is cancelled through the emailed link, not by signing in
+    if user.deletion_scheduled_at.is_some() {
+        return Err(AppError::Authentication("Account is scheduled for deletion".to_string()));
+    }
+
+    if user.email_verified_at.is_none() {
+        return Err(AppError::Authorization("Email address is not verified".to_string()));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MagicLinkRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MagicLinkResponse {
+    // Kept by the browser that asked for the link and sent back with the
+    // token; a link opened anywhere else does not sign in
+    pub binding: String,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MagicLinkLoginRequest {
+    pub token: String,
+    pub binding: String,
+    pub device_name: Option<String>,
+}
+
+// Always accepted with a fresh binding, so the response does not reveal
+// whether the address belongs to an account
+pub async fn request_magic_link(
+    State(state): State<AppState>,
+    Json(payload): Json<MagicLinkRequest>,
+) -> Result<(StatusCode, ResponseJson<MagicLinkResponse>), AppError> {
+    if !state.config.magic_link_login_enabled {
+        return Err(AppError::NotFound("Magic link login is disabled".to_string()));
+    }
+
+    let binding = generate_token();
+    let response = MagicLinkResponse {
+        binding: binding.clone(),
+        expires_in: (MAGIC_LINK_EXPIRY_MINUTES * 60) as u64,
+    };
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+        .bind(&payload.email)
+        .fetch_optional(state.database.pool())
+        .await?
+        .filter(|user| user.is_active && user.deletion_scheduled_at.is_none());
+
+    // Everything past the lookup runs after the response, so an existing
+    // account takes no longer to answer than an unknown address
+    if let Some(user) = user {
+        tokio::spawn(async move {
+            if let Err(e) = send_magic_link(&state, &user, &binding).await {
+                warn!("Magic link email for user {} failed: {}", user.id, e);
+            }
+        });
+    }
+
+    Ok((StatusCode::ACCEPTED, ResponseJson(response)))
+}
+
+async fn send_magic_link(state: &AppState, user: &User, binding: &str) -> Result<(), AppError> {
+    if mailer::is_throttled(state, "magic_link_tokens", &user.id).await? {
+        info!("Magic link email for user {} throttled", user.id);
+        return Ok(());
+    }
+
+    let token = generate_token();
+    let now = Utc::now();
+
+    sqlx::query(
+        r#"
+        INSERT INTO magic_link_tokens (token_hash, user_id, binding_hash, expires_at, created_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(hash_token(&token))
+    .bind(&user.id)
+    .bind(hash_token(binding))
+    .bind(now + Duration::minutes(MAGIC_LINK_EXPIRY_MINUTES))
+    .bind(now)
+    .execute(state.database.pool())
+    .await?;
+
+    let link = format!("{}/magic-link?token={}", state.config.frontend_url, token);
+    state
+        .mailer
+        .send(Email {
+            to: user.email.clone(),
+            subject: "Your sign-in link".to_string(),
+            body: format!(
+                "Hello {},\n\nSign in by opening this link in the browser where you asked for it:\n\n{}\n\n\
+                 The link expires in {} minutes and works once. If you did not ask for this, ignore this email.\n",
+                user.username, link, MAGIC_LINK_EXPIRY_MINUTES
+            ),
+        })
+        .await
+}
+
+pub async fn magic_link_login(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<MagicLinkLoginRequest>,
+) -> Result<ResponseJson<LoginOutcome>, AppError> {
+    if !state.config.magic_link_login_enabled {
+        return Err(AppError::NotFound("Magic link login is disabled".to_string()));
+    }
+
+    let token_hash = hash_token(&payload.token);
+    let now = Utc::now();
+
+    // Claim the token first, so it cannot be used twice concurrently. A link
+    // opened in another browser is left unclaimed for the right one.
+    let result = sqlx::query(
+        r#"
+        UPDATE magic_link_tokens SET used_at = ?
+        WHERE token_hash = ? AND binding_hash = ? AND used_at IS NULL AND expires_at > ?
+        "#
+    )
+    .bind(now)
+    .bind(&token_hash)
+    .bind(hash_token(&payload.binding))
+    .bind(now)
+    .execute(state.database.pool())
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::Authentication("Invalid or expired sign-in link".to_string()));
+    }
+
+    let (user_id,): (String,) = sqlx::query_as("SELECT user_id FROM magic_link_tokens WHERE token_hash = ?")
+        .bind(&token_hash)
+        .fetch_one(state.database.pool())
+        .await?;
+
+    // Following the emailed link also proves control of the address
+    sqlx::query(
+        r#"
+        UPDATE users
+        SET updated_at = CASE WHEN email_verified_at IS NULL THEN ? ELSE updated_at END,
+            email_verified_at = COALESCE(email_verified_at, ?)
+        WHERE id = ?
+        "#
+    )
+    .bind(now)
+    .bind(now)
+    .bind(&user_id)
+    .execute(state.database.pool())
+    .await?;
+
+    sqlx::query("DELETE FROM magic_link_tokens WHERE user_id = ? AND used_at IS NULL")
+        .bind(&user_id)
+        .execute(state.database.pool())
+        .await?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(&user_id)
+        .fetch_one(state.database.pool())
+        .await?;
+
+    let metadata = SessionMetadata::from_request(&headers, addr, payload.device_name);
+    let outcome = finish_first_factor(&state, user, &metadata).await?;
+
+    Ok(ResponseJson(outcome))
+}
+
+// Start a session for a fully authenticated user and create its tokens
+pub async fn complete_login(
+    state: &AppState,
+    user: User,
+    metadata: &SessionMetadata,
+) -> Result<LoginResponse, AppError> {
+    let (session, refresh_token) = create_session(state, &user.id, metadata).await?;
+
+    let lifetimes = state.config.default_token_lifetimes();
+    let access_token = create_access_token(
+        &user.id,
+        &state.config.first_party_client_id,
+        Some(&session.id),
+        None,
+        None,
+        lifetimes.access,
+        &state.config,
+    )?;
 
     let response = LoginResponse {
         access_token,
         refresh_token,
         token_type: "Bearer".to_string(),
-        expires_in: state.config.token_expiration_minutes * 60,
+        expires_in: lifetimes.access.num_seconds() as u64,
         user: user.into(),
     };
 
-    Ok(ResponseJson(response))
+    Ok(response)
 }
 
 #[derive(Deserialize)]
@@ -118,30 +382,45 @@ pub struct RefreshTokenRequest {
 
 pub async fn refresh_token(
     State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(payload): Json<RefreshTokenRequest>,
 ) -> Result<ResponseJson<serde_json::Value>, AppError> {
-    use crate::jwt::verify_refresh_token;
+    // Rotate the refresh token, the presented one is spent
+    let metadata = SessionMetadata::from_request(&headers, addr, None);
+    let (session, refresh_token) = rotate_session(&state, &payload.refresh_token, &metadata).await?;
 
-    // Verify refresh token
-    let user_id = verify_refresh_token(&payload.refresh_token, &state.config.jwt_secret)?;
-
-    // Check if user exists and is active
-    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
-        .bind(&user_id)
-        .fetch_optional(state.database.pool())
-        .await?
-        .ok_or_else(|| AppError::Authentication("User not found".to_string()))?;
-
-    if !user.is_active {
-        return Err(AppError::Authentication("Account is disabled".to_string()));
-    }
+    // The account must still be active and not scheduled for deletion
+    let user = find_active_user(&state, &session.user_id).await?;
 
     // Create new access token
-    let access_token = create_access_token(&user.id, &state.config.jwt_secret, state.config.token_expiration_minutes)?;
+    let lifetimes = state.config.default_token_lifetimes();
+    let access_token = create_access_token(
+        &user.id,
+        &state.config.first_party_client_id,
+        Some(&session.id),
+        None,
+        None,
+        lifetimes.access,
+        &state.config,
+    )?;
 
     Ok(ResponseJson(serde_json::json!({
         "access_token": access_token,
+        "refresh_token": refresh_token,
         "token_type": "Bearer",
-        "expires_in": state.config.token_expiration_minutes * 60
+        "expires_in": lifetimes.access.num_seconds()
     })))
 }
+
+// End the session the access token belongs to
+pub async fn logout(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+) -> Result<StatusCode, AppError> {
+    if let Some(session_id) = auth.session_id.as_deref() {
+        revoke_session(&state, &auth.user.id, session_id).await?;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
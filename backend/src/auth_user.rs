@@ -0,0 +1,152 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{request::Parts, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use chrono::Utc;
+
+use crate::{
+    crypto::hash_token,
+    error::AppError,
+    jwt::{bearer_token, verify_access_token},
+    models::{AccessTokenRecord, User},
+    oauth::{find_active_client, find_active_user, is_revoked_jwt},
+    roles::Permission,
+    AppState,
+};
+
+// The user behind a request's bearer token. Both JWT and opaque access
+// tokens are accepted; tokens of revoked sessions or grants, disabled
+// accounts and disabled clients are not.
+//
+// `require_auth` authenticates once for a group of routes and stores the
+// result in the request extensions; the extractor reuses it, or
+// authenticates by itself on routes outside the layer.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub user: User,
+    pub client_id: String,
+    pub session_id: Option<String>, // First-party session, when the token belongs to one
+    pub scopes: Vec<String>,
+}
+
+// An AuthUser whose token was issued to Idryos itself, as opposed to a
+// third-party client
+#[derive(Debug, Clone)]
+pub struct FirstPartyUser(pub AuthUser);
+
+impl AuthUser {
+    pub async fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<Self, AppError> {
+        let token = bearer_token(headers)?;
+
+        // Opaque reference tokens are revoked by deleting them
+        let record = sqlx::query_as::<_, AccessTokenRecord>("SELECT * FROM oauth_access_tokens WHERE token_hash = ?")
+            .bind(hash_token(token))
+            .fetch_optional(state.database.pool())
+            .await?;
+
+        let (user_id, client_id, session_id, scope) = match record {
+            Some(record) if record.expires_at < Utc::now() => {
+                return Err(AppError::Authentication("Access token has expired".to_string()));
+            }
+            Some(record) => (record.user_id, record.client_id, None, record.scopes),
+            None => {
+                let claims = verify_access_token(token, &state.config)?;
+                if is_revoked_jwt(state, &claims.jti).await? {
+                    return Err(AppError::Authentication("Access token has been revoked".to_string()));
+                }
+                (claims.sub, claims.client_id, claims.sid, claims.scope)
+            }
+        };
+
+        // Tokens bound to a session end with it
+        if let Some(session_id) = &session_id {
+            let (active,): (i64,) = sqlx::query_as(
+                "SELECT COUNT(*) FROM sessions WHERE id = ? AND user_id = ? AND revoked_at IS NULL"
+            )
+            .bind(session_id)
+            .bind(&user_id)
+            .fetch_one(state.database.pool())
+            .await?;
+            if active == 0 {
+                return Err(AppError::Authentication("Session has been revoked".to_string()));
+            }
+        }
+
+        if client_id != state.config.first_party_client_id {
+            find_active_client(state, &client_id).await?;
+        }
+        let user = find_active_user(state, &user_id).await?;
+
+        Ok(AuthUser {
+            user,
+            client_id,
+            session_id,
+            scopes: scope
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+
+    pub fn is_first_party(&self, state: &AppState) -> bool {
+        self.client_id == state.config.first_party_client_id
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == scope)
+    }
+
+    pub fn require_scope(&self, scope: &str) -> Result<(), AppError> {
+        if !self.has_scope(scope) {
+            return Err(AppError::Authorization(format!("The {} scope is required", scope)));
+        }
+        Ok(())
+    }
+
+    pub fn require_permission(&self, permission: Permission) -> Result<(), AppError> {
+        if !self.user.has_permission(permission) {
+            return Err(AppError::Authorization("Insufficient permissions".to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        if let Some(auth) = parts.extensions.get::<AuthUser>() {
+            return Ok(auth.clone());
+        }
+        AuthUser::authenticate(state, &parts.headers).await
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for FirstPartyUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let auth = AuthUser::from_request_parts(parts, state).await?;
+        if !auth.is_first_party(state) {
+            return Err(AppError::Authorization("A first-party session is required".to_string()));
+        }
+        Ok(FirstPartyUser(auth))
+    }
+}
+
+// Layer for routes that all need an authenticated user
+pub async fn require_auth(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let auth = AuthUser::authenticate(&state, request.headers()).await?;
+    request.extensions_mut().insert(auth);
+    Ok(next.run(request).await)
+}
@@ -0,0 +1,354 @@
+use axum::{
+    extract::{Form, Path, State},
+    response::Json as ResponseJson,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    auth_user::FirstPartyUser,
+    claims::ClaimsRequest,
+    crypto::generate_token,
+    error::AppError,
+    models::{BackchannelAuthRequest, OAuthClient, TokenRequest, TokenResponse, User},
+    oauth::{authenticate_client, find_active_client, granted_scopes, issue_tokens},
+    AppState,
+};
+
+// Client-Initiated Backchannel Authentication (OpenID Connect CIBA Core 1.0)
+pub const GRANT_TYPE: &str = "urn:openid:params:grant-type:ciba";
+
+const DEFAULT_EXPIRY_SECONDS: i64 = 300;
+const MAX_EXPIRY_SECONDS: i64 = 1800;
+const POLL_INTERVAL_SECONDS: i64 = 5;
+
+pub const DELIVERY_MODES_SUPPORTED: &[&str] = &["poll", "ping", "push"];
+
+#[derive(Debug, Deserialize)]
+pub struct BackchannelAuthenticationRequest {
+    pub client_id: String,
+    pub client_secret: String,
+    pub scope: Option<String>,
+    pub login_hint: Option<String>,
+    pub binding_message: Option<String>,
+    pub client_notification_token: Option<String>,
+    pub requested_expiry: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackchannelAuthenticationResponse {
+    pub auth_req_id: String,
+    pub expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<i64>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct PendingBackchannelRequest {
+    pub id: String,
+    pub client_name: String,
+    pub scopes: Option<String>,
+    pub binding_message: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub async fn backchannel_authentication(
+    State(state): State<AppState>,
+    Form(payload): Form<BackchannelAuthenticationRequest>,
+) -> Result<ResponseJson<BackchannelAuthenticationResponse>, AppError> {
+    let client = authenticate_client(&state, &payload.client_id, &payload.client_secret).await?;
+
+    let mode = client
+        .backchannel_token_delivery_mode
+        .clone()
+        .ok_or_else(|| AppError::Authorization("Client is not registered for CIBA".to_string()))?;
+
+    let scopes = granted_scopes(&client, payload.scope.as_deref());
+    if !scopes.split_whitespace().any(|scope| scope == "openid") {
+        return Err(AppError::Validation("The openid scope is required".to_string()));
+    }
+
+    if mode != "poll" && payload.client_notification_token.is_none() {
+        return Err(AppError::Validation("client_notification_token is required".to_string()));
+    }
+
+    // The hint is the customer's email or username, as given to the agent
+    let login_hint = payload
+        .login_hint
+        .ok_or_else(|| AppError::Validation("login_hint is required".to_string()))?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ? OR username = ?")
+        .bind(&login_hint)
+        .bind(&login_hint)
+        .fetch_optional(state.database.pool())
+        .await?
+        .filter(|user| user.is_active && user.deletion_scheduled_at.is_none())
+        .ok_or_else(|| AppError::Validation("unknown_user_id".to_string()))?;
+
+    let expires_in = payload
+        .requested_expiry
+        .unwrap_or(DEFAULT_EXPIRY_SECONDS)
+        .clamp(1, MAX_EXPIRY_SECONDS);
+    let auth_req_id = generate_token();
+
+    sqlx::query(
+        r#"
+        INSERT INTO ciba_requests (id, auth_req_id, client_id, user_id, scopes, binding_message,
+                                   client_notification_token, expires_at, interval_seconds)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(&auth_req_id)
+    .bind(&client.id)
+    .bind(&user.id)
+    .bind(&scopes)
+    .bind(&payload.binding_message)
+    .bind(&payload.client_notification_token)
+    .bind(Utc::now() + Duration::seconds(expires_in))
+    .bind(POLL_INTERVAL_SECONDS)
+    .execute(state.database.pool())
+    .await?;
+
+    Ok(ResponseJson(BackchannelAuthenticationResponse {
+        auth_req_id,
+        expires_in,
+        interval: (mode != "push").then_some(POLL_INTERVAL_SECONDS),
+    }))
+}
+
+// Pending requests shown on the user's authenticated device
+pub async fn list_requests(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+) -> Result<ResponseJson<Vec<PendingBackchannelRequest>>, AppError> {
+    let requests = sqlx::query_as::<_, PendingBackchannelRequest>(
+        r#"
+        SELECT r.id, c.name AS client_name, r.scopes, r.binding_message, r.expires_at
+        FROM ciba_requests r
+        JOIN oauth_clients c ON c.id = r.client_id
+        WHERE r.user_id = ? AND r.status = 'pending'
+        ORDER BY r.created_at
+        "#
+    )
+    .bind(&auth.user.id)
+    .fetch_all(state.database.pool())
+    .await?
+    .into_iter()
+    .filter(|request| request.expires_at > Utc::now())
+    .collect();
+
+    Ok(ResponseJson(requests))
+}
+
+pub async fn approve_request(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+) -> Result<ResponseJson<serde_json::Value>, AppError> {
+    let request = find_pending_request(&state, &id, &auth.user.id).await?;
+    let client = find_active_client(&state, &request.client_id).await?;
+
+    let result = sqlx::query("UPDATE ciba_requests SET status = 'approved' WHERE id = ? AND status = 'pending'")
+        .bind(&request.id)
+        .execute(state.database.pool())
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("Authentication request not found".to_string()));
+    }
+
+    match client.backchannel_token_delivery_mode.as_deref() {
+        Some("push") => {
+            // Tokens are delivered directly, the client never polls
+            let tokens = issue_tokens(
+                &state,
+                &client,
+                &request.user_id,
+                request.scopes.as_deref(),
+                None,
+                &ClaimsRequest::default(),
+                None,
+            )
+            .await?;
+            delete_request(&state, &request.id).await?;
+
+            let mut body = serde_json::to_value(&tokens)
+                .map_err(|e| AppError::Internal(format!("Token serialization failed: {}", e)))?;
+            body["auth_req_id"] = serde_json::Value::String(request.auth_req_id.clone());
+            notify_client(&client, &request, body);
+        }
+        Some("ping") => {
+            notify_client(&client, &request, serde_json::json!({ "auth_req_id": request.auth_req_id }));
+        }
+        _ => {}
+    }
+
+    Ok(ResponseJson(serde_json::json!({ "status": "approved" })))
+}
+
+pub async fn deny_request(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+) -> Result<ResponseJson<serde_json::Value>, AppError> {
+    let request = find_pending_request(&state, &id, &auth.user.id).await?;
+    let client = find_active_client(&state, &request.client_id).await?;
+
+    let result = sqlx::query("UPDATE ciba_requests SET status = 'denied' WHERE id = ? AND status = 'pending'")
+        .bind(&request.id)
+        .execute(state.database.pool())
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("Authentication request not found".to_string()));
+    }
+
+    match client.backchannel_token_delivery_mode.as_deref() {
+        Some("push") => {
+            delete_request(&state, &request.id).await?;
+            notify_client(&client, &request, serde_json::json!({
+                "auth_req_id": request.auth_req_id,
+                "error": "access_denied"
+            }));
+        }
+        Some("ping") => {
+            notify_client(&client, &request, serde_json::json!({ "auth_req_id": request.auth_req_id }));
+        }
+        _ => {}
+    }
+
+    Ok(ResponseJson(serde_json::json!({ "status": "denied" })))
+}
+
+// Token endpoint handler for poll and ping mode clients
+pub async fn handle_ciba_grant(
+    state: AppState,
+    client: OAuthClient,
+    payload: TokenRequest,
+) -> Result<ResponseJson<TokenResponse>, AppError> {
+    // The token endpoint has already checked the secret of confidential clients
+    if client.is_public() {
+        return Err(AppError::Authentication("Invalid client credentials".to_string()));
+    }
+
+    let mode = client.backchannel_token_delivery_mode.as_deref();
+    if !matches!(mode, Some("poll") | Some("ping")) {
+        return Err(AppError::Authorization("unauthorized_client".to_string()));
+    }
+
+    let auth_req_id = payload
+        .auth_req_id
+        .ok_or_else(|| AppError::Validation("auth_req_id is required".to_string()))?;
+
+    let request = sqlx::query_as::<_, BackchannelAuthRequest>(
+        "SELECT * FROM ciba_requests WHERE auth_req_id = ? AND client_id = ?"
+    )
+    .bind(&auth_req_id)
+    .bind(&client.id)
+    .fetch_optional(state.database.pool())
+    .await?
+    .ok_or_else(|| AppError::Validation("invalid_grant".to_string()))?;
+
+    let now = Utc::now();
+    if request.expires_at < now {
+        delete_request(&state, &request.id).await?;
+        return Err(AppError::Validation("expired_token".to_string()));
+    }
+
+    match request.status.as_str() {
+        "approved" => {
+            delete_request(&state, &request.id).await?;
+            let tokens = issue_tokens(
+                &state,
+                &client,
+                &request.user_id,
+                request.scopes.as_deref(),
+                None,
+                &ClaimsRequest::default(),
+                None,
+            )
+            .await?;
+            Ok(ResponseJson(tokens))
+        }
+        "denied" => {
+            delete_request(&state, &request.id).await?;
+            Err(AppError::Validation("access_denied".to_string()))
+        }
+        _ => {
+            let too_fast = request
+                .last_polled_at
+                .is_some_and(|last| now < last + Duration::seconds(request.interval_seconds));
+
+            if too_fast {
+                // Clients polling too fast have to back off (CIBA Core, section 11)
+                sqlx::query("UPDATE ciba_requests SET interval_seconds = interval_seconds + ?, last_polled_at = ? WHERE id = ?")
+                    .bind(POLL_INTERVAL_SECONDS)
+                    .bind(now)
+                    .bind(&request.id)
+                    .execute(state.database.pool())
+                    .await?;
+                return Err(AppError::Validation("slow_down".to_string()));
+            }
+
+            sqlx::query("UPDATE ciba_requests SET last_polled_at = ? WHERE id = ?")
+                .bind(now)
+                .bind(&request.id)
+                .execute(state.database.pool())
+                .await?;
+            Err(AppError::Validation("authorization_pending".to_string()))
+        }
+    }
+}
+
+async fn find_pending_request(
+    state: &AppState,
+    id: &str,
+    user_id: &str,
+) -> Result<BackchannelAuthRequest, AppError> {
+    sqlx::query_as::<_, BackchannelAuthRequest>(
+        "SELECT * FROM ciba_requests WHERE id = ? AND user_id = ? AND status = 'pending'"
+    )
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(state.database.pool())
+    .await?
+    .filter(|request| request.expires_at > Utc::now())
+    .ok_or_else(|| AppError::NotFound("Authentication request not found".to_string()))
+}
+
+async fn delete_request(state: &AppState, id: &str) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM ciba_requests WHERE id = ?")
+        .bind(id)
+        .execute(state.database.pool())
+        .await?;
+    Ok(())
+}
+
+// Ping and push callbacks are sent in the background so the user's device
+// does not wait on the client's notification endpoint
+fn notify_client(client: &OAuthClient, request: &BackchannelAuthRequest, body: serde_json::Value) {
+    let (Some(endpoint), Some(notification_token)) = (
+        client.backchannel_client_notification_endpoint.clone(),
+        request.client_notification_token.clone(),
+    ) else {
+        warn!("CIBA client {} has no notification endpoint", client.id);
+        return;
+    };
+
+    tokio::spawn(async move {
+        let result = reqwest::Client::new()
+            .post(&endpoint)
+            .bearer_auth(notification_token)
+            .json(&body)
+            .send()
+            .await;
+
+        match result {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => warn!("CIBA notification to {} failed with {}", endpoint, response.status()),
+            Err(e) => warn!("CIBA notification to {} failed: {}", endpoint, e),
+        }
+    });
+}
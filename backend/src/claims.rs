@@ -0,0 +1,280 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use crate::{
+    error::AppError,
+    models::{OAuthClient, User},
+};
+
+// Where a set of claims is delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClaimTarget {
+    IdToken,
+    Userinfo,
+}
+
+// OIDC `claims` request parameter (OpenID Connect Core 1.0, section 5.5)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClaimsRequest {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub userinfo: BTreeMap<String, Option<IndividualClaimRequest>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub id_token: BTreeMap<String, Option<IndividualClaimRequest>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IndividualClaimRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub essential: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<Value>>,
+}
+
+impl IndividualClaimRequest {
+    fn accepts(&self, actual: &Value) -> bool {
+        if let Some(value) = &self.value {
+            return value == actual;
+        }
+        if let Some(values) = &self.values {
+            return values.contains(actual);
+        }
+        true
+    }
+}
+
+impl ClaimsRequest {
+    pub fn parse(raw: Option<&str>) -> Result<Self, AppError> {
+        match raw.filter(|raw| !raw.trim().is_empty()) {
+            Some(raw) => serde_json::from_str(raw)
+                .map_err(|_| AppError::Validation("Invalid claims parameter".to_string())),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn for_target(&self, target: ClaimTarget) -> &BTreeMap<String, Option<IndividualClaimRequest>> {
+        match target {
+            ClaimTarget::IdToken => &self.id_token,
+            ClaimTarget::Userinfo => &self.userinfo,
+        }
+    }
+
+    pub fn essential_claims(&self) -> Vec<&str> {
+        self.id_token
+            .iter()
+            .chain(self.userinfo.iter())
+            .filter(|(_, request)| request.as_ref().and_then(|r| r.essential).unwrap_or(false))
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    // A client asking for a specific `sub` must get that user or nothing
+    pub fn check_subject(&self, user: &User) -> Result<(), AppError> {
+        let subject = Value::String(user.id.clone());
+        for target in [ClaimTarget::IdToken, ClaimTarget::Userinfo] {
+            if let Some(Some(request)) = self.for_target(target).get("sub") {
+                if !request.accepts(&subject) {
+                    return Err(AppError::Authorization("Requested subject does not match".to_string()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// User fields that can be exposed through a claim mapping rule
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserField {
+    Id,
+    Username,
+    Email,
+    Did,
+    GivenName,
+    FamilyName,
+    Locale,
+    Zoneinfo,
+    Picture,
+    PhoneNumber,
+    CreatedAt,
+    UpdatedAt,
+}
+
+impl UserField {
+    fn value(self, user: &User) -> Option<Value> {
+        match self {
+            UserField::Id => Some(Value::String(user.id.clone())),
+            UserField::Username => Some(Value::String(user.username.clone())),
+            UserField::Email => Some(Value::String(user.email.clone())),
+            UserField::Did => user.did.clone().map(Value::String),
+            UserField::GivenName => user.given_name.clone().map(Value::String),
+            UserField::FamilyName => user.family_name.clone().map(Value::String),
+            UserField::Locale => user.locale.clone().map(Value::String),
+            UserField::Zoneinfo => user.zoneinfo.clone().map(Value::String),
+            UserField::Picture => user.picture.clone().map(Value::String),
+            UserField::PhoneNumber => user.phone_number.clone().map(Value::String),
+            UserField::CreatedAt => Some(Value::from(user.created_at.timestamp())),
+            UserField::UpdatedAt => Some(Value::from(user.updated_at.timestamp())),
+        }
+    }
+}
+
+// Per-client claim mapping rule, stored as JSON on `oauth_clients.claim_mappings`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClaimMapping {
+    // Deliver an existing claim under another name
+    Rename {
+        claim: String,
+        to: String,
+        #[serde(default)]
+        target: Option<ClaimTarget>,
+    },
+    // Always add a fixed value
+    Static {
+        claim: String,
+        value: Value,
+        #[serde(default)]
+        target: Option<ClaimTarget>,
+    },
+    // Add a claim derived from a user field
+    UserField {
+        claim: String,
+        field: UserField,
+        #[serde(default)]
+        target: Option<ClaimTarget>,
+    },
+}
+
+impl ClaimMapping {
+    pub fn for_client(client: &OAuthClient) -> Result<Vec<Self>, AppError> {
+        match client.claim_mappings.as_deref() {
+            Some(raw) => serde_json::from_str(raw)
+                .map_err(|_| AppError::Internal("Invalid claim mappings format".to_string())),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn applies_to(&self, target: ClaimTarget) -> bool {
+        let rule_target = match self {
+            ClaimMapping::Rename { target, .. }
+            | ClaimMapping::Static { target, .. }
+            | ClaimMapping::UserField { target, .. } => target,
+        };
+        rule_target.is_none() || *rule_target == Some(target)
+    }
+
+    fn apply(&self, user: &User, claims: &mut Map<String, Value>) {
+        match self {
+            ClaimMapping::Rename { claim, to, .. } => {
+                if let Some(value) = claims.remove(claim) {
+                    claims.insert(to.clone(), value);
+                }
+            }
+            ClaimMapping::Static { claim, value, .. } => {
+                claims.insert(claim.clone(), value.clone());
+            }
+            ClaimMapping::UserField { claim, field, .. } => {
+                if let Some(value) = field.value(user) {
+                    claims.insert(claim.clone(), value);
+                }
+            }
+        }
+    }
+}
+
+pub const SCOPES_SUPPORTED: &[&str] = &["openid", "profile", "email", "address", "phone", "offline_access"];
+pub const CLAIMS_SUPPORTED: &[&str] = &[
+    "sub",
+    "preferred_username",
+    "given_name",
+    "family_name",
+    "locale",
+    "zoneinfo",
+    "picture",
+    "email",
+    "email_verified",
+    "phone_number",
+    "phone_number_verified",
+    "address",
+    "did",
+    "updated_at",
+];
+
+// Standard claims released by each scope (OpenID Connect Core 1.0, section 5.4)
+fn scope_claims(scope: &str) -> &'static [&'static str] {
+    match scope {
+        "profile" => &["preferred_username", "given_name", "family_name", "locale", "zoneinfo", "picture", "updated_at"],
+        "email" => &["email", "email_verified"],
+        "address" => &["address"],
+        "phone" => &["phone_number", "phone_number_verified"],
+        _ => &[],
+    }
+}
+
+fn user_claim(user: &User, name: &str) -> Option<Value> {
+    match name {
+        "sub" => Some(Value::String(user.id.clone())),
+        "preferred_username" => Some(Value::String(user.username.clone())),
+        "email" => Some(Value::String(user.email.clone())),
+        "email_verified" => Some(Value::Bool(user.email_verified_at.is_some())),
+        "given_name" => user.given_name.clone().map(Value::String),
+        "family_name" => user.family_name.clone().map(Value::String),
+        "locale" => user.locale.clone().map(Value::String),
+        "zoneinfo" => user.zoneinfo.clone().map(Value::String),
+        "picture" => user.picture.clone().map(Value::String),
+        "phone_number" => user.phone_number.clone().map(Value::String),
+        // Phone numbers are entered by the user and never verified
+        "phone_number_verified" => user.phone_number.as_ref().map(|_| Value::Bool(false)),
+        "address" => user.address().and_then(|address| serde_json::to_value(address).ok()),
+        "did" => user.did.clone().map(Value::String),
+        "updated_at" => Some(Value::from(user.updated_at.timestamp())),
+        _ => None,
+    }
+}
+
+// Build the user claims for an ID token or userinfo response. Scope-based
+// claims go to userinfo, explicitly requested claims go where they were
+// requested, then the client's mapping rules are applied.
+pub fn build_claims(
+    user: &User,
+    client: &OAuthClient,
+    scopes: &str,
+    request: &ClaimsRequest,
+    target: ClaimTarget,
+) -> Result<Map<String, Value>, AppError> {
+    let mut claims = Map::new();
+    claims.insert("sub".to_string(), Value::String(user.id.clone()));
+
+    if target == ClaimTarget::Userinfo {
+        for name in scopes.split_whitespace().flat_map(scope_claims) {
+            if let Some(value) = user_claim(user, name) {
+                claims.insert(name.to_string(), value);
+            }
+        }
+    }
+
+    for (name, individual) in request.for_target(target) {
+        let Some(value) = user_claim(user, name) else {
+            continue;
+        };
+        let accepted = match individual {
+            Some(individual) => individual.accepts(&value),
+            None => true,
+        };
+        if accepted {
+            claims.insert(name.clone(), value);
+        }
+    }
+
+    for rule in ClaimMapping::for_client(client)? {
+        if rule.applies_to(target) {
+            rule.apply(user, &mut claims);
+        }
+    }
+
+    Ok(claims)
+}
@@ -0,0 +1,44 @@
+use std::env;
+
+use crate::{
+    error::AppError,
+    models::User,
+    portability::{export_user, import_bundle, Bundle},
+    AppState,
+};
+
+// Operator commands, run instead of the server when arguments are given:
+//
+//   export <user id or email> [file]   write a bundle to the file, or stdout
+//   import <file>                      create an account from a bundle
+//
+// EXPORT_PASSPHRASE encrypts exports and decrypts imports.
+pub async fn run(state: &AppState, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let passphrase = env::var("EXPORT_PASSPHRASE").ok();
+
+    match args {
+        [command, user, rest @ ..] if command == "export" && rest.len() <= 1 => {
+            let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ? OR LOWER(email) = LOWER(?)")
+                .bind(user)
+                .bind(user)
+                .fetch_optional(state.database.pool())
+                .await?
+                .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+            let bundle = export_user(state, &user, passphrase.as_deref()).await?;
+            let json = serde_json::to_string_pretty(&bundle)?;
+            match rest.first() {
+                Some(path) => std::fs::write(path, json)?,
+                None => println!("{}", json),
+            }
+        }
+        [command, path] if command == "import" => {
+            let bundle: Bundle = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+            let user = import_bundle(state, &bundle, passphrase.as_deref()).await?;
+            println!("Imported {} as user {}", user.email, user.id);
+        }
+        _ => return Err("usage: idryos-auth export <user id or email> [file] | import <file>".into()),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,340 @@
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+    response::Json as ResponseJson,
+};
+use chrono::Utc;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use uuid::Uuid;
+
+use crate::{
+    claims,
+    crypto::generate_token,
+    error::AppError,
+    models::OAuthClient,
+    AppState,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct ClientRegistrationRequest {
+    pub client_name: String,
+    pub redirect_uris: Vec<String>,
+    #[serde(default = "default_application_type")]
+    pub application_type: String,
+    pub token_endpoint_auth_method: Option<String>,
+    pub scope: Option<String>,
+    pub backchannel_logout_uri: Option<String>,
+    pub backchannel_token_delivery_mode: Option<String>,
+    pub backchannel_client_notification_endpoint: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClientRegistrationResponse {
+    pub client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    pub client_id_issued_at: i64,
+    pub client_name: String,
+    pub redirect_uris: Vec<String>,
+    pub application_type: String,
+    pub token_endpoint_auth_method: String,
+    pub scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backchannel_logout_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backchannel_token_delivery_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backchannel_client_notification_endpoint: Option<String>,
+}
+
+fn default_application_type() -> String {
+    "web".to_string()
+}
+
+// Dynamic client registration (RFC 7591), disabled unless configured
+pub async fn register_client(
+    State(state): State<AppState>,
+    Json(payload): Json<ClientRegistrationRequest>,
+) -> Result<(StatusCode, ResponseJson<ClientRegistrationResponse>), AppError> {
+    if !state.config.client_registration_enabled {
+        return Err(AppError::NotFound("Client registration is disabled".to_string()));
+    }
+
+    let response = create_client(&state, payload).await?;
+    Ok((StatusCode::CREATED, ResponseJson(response)))
+}
+
+// Shared by dynamic registration and the admin API
+pub async fn create_client(
+    state: &AppState,
+    payload: ClientRegistrationRequest,
+) -> Result<ClientRegistrationResponse, AppError> {
+    if payload.client_name.trim().is_empty() {
+        return Err(AppError::Validation("client_name is required".to_string()));
+    }
+
+    validate_redirect_uris(&payload.application_type, &payload.redirect_uris)?;
+    if let Some(uri) = &payload.backchannel_logout_uri {
+        validate_backchannel_logout_uri(uri)?;
+    }
+    validate_backchannel_delivery(
+        payload.backchannel_token_delivery_mode.as_deref(),
+        payload.backchannel_client_notification_endpoint.as_deref(),
+    )?;
+
+    // Native apps cannot keep a secret (RFC 8252, section 8.4)
+    let token_endpoint_auth_method = match payload.token_endpoint_auth_method.as_deref() {
+        Some(method) => method.to_string(),
+        None if payload.application_type == "native" => "none".to_string(),
+        None => "client_secret_post".to_string(),
+    };
+    let client_secret = match token_endpoint_auth_method.as_str() {
+        "client_secret_post" => Some(generate_token()),
+        "none" => None,
+        _ => return Err(AppError::Validation("Unsupported token_endpoint_auth_method".to_string())),
+    };
+
+    let scope = payload
+        .scope
+        .unwrap_or_else(|| claims::SCOPES_SUPPORTED.join(" "));
+    let redirect_uris = serde_json::to_string(&payload.redirect_uris)
+        .map_err(|e| AppError::Internal(format!("Redirect URI serialization failed: {}", e)))?;
+
+    let client_id = Uuid::new_v4().to_string();
+    let now = Utc::now();
+
+    sqlx::query(
+        r#"
+        INSERT INTO oauth_clients (id, client_secret, name, redirect_uris, scopes, created_at,
+                                   application_type, token_endpoint_auth_method, backchannel_logout_uri,
+                                   backchannel_token_delivery_mode, backchannel_client_notification_endpoint)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(&client_id)
+    .bind(client_secret.as_deref().unwrap_or_default())
+    .bind(&payload.client_name)
+    .bind(&redirect_uris)
+    .bind(&scope)
+    .bind(now)
+    .bind(&payload.application_type)
+    .bind(&token_endpoint_auth_method)
+    .bind(&payload.backchannel_logout_uri)
+    .bind(&payload.backchannel_token_delivery_mode)
+    .bind(&payload.backchannel_client_notification_endpoint)
+    .execute(state.database.pool())
+    .await?;
+
+    Ok(ClientRegistrationResponse {
+        client_id,
+        client_secret,
+        client_id_issued_at: now.timestamp(),
+        client_name: payload.client_name,
+        redirect_uris: payload.redirect_uris,
+        application_type: payload.application_type,
+        token_endpoint_auth_method,
+        scope,
+        backchannel_logout_uri: payload.backchannel_logout_uri,
+        backchannel_token_delivery_mode: payload.backchannel_token_delivery_mode,
+        backchannel_client_notification_endpoint: payload.backchannel_client_notification_endpoint,
+    })
+}
+
+// Registration-time checks. Redirect URIs must be absolute, without
+// wildcards or fragments, and use a scheme allowed for the client type.
+pub fn validate_redirect_uris(application_type: &str, redirect_uris: &[String]) -> Result<(), AppError> {
+    if !matches!(application_type, "web" | "native") {
+        return Err(AppError::Validation("application_type must be web or native".to_string()));
+    }
+
+    if redirect_uris.is_empty() {
+        return Err(AppError::Validation("At least one redirect URI is required".to_string()));
+    }
+
+    for redirect_uri in redirect_uris {
+        let invalid = |reason: &str| AppError::Validation(format!("Invalid redirect URI {}: {}", redirect_uri, reason));
+
+        if redirect_uri.contains('*') {
+            return Err(invalid("wildcards are not allowed"));
+        }
+
+        let url = Url::parse(redirect_uri).map_err(|_| invalid("not an absolute URI"))?;
+        if url.fragment().is_some() {
+            return Err(invalid("fragments are not allowed"));
+        }
+
+        match (application_type, url.scheme()) {
+            (_, "https") => {}
+            // Loopback redirects are how native apps receive the response
+            // without a fixed port (RFC 8252, section 7.3)
+            ("native", "http") if is_loopback(&url) => {}
+            ("native", "http") => return Err(invalid("http is only allowed for loopback addresses")),
+            // Private-use schemes must be reverse domain names (RFC 8252, section 7.1)
+            ("native", scheme) if scheme.contains('.') => {}
+            ("native", _) => return Err(invalid("private-use schemes must be reverse domain names")),
+            ("web", "http") if is_loopback(&url) || url.host_str() == Some("localhost") => {}
+            _ => return Err(invalid("web clients must use https")),
+        }
+    }
+
+    Ok(())
+}
+
+// The server calls this endpoint itself, so it must be an absolute https
+// URL without a fragment (OpenID Connect Back-Channel Logout 1.0, section 2.2)
+pub fn validate_backchannel_logout_uri(uri: &str) -> Result<(), AppError> {
+    let invalid = || AppError::Validation(format!("Invalid backchannel_logout_uri {}", uri));
+
+    let url = Url::parse(uri).map_err(|_| invalid())?;
+    if url.fragment().is_some() {
+        return Err(invalid());
+    }
+    match url.scheme() {
+        "https" => Ok(()),
+        "http" if is_loopback(&url) || url.host_str() == Some("localhost") => Ok(()),
+        _ => Err(invalid()),
+    }
+}
+
+// CIBA client metadata (CIBA Core 1.0, section 4). Ping and push clients
+// are called back, so they need an https notification endpoint.
+pub fn validate_backchannel_delivery(mode: Option<&str>, endpoint: Option<&str>) -> Result<(), AppError> {
+    match mode {
+        None if endpoint.is_some() => Err(AppError::Validation(
+            "backchannel_client_notification_endpoint requires backchannel_token_delivery_mode".to_string(),
+        )),
+        None | Some("poll") => Ok(()),
+        Some("ping" | "push") => {
+            let endpoint = endpoint.ok_or_else(|| {
+                AppError::Validation("backchannel_client_notification_endpoint is required for ping and push".to_string())
+            })?;
+            let invalid = || AppError::Validation(format!("Invalid backchannel_client_notification_endpoint {}", endpoint));
+            let url = Url::parse(endpoint).map_err(|_| invalid())?;
+            if url.scheme() != "https" || url.fragment().is_some() {
+                return Err(invalid());
+            }
+            Ok(())
+        }
+        Some(_) => Err(AppError::Validation("Unsupported backchannel_token_delivery_mode".to_string())),
+    }
+}
+
+// Runtime matching of a requested redirect URI against the registered ones.
+// Native clients may use any port on a registered loopback redirect, every
+// other redirect must match exactly.
+pub fn redirect_uri_allowed(client: &OAuthClient, redirect_uri: &str) -> Result<bool, AppError> {
+    let registered: Vec<String> = serde_json::from_str(&client.redirect_uris)
+        .map_err(|_| AppError::Internal("Invalid redirect URIs format".to_string()))?;
+
+    if registered.iter().any(|uri| uri == redirect_uri) {
+        return Ok(true);
+    }
+
+    if client.application_type != "native" {
+        return Ok(false);
+    }
+
+    let Ok(requested) = Url::parse(redirect_uri) else {
+        return Ok(false);
+    };
+    if requested.scheme() != "http" || !is_loopback(&requested) || requested.fragment().is_some() {
+        return Ok(false);
+    }
+
+    Ok(registered
+        .iter()
+        .filter_map(|uri| Url::parse(uri).ok())
+        .any(|uri| {
+            uri.scheme() == "http"
+                && uri.host() == requested.host()
+                && uri.path() == requested.path()
+                && uri.query() == requested.query()
+        }))
+}
+
+pub fn is_loopback(url: &Url) -> bool {
+    match url.host_str() {
+        Some(host) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn client(application_type: &str, redirect_uris: &[&str]) -> OAuthClient {
+        OAuthClient {
+            id: "client".to_string(),
+            client_secret: String::new(),
+            name: "Client".to_string(),
+            redirect_uris: serde_json::to_string(redirect_uris).unwrap(),
+            scopes: "openid".to_string(),
+            created_at: Utc::now(),
+            is_active: true,
+            access_token_format: "jwt".to_string(),
+            claim_mappings: None,
+            backchannel_token_delivery_mode: None,
+            backchannel_client_notification_endpoint: None,
+            authorization_details_types: None,
+            backchannel_logout_uri: None,
+            application_type: application_type.to_string(),
+            token_endpoint_auth_method: "none".to_string(),
+            access_token_lifetime_seconds: None,
+            id_token_lifetime_seconds: None,
+            refresh_token_idle_seconds: None,
+            refresh_token_absolute_seconds: None,
+        }
+    }
+
+    // RFC 8252, section 7.3
+    #[test]
+    fn native_loopback_redirects_may_use_any_port() {
+        let client = client("native", &["http://127.0.0.1/callback", "http://[::1]/callback"]);
+
+        assert!(redirect_uri_allowed(&client, "http://127.0.0.1:51234/callback").unwrap());
+        assert!(redirect_uri_allowed(&client, "http://[::1]:8080/callback").unwrap());
+        assert!(!redirect_uri_allowed(&client, "http://127.0.0.1:51234/other").unwrap());
+        assert!(!redirect_uri_allowed(&client, "http://127.0.0.1:51234/callback?next=1").unwrap());
+        assert!(!redirect_uri_allowed(&client, "http://127.0.0.1:51234/callback#fragment").unwrap());
+        assert!(!redirect_uri_allowed(&client, "http://localhost:51234/callback").unwrap());
+        assert!(!redirect_uri_allowed(&client, "http://evil.example:51234/callback").unwrap());
+    }
+
+    #[test]
+    fn other_redirects_must_match_exactly() {
+        let web = client("web", &["https://app.example.com/callback", "http://127.0.0.1/callback"]);
+        assert!(redirect_uri_allowed(&web, "https://app.example.com/callback").unwrap());
+        assert!(!redirect_uri_allowed(&web, "https://app.example.com/callback/").unwrap());
+        assert!(!redirect_uri_allowed(&web, "http://127.0.0.1:8080/callback").unwrap());
+
+        // RFC 8252, section 7.1
+        let native = client("native", &["com.example.app:/oauth2redirect"]);
+        assert!(redirect_uri_allowed(&native, "com.example.app:/oauth2redirect").unwrap());
+        assert!(!redirect_uri_allowed(&native, "com.example.app:/other").unwrap());
+        assert!(!redirect_uri_allowed(&native, "com.evil.app:/oauth2redirect").unwrap());
+    }
+
+    #[test]
+    fn registration_checks_schemes_by_application_type() {
+        let uris = |uris: &[&str]| uris.iter().map(|uri| uri.to_string()).collect::<Vec<_>>();
+
+        assert!(validate_redirect_uris("native", &uris(&["com.example.app:/callback"])).is_ok());
+        assert!(validate_redirect_uris("native", &uris(&["http://127.0.0.1/callback"])).is_ok());
+        assert!(validate_redirect_uris("native", &uris(&["myapp:/callback"])).is_err());
+        assert!(validate_redirect_uris("native", &uris(&["http://app.example.com/callback"])).is_err());
+        assert!(validate_redirect_uris("web", &uris(&["https://app.example.com/callback"])).is_ok());
+        assert!(validate_redirect_uris("web", &uris(&["http://localhost:3000/callback"])).is_ok());
+        assert!(validate_redirect_uris("web", &uris(&["com.example.app:/callback"])).is_err());
+        assert!(validate_redirect_uris("web", &uris(&["https://*.example.com/callback"])).is_err());
+        assert!(validate_redirect_uris("web", &uris(&["https://app.example.com/callback#x"])).is_err());
+        assert!(validate_redirect_uris("web", &[]).is_err());
+    }
+}
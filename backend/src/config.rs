@@ -1,20 +1,67 @@
+use chrono::Duration;
 use serde::{Deserialize, Serialize};
 use std::env;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Config {
     pub port: u16,
     pub jwt_secret: String,
     pub token_expiration_minutes: u64,
+    pub id_token_expiration_minutes: u64,
+    pub refresh_token_idle_days: u64,
+    pub refresh_token_absolute_days: u64,
     pub database_url: String,
     pub cors_origins: Vec<String>,
     pub frontend_url: String,
+    pub issuer: String,
+    pub access_token_audience: String,
+    pub first_party_client_id: String,
+    pub jwt_leeway_seconds: u64,
+    pub client_registration_enabled: bool,
+    pub magic_link_login_enabled: bool,
+    pub admin_emails: Vec<String>, // Accounts given the admin role at startup
+    pub trusted_import_issuers: Vec<String>, // Instances whose bundles and receipts are accepted
+    pub secret_encryption_key: Option<String>, // Base64, 32 bytes
+    pub user_key_dir: String,                 // Per-user data keys, kept out of database backups
+    pub account_deletion_grace_days: i64,     // Deleted accounts can be restored for this long
+    pub totp_issuer: String,
+    pub webauthn_rp_id: String,   // Domain passkeys are scoped to
+    pub webauthn_rp_name: String,
+    pub webauthn_origin: String,  // Origin of the frontend running the ceremonies
+    pub mailer: String,           // "file" or "smtp"
+    pub mail_from: String,
+    pub mail_outbox_dir: String,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub smtp_starttls: bool,
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    pub password_hash_concurrency: usize, // Password hashes computed at once
 }
 
 impl Config {
     pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
         dotenvy::dotenv().ok();
 
+        let frontend_url = env::var("FRONTEND_URL")
+            .unwrap_or_else(|_| "http://localhost:3000".to_string());
+        let frontend_host = reqwest::Url::parse(&frontend_url)?
+            .host_str()
+            .unwrap_or("localhost")
+            .to_string();
+
+        let cpus = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+
+        let issuer = env::var("ISSUER_URL")
+            .unwrap_or_else(|_| "http://localhost:8000".to_string())
+            .trim_end_matches('/')
+            .to_string();
+
         Ok(Config {
             port: env::var("AUTH_SERVICE_PORT")
                 .unwrap_or_else(|_| "8000".to_string())
@@ -24,6 +71,15 @@ impl Config {
             token_expiration_minutes: env::var("TOKEN_EXPIRATION_MINUTES")
                 .unwrap_or_else(|_| "15".to_string())
                 .parse()?,
+            id_token_expiration_minutes: env::var("ID_TOKEN_EXPIRATION_MINUTES")
+                .unwrap_or_else(|_| "15".to_string())
+                .parse()?,
+            refresh_token_idle_days: env::var("REFRESH_TOKEN_IDLE_DAYS")
+                .unwrap_or_else(|_| "30".to_string())
+                .parse()?,
+            refresh_token_absolute_days: env::var("REFRESH_TOKEN_ABSOLUTE_DAYS")
+                .unwrap_or_else(|_| "90".to_string())
+                .parse()?,
             database_url: env::var("DATABASE_URL")
                 .unwrap_or_else(|_| "sqlite://./data/idryos.db".to_string()),
             cors_origins: env::var("CORS_ORIGINS")
@@ -31,8 +87,98 @@ impl Config {
                 .split(',')
                 .map(|s| s.trim().to_string())
                 .collect(),
-            frontend_url: env::var("FRONTEND_URL")
-                .unwrap_or_else(|_| "http://localhost:3000".to_string()),
+            access_token_audience: env::var("ACCESS_TOKEN_AUDIENCE")
+                .unwrap_or_else(|_| issuer.clone()),
+            first_party_client_id: env::var("FIRST_PARTY_CLIENT_ID")
+                .unwrap_or_else(|_| "idryos".to_string()),
+            jwt_leeway_seconds: env::var("JWT_LEEWAY_SECONDS")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()?,
+            client_registration_enabled: env::var("CLIENT_REGISTRATION_ENABLED")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()?,
+            magic_link_login_enabled: env::var("MAGIC_LINK_LOGIN_ENABLED")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()?,
+            admin_emails: env::var("ADMIN_EMAILS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            trusted_import_issuers: env::var("TRUSTED_IMPORT_ISSUERS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().trim_end_matches('/').to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            secret_encryption_key: env::var("SECRET_ENCRYPTION_KEY")
+                .ok()
+                .filter(|key| !key.is_empty()),
+            user_key_dir: env::var("USER_KEY_DIR")
+                .unwrap_or_else(|_| "./data/user-keys".to_string()),
+            account_deletion_grace_days: env::var("ACCOUNT_DELETION_GRACE_DAYS")
+                .unwrap_or_else(|_| "14".to_string())
+                .parse()?,
+            totp_issuer: env::var("TOTP_ISSUER")
+                .unwrap_or_else(|_| "Idryos".to_string()),
+            webauthn_rp_id: env::var("WEBAUTHN_RP_ID")
+                .unwrap_or(frontend_host),
+            webauthn_rp_name: env::var("WEBAUTHN_RP_NAME")
+                .unwrap_or_else(|_| "Idryos".to_string()),
+            webauthn_origin: env::var("WEBAUTHN_ORIGIN")
+                .unwrap_or_else(|_| frontend_url.trim_end_matches('/').to_string()),
+            mailer: env::var("MAILER")
+                .unwrap_or_else(|_| "file".to_string()),
+            mail_from: env::var("MAIL_FROM")
+                .unwrap_or_else(|_| "Idryos <no-reply@localhost>".to_string()),
+            mail_outbox_dir: env::var("MAIL_OUTBOX_DIR")
+                .unwrap_or_else(|_| "./data/outbox".to_string()),
+            smtp_host: env::var("SMTP_HOST")
+                .unwrap_or_else(|_| "localhost".to_string()),
+            smtp_port: env::var("SMTP_PORT")
+                .unwrap_or_else(|_| "1025".to_string())
+                .parse()?,
+            smtp_username: env::var("SMTP_USERNAME").ok().filter(|value| !value.is_empty()),
+            smtp_password: env::var("SMTP_PASSWORD").ok().filter(|value| !value.is_empty()),
+            smtp_starttls: env::var("SMTP_STARTTLS")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()?,
+            // Defaults follow the OWASP recommendation for Argon2id
+            argon2_memory_kib: env::var("ARGON2_MEMORY_KIB")
+                .unwrap_or_else(|_| "19456".to_string())
+                .parse()?,
+            argon2_iterations: env::var("ARGON2_ITERATIONS")
+                .unwrap_or_else(|_| "2".to_string())
+                .parse()?,
+            argon2_parallelism: env::var("ARGON2_PARALLELISM")
+                .unwrap_or_else(|_| "1".to_string())
+                .parse()?,
+            password_hash_concurrency: env::var("PASSWORD_HASH_CONCURRENCY")
+                .unwrap_or_else(|_| cpus.to_string())
+                .parse()?,
+            frontend_url,
+            issuer,
         })
     }
 }
+
+// Token lifetimes in effect for a client
+#[derive(Debug, Clone, Copy)]
+pub struct TokenLifetimes {
+    pub access: Duration,
+    pub id_token: Duration,
+    pub refresh_idle: Duration,     // Unused refresh tokens expire after this
+    pub refresh_absolute: Duration, // Refresh tokens never outlive this, however often used
+}
+
+impl Config {
+    pub fn default_token_lifetimes(&self) -> TokenLifetimes {
+        TokenLifetimes {
+            access: Duration::minutes(self.token_expiration_minutes as i64),
+            id_token: Duration::minutes(self.id_token_expiration_minutes as i64),
+            refresh_idle: Duration::days(self.refresh_token_idle_days as i64),
+            refresh_absolute: Duration::days(self.refresh_token_absolute_days as i64),
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use base64::{Engine as _, engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}};
+use rand::Rng;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    digest::{digest, Context, SHA256},
+};
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+use crate::{config::Config, database::Database, did::managed_key_context, error::AppError, mfa::totp_context};
+
+// Random URL-safe token with 256 bits of entropy
+pub fn generate_token() -> String {
+    let mut rng = rand::thread_rng();
+    let bytes: [u8; 32] = rng.gen();
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// Tokens are only ever stored as their SHA-256 digest, so a database leak
+// does not hand out usable credentials
+pub fn hash_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(digest(&SHA256, token.as_bytes()))
+}
+
+// Compare secrets through their digests so the comparison time does not
+// depend on how many leading bytes match
+pub fn secrets_match(a: &str, b: &str) -> bool {
+    digest(&SHA256, a.as_bytes()).as_ref() == digest(&SHA256, b.as_bytes()).as_ref()
+}
+
+// Key for secrets that have to be stored recoverably (e.g. TOTP seeds).
+// Deployments should set SECRET_ENCRYPTION_KEY, development setups fall
+// back to a key derived from the JWT secret.
+fn secret_encryption_key(config: &Config) -> Result<LessSafeKey, AppError> {
+    let key = match &config.secret_encryption_key {
+        Some(encoded) => STANDARD
+            .decode(encoded)
+            .map_err(|_| AppError::Internal("SECRET_ENCRYPTION_KEY is not valid base64".to_string()))?,
+        None => {
+            let mut context = Context::new(&SHA256);
+            context.update(b"idryos secret encryption\0");
+            context.update(config.jwt_secret.as_bytes());
+            context.finish().as_ref().to_vec()
+        }
+    };
+
+    let key = UnboundKey::new(&AES_256_GCM, &key)
+        .map_err(|_| AppError::Internal("SECRET_ENCRYPTION_KEY must be 32 bytes".to_string()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+// AES-256-GCM with a random nonce, returned as base64url(nonce || ciphertext).
+// `context` is authenticated but not stored, so a ciphertext copied to another
+// row (e.g. another user's) fails to decrypt.
+pub fn encrypt_secret(config: &Config, context: &str, plaintext: &[u8]) -> Result<String, AppError> {
+    seal(&secret_encryption_key(config)?, context, plaintext)
+}
+
+pub fn decrypt_secret(config: &Config, context: &str, sealed: &str) -> Result<Vec<u8>, AppError> {
+    open(&secret_encryption_key(config)?, context, sealed)
+}
+
+// Per-user data keys, for crypto-shredding. Each account's secrets are
+// sealed with its own key, kept wrapped by the server key in a file under
+// USER_KEY_DIR rather than in the database. Destroying the file leaves any
+// copy of the account's rows, including database backups, unreadable.
+pub async fn encrypt_user_secret(
+    config: &Config,
+    user_id: &str,
+    context: &str,
+    plaintext: &[u8],
+) -> Result<String, AppError> {
+    let key = user_key(config, user_id, true)
+        .await?
+        .ok_or_else(|| AppError::Internal("User key is missing".to_string()))?;
+    seal(&key, context, plaintext)
+}
+
+pub async fn decrypt_user_secret(
+    config: &Config,
+    user_id: &str,
+    context: &str,
+    sealed: &str,
+) -> Result<Vec<u8>, AppError> {
+    let key = user_key(config, user_id, false)
+        .await?
+        .ok_or_else(|| AppError::Internal("User key is missing".to_string()))?;
+    open(&key, context, sealed)
+}
+
+// Secrets stored before per-user keys existed were sealed with the server
+// key. Run at startup, this moves them under their user's key so erasure
+// shreds them too; rows already moved do not open with the server key and
+// are left alone.
+pub async fn reseal_with_user_keys(database: &Database, config: &Config) -> Result<(), AppError> {
+    let totp: Vec<(String, String)> = sqlx::query_as("SELECT user_id, secret_encrypted FROM user_totp")
+        .fetch_all(database.pool())
+        .await?;
+    for (user_id, sealed) in totp {
+        let context = totp_context(&user_id);
+        let Ok(secret) = decrypt_secret(config, &context, &sealed) else {
+            continue;
+        };
+        sqlx::query("UPDATE user_totp SET secret_encrypted = ? WHERE user_id = ? AND secret_encrypted = ?")
+            .bind(encrypt_user_secret(config, &user_id, &context, &secret).await?)
+            .bind(&user_id)
+            .bind(&sealed)
+            .execute(database.pool())
+            .await?;
+        info!("Moved the TOTP secret of user {} under its user key", user_id);
+    }
+
+    let keys: Vec<(String, String, String)> =
+        sqlx::query_as("SELECT did, user_id, private_key_encrypted FROM managed_did_keys")
+            .fetch_all(database.pool())
+            .await?;
+    for (did, user_id, sealed) in keys {
+        let context = managed_key_context(&did);
+        let Ok(pkcs8) = decrypt_secret(config, &context, &sealed) else {
+            continue;
+        };
+        sqlx::query("UPDATE managed_did_keys SET private_key_encrypted = ? WHERE did = ? AND private_key_encrypted = ?")
+            .bind(encrypt_user_secret(config, &user_id, &context, &pkcs8).await?)
+            .bind(&did)
+            .bind(&sealed)
+            .execute(database.pool())
+            .await?;
+        info!("Moved the key of {} under the key of user {}", did, user_id);
+    }
+
+    Ok(())
+}
+
+pub async fn destroy_user_key(config: &Config, user_id: &str) -> Result<(), AppError> {
+    match tokio::fs::remove_file(user_key_path(config, user_id)?).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(AppError::Internal(format!("Destroying user key failed: {}", e))),
+    }
+}
+
+async fn user_key(config: &Config, user_id: &str, create: bool) -> Result<Option<LessSafeKey>, AppError> {
+    let path = user_key_path(config, user_id)?;
+    let context = format!("user-key:{}", user_id);
+
+    let wrapped = match tokio::fs::read_to_string(&path).await {
+        Ok(wrapped) => wrapped,
+        Err(e) if e.kind() == ErrorKind::NotFound && create => {
+            tokio::fs::create_dir_all(&config.user_key_dir)
+                .await
+                .map_err(|e| AppError::Internal(format!("Creating user key directory failed: {}", e)))?;
+
+            let key: [u8; 32] = rand::thread_rng().gen();
+            let wrapped = encrypt_secret(config, &context, &key)?;
+
+            // Two requests may race to create the key; the first one wins
+            let file = tokio::fs::OpenOptions::new().write(true).create_new(true).open(&path).await;
+            match file {
+                Ok(mut file) => {
+                    file.write_all(wrapped.as_bytes())
+                        .await
+                        .map_err(|e| AppError::Internal(format!("Writing user key failed: {}", e)))?;
+                    wrapped
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => tokio::fs::read_to_string(&path)
+                    .await
+                    .map_err(|e| AppError::Internal(format!("Reading user key failed: {}", e)))?,
+                Err(e) => return Err(AppError::Internal(format!("Writing user key failed: {}", e))),
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(AppError::Internal(format!("Reading user key failed: {}", e))),
+    };
+
+    let key = decrypt_secret(config, &context, wrapped.trim())?;
+    let key = UnboundKey::new(&AES_256_GCM, &key)
+        .map_err(|_| AppError::Internal("Invalid user key".to_string()))?;
+    Ok(Some(LessSafeKey::new(key)))
+}
+
+fn user_key_path(config: &Config, user_id: &str) -> Result<PathBuf, AppError> {
+    if user_id.is_empty() || !user_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(AppError::Internal("Invalid user id for key storage".to_string()));
+    }
+    Ok(Path::new(&config.user_key_dir).join(format!("{}.key", user_id)))
+}
+
+fn seal(key: &LessSafeKey, context: &str, plaintext: &[u8]) -> Result<String, AppError> {
+    let nonce_bytes: [u8; NONCE_LEN] = rand::thread_rng().gen();
+
+    let mut in_out = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce_bytes),
+        Aad::from(context.as_bytes()),
+        &mut in_out,
+    )
+    .map_err(|_| AppError::Internal("Secret encryption failed".to_string()))?;
+
+    let mut sealed = nonce_bytes.to_vec();
+    sealed.extend_from_slice(&in_out);
+    Ok(URL_SAFE_NO_PAD.encode(sealed))
+}
+
+fn open(key: &LessSafeKey, context: &str, sealed: &str) -> Result<Vec<u8>, AppError> {
+    let sealed = URL_SAFE_NO_PAD
+        .decode(sealed)
+        .map_err(|_| AppError::Internal("Invalid encrypted secret".to_string()))?;
+    if sealed.len() < NONCE_LEN {
+        return Err(AppError::Internal("Invalid encrypted secret".to_string()));
+    }
+
+    let (nonce_bytes, ciphertext) = sealed.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce_bytes)
+        .map_err(|_| AppError::Internal("Invalid encrypted secret".to_string()))?;
+
+    let mut in_out = ciphertext.to_vec();
+    let plaintext = key
+        .open_in_place(nonce, Aad::from(context.as_bytes()), &mut in_out)
+        .map_err(|_| AppError::Internal("Secret decryption failed".to_string()))?;
+    Ok(plaintext.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn sealed_secrets_only_open_in_their_context() {
+        let config = test_support::config();
+        let sealed = encrypt_secret(&config, "totp:a", b"secret").unwrap();
+
+        assert_eq!(decrypt_secret(&config, "totp:a", &sealed).unwrap(), b"secret");
+        assert!(decrypt_secret(&config, "totp:b", &sealed).is_err());
+    }
+
+    #[tokio::test]
+    async fn destroying_the_user_key_shreds_user_secrets() {
+        let config = test_support::config();
+        let sealed = encrypt_user_secret(&config, "user-1", "totp:user-1", b"secret").await.unwrap();
+        assert_eq!(decrypt_user_secret(&config, "user-1", "totp:user-1", &sealed).await.unwrap(), b"secret");
+
+        destroy_user_key(&config, "user-1").await.unwrap();
+        assert!(decrypt_user_secret(&config, "user-1", "totp:user-1", &sealed).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn secrets_under_the_server_key_are_resealed() {
+        let state = test_support::state().await;
+        test_support::user(&state, "user-1").await;
+        let sealed = encrypt_secret(&state.config, &totp_context("user-1"), b"seed").unwrap();
+        sqlx::query("INSERT INTO user_totp (user_id, secret_encrypted, created_at) VALUES (?, ?, ?)")
+            .bind("user-1")
+            .bind(&sealed)
+            .bind(chrono::Utc::now())
+            .execute(state.database.pool())
+            .await
+            .unwrap();
+
+        reseal_with_user_keys(&state.database, &state.config).await.unwrap();
+
+        let (resealed,): (String,) = sqlx::query_as("SELECT secret_encrypted FROM user_totp WHERE user_id = 'user-1'")
+            .fetch_one(state.database.pool())
+            .await
+            .unwrap();
+        assert!(decrypt_secret(&state.config, &totp_context("user-1"), &resealed).is_err());
+        assert_eq!(
+            decrypt_user_secret(&state.config, "user-1", &totp_context("user-1"), &resealed).await.unwrap(),
+            b"seed"
+        );
+    }
+}
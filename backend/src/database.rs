@@ -35,13 +35,40 @@ pub async fn init_db(pool: &SqlitePool) -> Result<(), AppError> {
             did TEXT,
             created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
             updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-            is_active BOOLEAN DEFAULT TRUE
+            is_active BOOLEAN DEFAULT TRUE,
+            email_verified_at DATETIME,
+            opaque_record TEXT,
+            role TEXT NOT NULL DEFAULT 'user',
+            given_name TEXT,
+            family_name TEXT,
+            locale TEXT,
+            zoneinfo TEXT,
+            picture TEXT,
+            phone_number TEXT,
+            address TEXT,
+            deletion_scheduled_at DATETIME
         )
         "#,
     )
     .execute(pool)
     .await?;
 
+    // Accounts that existed before email verification are treated as verified
+    if add_column_if_missing(pool, "users", "email_verified_at", "DATETIME").await? {
+        sqlx::query("UPDATE users SET email_verified_at = created_at")
+            .execute(pool)
+            .await?;
+    }
+
+    // OPAQUE accounts keep their registration record here and an empty password_hash
+    add_column_if_missing(pool, "users", "opaque_record", "TEXT").await?;
+    add_column_if_missing(pool, "users", "role", "TEXT NOT NULL DEFAULT 'user'").await?;
+    for column in ["given_name", "family_name", "locale", "zoneinfo", "picture", "phone_number", "address"] {
+        add_column_if_missing(pool, "users", column, "TEXT").await?;
+    }
+    add_column_if_missing(pool, "users", "deletion_scheduled_at", "DATETIME").await?;
+    add_column_if_missing(pool, "users", "profile_sealed", "BOOLEAN NOT NULL DEFAULT 0").await?;
+
     // Create oauth_clients table
     sqlx::query(
         r#"
@@ -52,13 +79,38 @@ pub async fn init_db(pool: &SqlitePool) -> Result<(), AppError> {
             redirect_uris TEXT NOT NULL,
             scopes TEXT NOT NULL,
             created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-            is_active BOOLEAN DEFAULT TRUE
+            is_active BOOLEAN DEFAULT TRUE,
+            access_token_format TEXT NOT NULL DEFAULT 'jwt',
+            claim_mappings TEXT,
+            backchannel_token_delivery_mode TEXT,
+            backchannel_client_notification_endpoint TEXT,
+            authorization_details_types TEXT,
+            backchannel_logout_uri TEXT,
+            application_type TEXT NOT NULL DEFAULT 'web',
+            token_endpoint_auth_method TEXT NOT NULL DEFAULT 'client_secret_post',
+            access_token_lifetime_seconds INTEGER,
+            id_token_lifetime_seconds INTEGER,
+            refresh_token_idle_seconds INTEGER,
+            refresh_token_absolute_seconds INTEGER
         )
         "#,
     )
     .execute(pool)
     .await?;
 
+    add_column_if_missing(pool, "oauth_clients", "access_token_format", "TEXT NOT NULL DEFAULT 'jwt'").await?;
+    add_column_if_missing(pool, "oauth_clients", "claim_mappings", "TEXT").await?;
+    add_column_if_missing(pool, "oauth_clients", "backchannel_token_delivery_mode", "TEXT").await?;
+    add_column_if_missing(pool, "oauth_clients", "backchannel_client_notification_endpoint", "TEXT").await?;
+    add_column_if_missing(pool, "oauth_clients", "authorization_details_types", "TEXT").await?;
+    add_column_if_missing(pool, "oauth_clients", "backchannel_logout_uri", "TEXT").await?;
+    add_column_if_missing(pool, "oauth_clients", "application_type", "TEXT NOT NULL DEFAULT 'web'").await?;
+    add_column_if_missing(pool, "oauth_clients", "token_endpoint_auth_method", "TEXT NOT NULL DEFAULT 'client_secret_post'").await?;
+    add_column_if_missing(pool, "oauth_clients", "access_token_lifetime_seconds", "INTEGER").await?;
+    add_column_if_missing(pool, "oauth_clients", "id_token_lifetime_seconds", "INTEGER").await?;
+    add_column_if_missing(pool, "oauth_clients", "refresh_token_idle_seconds", "INTEGER").await?;
+    add_column_if_missing(pool, "oauth_clients", "refresh_token_absolute_seconds", "INTEGER").await?;
+
     // Create oauth_authorization_codes table
     sqlx::query(
         r#"
@@ -70,6 +122,11 @@ pub async fn init_db(pool: &SqlitePool) -> Result<(), AppError> {
             scopes TEXT,
             expires_at DATETIME NOT NULL,
             created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            nonce TEXT,
+            claims TEXT,
+            authorization_details TEXT,
+            code_challenge TEXT,
+            code_challenge_method TEXT,
             FOREIGN KEY (client_id) REFERENCES oauth_clients (id),
             FOREIGN KEY (user_id) REFERENCES users (id)
         )
@@ -78,6 +135,12 @@ pub async fn init_db(pool: &SqlitePool) -> Result<(), AppError> {
     .execute(pool)
     .await?;
 
+    add_column_if_missing(pool, "oauth_authorization_codes", "nonce", "TEXT").await?;
+    add_column_if_missing(pool, "oauth_authorization_codes", "claims", "TEXT").await?;
+    add_column_if_missing(pool, "oauth_authorization_codes", "authorization_details", "TEXT").await?;
+    add_column_if_missing(pool, "oauth_authorization_codes", "code_challenge", "TEXT").await?;
+    add_column_if_missing(pool, "oauth_authorization_codes", "code_challenge_method", "TEXT").await?;
+
     // Create oauth_refresh_tokens table
     sqlx::query(
         r#"
@@ -88,6 +151,54 @@ pub async fn init_db(pool: &SqlitePool) -> Result<(), AppError> {
             scopes TEXT,
             expires_at DATETIME NOT NULL,
             created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            authorization_details TEXT,
+            absolute_expires_at DATETIME,
+            FOREIGN KEY (client_id) REFERENCES oauth_clients (id),
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    add_column_if_missing(pool, "oauth_refresh_tokens", "authorization_details", "TEXT").await?;
+    add_column_if_missing(pool, "oauth_refresh_tokens", "absolute_expires_at", "DATETIME").await?;
+
+    // Create oauth_consents table
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS oauth_consents (
+            user_id TEXT NOT NULL,
+            client_id TEXT NOT NULL,
+            scopes TEXT,
+            claims TEXT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (user_id, client_id),
+            FOREIGN KEY (client_id) REFERENCES oauth_clients (id),
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create ciba_requests table (Client-Initiated Backchannel Authentication)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS ciba_requests (
+            id TEXT PRIMARY KEY,
+            auth_req_id TEXT UNIQUE NOT NULL,
+            client_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            scopes TEXT,
+            binding_message TEXT,
+            client_notification_token TEXT,
+            status TEXT NOT NULL DEFAULT 'pending',
+            expires_at DATETIME NOT NULL,
+            interval_seconds INTEGER NOT NULL,
+            last_polled_at DATETIME,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
             FOREIGN KEY (client_id) REFERENCES oauth_clients (id),
             FOREIGN KEY (user_id) REFERENCES users (id)
         )
@@ -96,5 +207,433 @@ pub async fn init_db(pool: &SqlitePool) -> Result<(), AppError> {
     .execute(pool)
     .await?;
 
+    // Create oauth_access_tokens table (opaque reference tokens, stored hashed)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS oauth_access_tokens (
+            token_hash TEXT PRIMARY KEY,
+            client_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            scopes TEXT,
+            expires_at DATETIME NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            authorization_details TEXT,
+            FOREIGN KEY (client_id) REFERENCES oauth_clients (id),
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    add_column_if_missing(pool, "oauth_access_tokens", "authorization_details", "TEXT").await?;
+
+    // Create revoked_access_tokens table (JWT access tokens revoked before they expire, by jti)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS revoked_access_tokens (
+            jti TEXT PRIMARY KEY,
+            expires_at DATETIME NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create oauth_par_requests table (pushed authorization requests)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS oauth_par_requests (
+            request_uri TEXT PRIMARY KEY,
+            client_id TEXT NOT NULL,
+            parameters TEXT NOT NULL,
+            expires_at DATETIME NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (client_id) REFERENCES oauth_clients (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create sessions table (first-party logins and their refresh tokens)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS sessions (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            refresh_token_hash TEXT UNIQUE NOT NULL,
+            previous_token_hash TEXT,
+            device_name TEXT,
+            user_agent TEXT,
+            ip_address TEXT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            last_used_at DATETIME NOT NULL,
+            expires_at DATETIME NOT NULL,
+            absolute_expires_at DATETIME NOT NULL,
+            revoked_at DATETIME,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create user_totp table (TOTP second factor, secrets encrypted)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS user_totp (
+            user_id TEXT PRIMARY KEY,
+            secret_encrypted TEXT NOT NULL,
+            confirmed_at DATETIME,
+            last_used_step INTEGER,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create recovery_codes table (one-time MFA fallback codes)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS recovery_codes (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            code_hash TEXT NOT NULL,
+            used_at DATETIME,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create email_verification_tokens table
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS email_verification_tokens (
+            token_hash TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            email TEXT NOT NULL,
+            expires_at DATETIME NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create password_reset_tokens table
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS password_reset_tokens (
+            token_hash TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            expires_at DATETIME NOT NULL,
+            used_at DATETIME,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create email_change_tokens table (pending address changes)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS email_change_tokens (
+            token_hash TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            new_email TEXT NOT NULL,
+            expires_at DATETIME NOT NULL,
+            used_at DATETIME,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create account_deletion_tokens table (cancel a scheduled deletion)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS account_deletion_tokens (
+            token_hash TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            expires_at DATETIME NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create login_throttles table (failed login counters per account and IP)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS login_throttles (
+            key TEXT PRIMARY KEY,
+            failures INTEGER NOT NULL,
+            last_failure_at DATETIME NOT NULL,
+            locked_until DATETIME
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create account_unlock_tokens table
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS account_unlock_tokens (
+            token_hash TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            expires_at DATETIME NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create opaque_server_setup table (a single row: OPRF seed and AKE private key, encrypted)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS opaque_server_setup (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            secret_encrypted TEXT NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create opaque_registrations table (registrations between their two messages)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS opaque_registrations (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            username TEXT NOT NULL,
+            email TEXT NOT NULL,
+            expires_at DATETIME NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    add_column_if_missing(pool, "opaque_registrations", "client_ip", "TEXT").await?;
+
+    // Create opaque_logins table (handshakes waiting for the client's KE3)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS opaque_logins (
+            id TEXT PRIMARY KEY,
+            user_id TEXT,
+            email TEXT NOT NULL,
+            expected_client_mac TEXT NOT NULL,
+            expires_at DATETIME NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    add_column_if_missing(pool, "opaque_logins", "client_ip", "TEXT").await?;
+
+    // Create did_challenges table (messages to sign for DID login or linking)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS did_challenges (
+            id TEXT PRIMARY KEY,
+            did TEXT NOT NULL,
+            user_id TEXT,
+            purpose TEXT NOT NULL,
+            message TEXT NOT NULL,
+            expires_at DATETIME NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create export_signing_key table (a single row: Ed25519 key signing data exports, encrypted)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS export_signing_key (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            private_key_encrypted TEXT NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create managed_did_keys table (DID keys held for users, encrypted)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS managed_did_keys (
+            did TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            verification_method TEXT NOT NULL,
+            private_key_encrypted TEXT NOT NULL,
+            also_known_as TEXT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Earlier identifiers of the DID (JSON array), published as alsoKnownAs
+    add_column_if_missing(pool, "managed_did_keys", "also_known_as", "TEXT").await?;
+
+    // Create account_exports table (bundles exported here; a move out must present one)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS account_exports (
+            jti TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            expires_at DATETIME NOT NULL,
+            used_at DATETIME,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create account_forwards table (users who moved to another instance; outlives the account)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS account_forwards (
+            subject TEXT PRIMARY KEY,
+            did TEXT,
+            destination_issuer TEXT NOT NULL,
+            destination_subject TEXT NOT NULL,
+            destination_did TEXT,
+            record TEXT NOT NULL,
+            did_document TEXT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create siop_requests table (self-issued ID token requests awaiting a response)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS siop_requests (
+            id TEXT PRIMARY KEY,
+            nonce TEXT NOT NULL,
+            purpose TEXT NOT NULL,
+            user_id TEXT,
+            expires_at DATETIME NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create magic_link_tokens table (binding_hash ties a link to the browser that asked for it)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS magic_link_tokens (
+            token_hash TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            binding_hash TEXT NOT NULL,
+            expires_at DATETIME NOT NULL,
+            used_at DATETIME,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create webauthn_credentials table
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS webauthn_credentials (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            credential_id TEXT UNIQUE NOT NULL,
+            public_key TEXT NOT NULL,
+            sign_count INTEGER NOT NULL DEFAULT 0,
+            name TEXT NOT NULL,
+            transports TEXT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            last_used_at DATETIME,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create webauthn_challenges table
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS webauthn_challenges (
+            id TEXT PRIMARY KEY,
+            user_id TEXT,
+            challenge TEXT NOT NULL,
+            ceremony TEXT NOT NULL,
+            expires_at DATETIME NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
     Ok(())
 }
+
+// Tables are created with IF NOT EXISTS, so columns added after a database
+// was first initialized have to be added explicitly. Returns whether the
+// column was added.
+async fn add_column_if_missing(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<bool, AppError> {
+    let existing: Option<(String,)> =
+        sqlx::query_as("SELECT name FROM pragma_table_info(?) WHERE name = ?")
+            .bind(table)
+            .bind(column)
+            .fetch_optional(pool)
+            .await?;
+
+    if existing.is_some() {
+        return Ok(false);
+    }
+
+    sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+        .execute(pool)
+        .await?;
+
+    Ok(true)
+}
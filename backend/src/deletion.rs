@@ -0,0 +1,295 @@
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+    response::Json as ResponseJson,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    auth_user::FirstPartyUser,
+    crypto::{destroy_user_key, generate_token, hash_token},
+    error::AppError,
+    jwt::create_logout_token,
+    lockout::clear_account,
+    mailer::Email,
+    models::{OAuthClient, User, UserResponse},
+    oauth::revoke_user_tokens,
+    sessions::revoke_all_sessions,
+    AppState,
+};
+
+// Account deletion. A deleted account is first only scheduled for erasure:
+// it cannot be used, and the emailed link restores it until the grace
+// period ends. Erasure then removes every row belonging to the account,
+// tells relying parties through back-channel logout and destroys the
+// account's data key (see `crypto::encrypt_user_secret`).
+const PURGE_INTERVAL_SECONDS: u64 = 3600;
+const LOGOUT_TOKEN_LIFETIME_SECONDS: i64 = 120;
+const LOGOUT_REQUEST_TIMEOUT_SECONDS: u64 = 10;
+
+// Tables holding rows of a user, in an order that satisfies foreign keys
+const USER_TABLES: &[&str] = &[
+    "oauth_authorization_codes",
+    "oauth_refresh_tokens",
+    "oauth_access_tokens",
+    "oauth_consents",
+    "ciba_requests",
+    "sessions",
+    "user_totp",
+    "recovery_codes",
+    "webauthn_credentials",
+    "webauthn_challenges",
+    "email_verification_tokens",
+    "email_change_tokens",
+    "password_reset_tokens",
+    "magic_link_tokens",
+    "account_unlock_tokens",
+    "account_deletion_tokens",
+    "opaque_registrations",
+    "opaque_logins",
+    "did_challenges",
+    "managed_did_keys",
+    "siop_requests",
+    "account_exports",
+];
+
+#[derive(Debug, Serialize)]
+pub struct DeletionResponse {
+    pub deletion_scheduled_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CancelDeletionRequest {
+    pub token: String,
+}
+
+// Signs the user out everywhere; signing back in is not possible until the
+// deletion is cancelled
+pub async fn request_deletion(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+) -> Result<(StatusCode, ResponseJson<DeletionResponse>), AppError> {
+    let deletion_scheduled_at = schedule_deletion(&state, &auth.user).await?;
+    Ok((StatusCode::ACCEPTED, ResponseJson(DeletionResponse { deletion_scheduled_at })))
+}
+
+pub async fn cancel_deletion(
+    State(state): State<AppState>,
+    Json(payload): Json<CancelDeletionRequest>,
+) -> Result<ResponseJson<UserResponse>, AppError> {
+    let now = Utc::now();
+
+    let (user_id,): (String,) = sqlx::query_as(
+        "DELETE FROM account_deletion_tokens WHERE token_hash = ? AND expires_at > ? RETURNING user_id"
+    )
+    .bind(hash_token(&payload.token))
+    .bind(now)
+    .fetch_optional(state.database.pool())
+    .await?
+    .ok_or_else(|| AppError::Validation("Invalid or expired cancellation token".to_string()))?;
+
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users SET deletion_scheduled_at = NULL, updated_at = ?
+        WHERE id = ? AND deletion_scheduled_at > ?
+        RETURNING *
+        "#
+    )
+    .bind(now)
+    .bind(&user_id)
+    .bind(now)
+    .fetch_optional(state.database.pool())
+    .await?
+    .ok_or_else(|| AppError::Validation("Invalid or expired cancellation token".to_string()))?;
+
+    // A move to another instance is called off with the deletion
+    sqlx::query("DELETE FROM account_forwards WHERE subject = ?")
+        .bind(&user.id)
+        .execute(state.database.pool())
+        .await?;
+
+    info!("Deletion of user {} cancelled", user.id);
+    Ok(ResponseJson(user.into()))
+}
+
+pub async fn schedule_deletion(state: &AppState, user: &User) -> Result<DateTime<Utc>, AppError> {
+    if let Some(scheduled_at) = user.deletion_scheduled_at {
+        return Ok(scheduled_at);
+    }
+
+    let now = Utc::now();
+    let deletion_scheduled_at = now + Duration::days(state.config.account_deletion_grace_days);
+
+    sqlx::query("UPDATE users SET deletion_scheduled_at = ?, updated_at = ? WHERE id = ?")
+        .bind(deletion_scheduled_at)
+        .bind(now)
+        .bind(&user.id)
+        .execute(state.database.pool())
+        .await?;
+
+    revoke_all_sessions(state, &user.id).await?;
+    revoke_user_tokens(state, &user.id).await?;
+
+    let token = generate_token();
+    sqlx::query(
+        r#"
+        INSERT INTO account_deletion_tokens (token_hash, user_id, expires_at, created_at)
+        VALUES (?, ?, ?, ?)
+        "#
+    )
+    .bind(hash_token(&token))
+    .bind(&user.id)
+    .bind(deletion_scheduled_at)
+    .bind(now)
+    .execute(state.database.pool())
+    .await?;
+
+    // The account is already signed out everywhere, so the deletion stands
+    // even if the mail is lost
+    let link = format!("{}/cancel-deletion?token={}", state.config.frontend_url, token);
+    let sent = state
+        .mailer
+        .send(Email {
+            to: user.email.clone(),
+            subject: "Your account will be deleted".to_string(),
+            body: format!(
+                "Hello {},\n\nYour account is scheduled for deletion on {}. Until then you can keep it \
+                 by opening this link:\n\n{}\n\nAfter that date your account and its data are erased \
+                 for good.\n",
+                user.username,
+                deletion_scheduled_at.format("%Y-%m-%d %H:%M UTC"),
+                link
+            ),
+        })
+        .await;
+    if let Err(e) = sent {
+        warn!("Deletion email for user {} failed: {}", user.id, e);
+    }
+
+    info!("User {} scheduled for deletion on {}", user.id, deletion_scheduled_at);
+    Ok(deletion_scheduled_at)
+}
+
+// Erase an account now, whether or not its grace period is over
+pub async fn erase_user(state: &AppState, user_id: &str) -> Result<(), AppError> {
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(state.database.pool())
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    // Clients that ever got tokens or consent for the user; collected before
+    // the rows that link them are deleted
+    let clients = sqlx::query_as::<_, OAuthClient>(
+        r#"
+        SELECT * FROM oauth_clients
+        WHERE backchannel_logout_uri IS NOT NULL AND id IN (
+            SELECT client_id FROM oauth_consents WHERE user_id = ?
+            UNION SELECT client_id FROM oauth_refresh_tokens WHERE user_id = ?
+            UNION SELECT client_id FROM oauth_access_tokens WHERE user_id = ?
+            UNION SELECT client_id FROM oauth_authorization_codes WHERE user_id = ?
+        )
+        "#
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_all(state.database.pool())
+    .await?;
+
+    // The key goes first: should deleting the rows fail, they are already
+    // unreadable, and the next purge run deletes them
+    destroy_user_key(&state.config, user_id).await?;
+
+    let mut tx = state.database.pool().begin().await?;
+    for table in USER_TABLES {
+        sqlx::query(&format!("DELETE FROM {} WHERE user_id = ?", table))
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    sqlx::query("DELETE FROM users WHERE id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    clear_account(state, &user.email).await?;
+
+    for client in &clients {
+        notify_client(state, client, user_id);
+    }
+
+    info!("User {} erased, {} clients notified", user_id, clients.len());
+    Ok(())
+}
+
+// Erase accounts whose grace period is over. Runs for the lifetime of the
+// server; an account that fails is retried on the next run, without holding
+// up the others.
+pub async fn run_purge(state: AppState) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(PURGE_INTERVAL_SECONDS));
+    loop {
+        interval.tick().await;
+        if let Err(e) = purge_due_accounts(&state).await {
+            warn!("Purging deleted accounts failed: {}", e);
+        }
+    }
+}
+
+async fn purge_due_accounts(state: &AppState) -> Result<(), AppError> {
+    let due: Vec<(String,)> = sqlx::query_as("SELECT id FROM users WHERE deletion_scheduled_at <= ?")
+        .bind(Utc::now())
+        .fetch_all(state.database.pool())
+        .await?;
+
+    for (user_id,) in due {
+        if let Err(e) = erase_user(state, &user_id).await {
+            warn!("Erasing user {} failed: {}", user_id, e);
+        }
+    }
+    Ok(())
+}
+
+// Logout tokens are sent in the background; a relying party that is down
+// misses the event, as with any back-channel logout
+fn notify_client(state: &AppState, client: &OAuthClient, user_id: &str) {
+    let Some(endpoint) = client.backchannel_logout_uri.clone() else {
+        return;
+    };
+    if client.is_public() {
+        return;
+    }
+
+    let logout_token = match create_logout_token(
+        user_id,
+        &client.id,
+        &client.client_secret,
+        Duration::seconds(LOGOUT_TOKEN_LIFETIME_SECONDS),
+        &state.config,
+    ) {
+        Ok(token) => token,
+        Err(e) => {
+            warn!("Logout token for client {} failed: {}", client.id, e);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        let result = reqwest::Client::new()
+            .post(&endpoint)
+            .timeout(std::time::Duration::from_secs(LOGOUT_REQUEST_TIMEOUT_SECONDS))
+            .form(&[("logout_token", logout_token)])
+            .send()
+            .await;
+
+        match result {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => warn!("Back-channel logout to {} failed with {}", endpoint, response.status()),
+            Err(e) => warn!("Back-channel logout to {} failed: {}", endpoint, e),
+        }
+    });
+}
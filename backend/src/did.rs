@@ -2,186 +2,640 @@ use axum::{
     extract::{Json, Path, State},
     response::Json as ResponseJson,
 };
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use reqwest::Url;
+use ring::{
+    rand::SystemRandom,
+    signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey},
+};
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
 use chrono::Utc;
+use std::net::{IpAddr, SocketAddr};
+use tracing::warn;
+
+use crate::{
+    auth_user::FirstPartyUser,
+    config::Config,
+    crypto::{decrypt_user_secret, encrypt_user_secret},
+    error::AppError,
+    models::ManagedDidKey,
+    AppState,
+};
 
-use crate::{error::AppError, AppState};
+// Multicodec prefix of an Ed25519 public key in did:key and publicKeyMultibase
+const ED25519_MULTICODEC: [u8; 2] = [0xed, 0x01];
+// Largest document accepted from a did:web host
+const MAX_DOCUMENT_BYTES: usize = 64 * 1024;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateDidRequest {
     pub method: String, // "key", "web", etc.
-    pub user_id: String,
 }
 
+// DID Core (https://www.w3.org/TR/did-core/) JSON representation. The
+// snake_case names of earlier versions are still read, e.g. from documents
+// served by an instance not yet upgraded.
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct DidDocument {
     pub id: String,
+    #[serde(rename = "@context", alias = "context", default)]
     pub context: Vec<String>,
+    #[serde(alias = "verification_method", default)]
     pub verification_method: Vec<VerificationMethod>,
-    pub authentication: Vec<String>,
-    pub created: String,
-    pub updated: String,
+    #[serde(default)]
+    pub authentication: Vec<VerificationRelationship>,
+    #[serde(alias = "also_known_as", default, skip_serializing_if = "Vec::is_empty")]
+    pub also_known_as: Vec<String>, // Other identifiers of the subject, e.g. after a move
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct VerificationMethod {
     pub id: String,
     pub r#type: String,
     pub controller: String,
+    #[serde(alias = "public_key_multibase", skip_serializing_if = "Option::is_none")]
+    pub public_key_multibase: Option<String>,
+    #[serde(alias = "public_key_base58", skip_serializing_if = "Option::is_none")]
     pub public_key_base58: Option<String>,
+    #[serde(alias = "public_key_jwk", skip_serializing_if = "Option::is_none")]
     pub public_key_jwk: Option<serde_json::Value>,
 }
 
+// Verification relationships list methods by reference or embed them
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum VerificationRelationship {
+    Reference(String),
+    Embedded(VerificationMethod),
+}
+
+// Create a DID for the authenticated user, replacing any previous one
 pub async fn create_did(
     State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
     Json(payload): Json<CreateDidRequest>,
 ) -> Result<ResponseJson<serde_json::Value>, AppError> {
-    // Validate user exists
-    let user = sqlx::query!("SELECT id FROM users WHERE id = ?", payload.user_id)
-        .fetch_optional(state.database.pool())
-        .await?
-        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+    let did = replace_managed_did(&state, &auth.user.id, &payload.method).await?;
 
-    let did = match payload.method.as_str() {
+    Ok(ResponseJson(serde_json::json!({
+        "did": did.id,
+        "document": did
+    })))
+}
+
+// Swap the user's DID and its server-held key in one transaction, so that
+// keys of earlier DIDs can no longer be used for self-issued ID tokens
+async fn replace_managed_did(state: &AppState, user_id: &str, method: &str) -> Result<DidDocument, AppError> {
+    let (did, pkcs8) = match method {
         "key" => create_did_key().await?,
-        "web" => create_did_web(&payload.user_id).await?,
+        "web" => create_did_web(&web_did(&state.config, user_id)).await?,
         _ => return Err(AppError::Validation("Unsupported DID method".to_string())),
     };
+    let private_key_encrypted =
+        encrypt_user_secret(&state.config, user_id, &managed_key_context(&did.id), &pkcs8).await?;
+    let now = Utc::now();
+
+    let mut tx = state.database.pool().begin().await?;
 
-    // Update user with DID
-    sqlx::query!(
-        "UPDATE users SET did = ?, updated_at = ? WHERE id = ?",
-        did.id,
-        Utc::now(),
-        payload.user_id
+    sqlx::query("DELETE FROM managed_did_keys WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    // The key stays with the server, which can then act as the user's
+    // self-issued OpenID provider
+    sqlx::query(
+        r#"
+        INSERT INTO managed_did_keys (did, user_id, verification_method, private_key_encrypted, created_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#
     )
-    .execute(state.database.pool())
+    .bind(&did.id)
+    .bind(user_id)
+    .bind(&did.verification_method[0].id)
+    .bind(&private_key_encrypted)
+    .bind(now)
+    .execute(&mut *tx)
     .await?;
 
-    Ok(ResponseJson(serde_json::json!({
-        "did": did.id,
-        "document": did
-    })))
+    sqlx::query("UPDATE users SET did = ?, updated_at = ? WHERE id = ?")
+        .bind(&did.id)
+        .bind(now)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(did)
 }
 
 pub async fn resolve_did(
     State(_state): State<AppState>,
     Path(did): Path<String>,
 ) -> Result<ResponseJson<DidDocument>, AppError> {
-    // This is a simplified implementation
-    // In practice, you'd resolve the DID from the appropriate registry
+    Ok(ResponseJson(resolve(&did).await?))
+}
+
+// Document of a did:web identifier hosted here, served at
+// /users/<id>/did.json. A user who moved to another instance keeps the
+// document frozen at the time of the move, pointing at the new identifier.
+pub async fn web_did_document(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+) -> Result<ResponseJson<DidDocument>, AppError> {
+    let did = web_did(&state.config, &user_id);
+
+    let forwarded: Option<(String,)> = sqlx::query_as(
+        "SELECT did_document FROM account_forwards WHERE did = ? AND subject = ? AND did_document IS NOT NULL"
+    )
+    .bind(&did)
+    .bind(&user_id)
+    .fetch_optional(state.database.pool())
+    .await?;
+    if let Some((document,)) = forwarded {
+        let document = serde_json::from_str(&document)
+            .map_err(|e| AppError::Internal(format!("Invalid stored DID document: {}", e)))?;
+        return Ok(ResponseJson(document));
+    }
+
+    // Only the user the path names may hold the key of this identifier
+    let (owned,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM managed_did_keys WHERE did = ? AND user_id = ?")
+        .bind(&did)
+        .bind(&user_id)
+        .fetch_one(state.database.pool())
+        .await?;
+    if owned == 0 {
+        return Err(AppError::NotFound("DID not found".to_string()));
+    }
+
+    managed_did_document(&state, &did)
+        .await?
+        .map(ResponseJson)
+        .ok_or_else(|| AppError::NotFound("DID not found".to_string()))
+}
+
+// did:web identifier of a user of this instance, derived from the issuer URL
+pub fn web_did(config: &Config, user_id: &str) -> String {
+    let issuer = config.issuer.split_once("://").map(|(_, rest)| rest).unwrap_or(&config.issuer);
+    let mut segments = issuer.split('/').filter(|segment| !segment.is_empty());
+    let host = segments.next().unwrap_or("localhost").replace(':', "%3A");
+
+    let mut did = format!("did:web:{}", host);
+    for segment in segments {
+        did.push(':');
+        did.push_str(segment);
+    }
+    format!("{}:users:{}", did, user_id)
+}
+
+// Whether a DID names a did:web document served by this instance
+pub fn is_local_web_did(config: &Config, did: &str) -> bool {
+    did.to_lowercase().starts_with(&web_did(config, "").to_lowercase())
+}
+
+// Document of a DID whose key this server holds
+pub async fn managed_did_document(state: &AppState, did: &str) -> Result<Option<DidDocument>, AppError> {
+    let Some(key) = sqlx::query_as::<_, ManagedDidKey>("SELECT * FROM managed_did_keys WHERE did = ?")
+        .bind(did)
+        .fetch_optional(state.database.pool())
+        .await?
+    else {
+        return Ok(None);
+    };
+
+    let pkcs8 =
+        decrypt_user_secret(&state.config, &key.user_id, &managed_key_context(did), &key.private_key_encrypted).await?;
+    let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+        .map_err(|_| AppError::Internal("Invalid managed DID key".to_string()))?;
+
+    let mut document = ed25519_document(
+        did.to_string(),
+        key.verification_method,
+        encode_ed25519_multibase(key_pair.public_key().as_ref()),
+    );
+    document.also_known_as = key
+        .also_known_as
+        .and_then(|also_known_as| serde_json::from_str(&also_known_as).ok())
+        .unwrap_or_default();
+    document.created = Some(key.created_at.to_rfc3339());
+    document.updated = None;
+    Ok(Some(document))
+}
+
+pub async fn resolve(did: &str) -> Result<DidDocument, AppError> {
     if did.starts_with("did:key:") {
-        resolve_did_key(&did).await
+        resolve_did_key(did)
+    } else if did.starts_with("did:jwk:") {
+        resolve_did_jwk(did)
     } else if did.starts_with("did:web:") {
-        resolve_did_web(&did).await
+        resolve_did_web(did).await
     } else {
         Err(AppError::NotFound("DID not found or unsupported method".to_string()))
     }
 }
 
-async fn create_did_key() -> Result<DidDocument, AppError> {
-    use ring::signature::{Ed25519KeyPair, KeyPair};
-    use ring::rand::SystemRandom;
-    use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+// The method listed under `authentication` with this id, which may be
+// relative to the document (e.g. "#key-1")
+pub fn authentication_method(document: &DidDocument, method_id: &str) -> Result<VerificationMethod, AppError> {
+    let absolute = |id: &str| match id.strip_prefix('#') {
+        Some(fragment) => format!("{}#{}", document.id, fragment),
+        None => id.to_string(),
+    };
+    let wanted = absolute(method_id);
+
+    document
+        .authentication
+        .iter()
+        .find_map(|relationship| match relationship {
+            VerificationRelationship::Reference(id) if absolute(id) == wanted => document
+                .verification_method
+                .iter()
+                .find(|method| absolute(&method.id) == wanted)
+                .cloned(),
+            VerificationRelationship::Embedded(method) if absolute(&method.id) == wanted => Some(method.clone()),
+            _ => None,
+        })
+        .ok_or_else(|| AppError::Authentication("Key is not an authentication method of the DID".to_string()))
+}
 
+// Verify a signature made with a verification method's key. Ed25519 and
+// P-256 keys are supported; P-256 signatures are r || s.
+pub fn verify_signature(method: &VerificationMethod, message: &[u8], signature: &[u8]) -> Result<(), AppError> {
+    let (algorithm, public_key): (&'static dyn signature::VerificationAlgorithm, Vec<u8>) =
+        if let Some(jwk) = &method.public_key_jwk {
+            jwk_public_key(jwk)?
+        } else if let Some(multibase) = &method.public_key_multibase {
+            (&signature::ED25519, decode_ed25519_multibase(multibase)?)
+        } else if let Some(base58) = &method.public_key_base58 {
+            let key = bs58::decode(base58)
+                .into_vec()
+                .map_err(|_| AppError::Validation("Invalid publicKeyBase58".to_string()))?;
+            (&signature::ED25519, key)
+        } else {
+            return Err(AppError::Validation("Verification method has no supported public key".to_string()));
+        };
+
+    UnparsedPublicKey::new(algorithm, public_key)
+        .verify(message, signature)
+        .map_err(|_| AppError::Authentication("Invalid signature".to_string()))
+}
+
+fn jwk_public_key(jwk: &serde_json::Value) -> Result<(&'static dyn signature::VerificationAlgorithm, Vec<u8>), AppError> {
+    let coordinate = |name: &str| {
+        jwk.get(name)
+            .and_then(|value| value.as_str())
+            .and_then(|value| URL_SAFE_NO_PAD.decode(value).ok())
+            .ok_or_else(|| AppError::Validation(format!("JWK is missing {}", name)))
+    };
+
+    match (jwk.get("kty").and_then(|v| v.as_str()), jwk.get("crv").and_then(|v| v.as_str())) {
+        (Some("OKP"), Some("Ed25519")) => Ok((&signature::ED25519, coordinate("x")?)),
+        (Some("EC"), Some("P-256")) => {
+            // Uncompressed SEC1 point
+            let mut point = vec![0x04];
+            point.extend(coordinate("x")?);
+            point.extend(coordinate("y")?);
+            Ok((&signature::ECDSA_P256_SHA256_FIXED, point))
+        }
+        _ => Err(AppError::Validation("Unsupported JWK key type".to_string())),
+    }
+}
+
+fn encode_ed25519_multibase(public_key: &[u8]) -> String {
+    let mut bytes = ED25519_MULTICODEC.to_vec();
+    bytes.extend_from_slice(public_key);
+    format!("z{}", bs58::encode(bytes).into_string())
+}
+
+fn decode_ed25519_multibase(multibase: &str) -> Result<Vec<u8>, AppError> {
+    let invalid = || AppError::Validation("Unsupported or invalid multibase Ed25519 key".to_string());
+
+    let encoded = multibase.strip_prefix('z').ok_or_else(invalid)?; // base58btc
+    let bytes = bs58::decode(encoded).into_vec().map_err(|_| invalid())?;
+    match bytes.strip_prefix(&ED25519_MULTICODEC) {
+        Some(key) if key.len() == 32 => Ok(key.to_vec()),
+        _ => Err(invalid()),
+    }
+}
+
+// Associated data for a managed key, binding the ciphertext to its DID
+pub fn managed_key_context(did: &str) -> String {
+    format!("managed-did-key:{}", did)
+}
+
+// Returns the PKCS#8 document and the public key
+fn generate_ed25519_key() -> Result<(Vec<u8>, Vec<u8>), AppError> {
     let rng = SystemRandom::new();
-    let key_pair = Ed25519KeyPair::generate_pkcs8(&rng)
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
         .map_err(|e| AppError::Internal(format!("Key generation failed: {:?}", e)))?;
-    
-    let public_key = key_pair.public_key();
-    let public_key_b58 = URL_SAFE_NO_PAD.encode(public_key.as_ref());
-    
-    let did_id = format!("did:key:z{}", public_key_b58);
-    let vm_id = format!("{}#key-1", did_id);
+    let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+        .map_err(|e| AppError::Internal(format!("Key generation failed: {:?}", e)))?;
+    Ok((pkcs8.as_ref().to_vec(), key_pair.public_key().as_ref().to_vec()))
+}
 
-    Ok(DidDocument {
+fn ed25519_document(did_id: String, method_id: String, public_key_multibase: String) -> DidDocument {
+    DidDocument {
         id: did_id.clone(),
         context: vec![
             "https://www.w3.org/ns/did/v1".to_string(),
             "https://w3id.org/security/suites/ed25519-2020/v1".to_string(),
         ],
         verification_method: vec![VerificationMethod {
-            id: vm_id.clone(),
+            id: method_id.clone(),
             r#type: "Ed25519VerificationKey2020".to_string(),
-            controller: did_id.clone(),
-            public_key_base58: Some(public_key_b58),
+            controller: did_id,
+            public_key_multibase: Some(public_key_multibase),
+            public_key_base58: None,
             public_key_jwk: None,
         }],
-        authentication: vec![vm_id],
-        created: Utc::now().to_rfc3339(),
-        updated: Utc::now().to_rfc3339(),
-    })
+        authentication: vec![VerificationRelationship::Reference(method_id)],
+        also_known_as: Vec::new(),
+        created: Some(Utc::now().to_rfc3339()),
+        updated: Some(Utc::now().to_rfc3339()),
+    }
+}
+
+async fn create_did_key() -> Result<(DidDocument, Vec<u8>), AppError> {
+    let (pkcs8, public_key) = generate_ed25519_key()?;
+
+    // did:key:z<base58btc(multicodec || key)>, the key's own fragment names it
+    let fingerprint = encode_ed25519_multibase(&public_key);
+    let did_id = format!("did:key:{}", fingerprint);
+    let vm_id = format!("{}#{}", did_id, fingerprint);
+
+    Ok((ed25519_document(did_id, vm_id, fingerprint), pkcs8))
 }
 
-async fn create_did_web(user_id: &str) -> Result<DidDocument, AppError> {
-    let domain = "localhost:8000"; // In practice, use configured domain
-    let did_id = format!("did:web:{}:users:{}", domain, user_id);
+async fn create_did_web(did_id: &str) -> Result<(DidDocument, Vec<u8>), AppError> {
+    let did_id = did_id.to_string();
     let vm_id = format!("{}#key-1", did_id);
 
-    // Generate a key pair for the DID:web method
-    use ring::signature::{Ed25519KeyPair, KeyPair};
-    use ring::rand::SystemRandom;
-    use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+    let (pkcs8, public_key) = generate_ed25519_key()?;
+    Ok((ed25519_document(did_id, vm_id, encode_ed25519_multibase(&public_key)), pkcs8))
+}
 
-    let rng = SystemRandom::new();
-    let key_pair = Ed25519KeyPair::generate_pkcs8(&rng)
-        .map_err(|e| AppError::Internal(format!("Key generation failed: {:?}", e)))?;
-    
-    let public_key = key_pair.public_key();
-    let public_key_b58 = URL_SAFE_NO_PAD.encode(public_key.as_ref());
+// did:key documents are derived from the identifier itself
+fn resolve_did_key(did: &str) -> Result<DidDocument, AppError> {
+    let fingerprint = &did["did:key:".len()..];
+    let mut document = if decode_ed25519_multibase(fingerprint).is_ok() {
+        ed25519_document(did.to_string(), format!("{}#{}", did, fingerprint), fingerprint.to_string())
+    } else {
+        // Identifiers created by earlier versions hold the bare key in
+        // base64url after the "z", with the method named "#key-1"
+        let public_key = fingerprint
+            .strip_prefix('z')
+            .and_then(|key| URL_SAFE_NO_PAD.decode(key).ok())
+            .filter(|key| key.len() == 32)
+            .ok_or_else(|| AppError::NotFound("Invalid or unsupported did:key".to_string()))?;
+        ed25519_document(did.to_string(), format!("{}#key-1", did), encode_ed25519_multibase(&public_key))
+    };
+    document.created = None;
+    document.updated = None;
+    Ok(document)
+}
+
+// did:jwk:<base64url(JWK)> has a single key, "#0"
+fn resolve_did_jwk(did: &str) -> Result<DidDocument, AppError> {
+    let invalid = || AppError::NotFound("Invalid did:jwk".to_string());
+
+    let jwk: serde_json::Value = URL_SAFE_NO_PAD
+        .decode(&did["did:jwk:".len()..])
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(invalid)?;
+    // A private key has no place in an identifier
+    if !jwk.is_object() || jwk.get("d").is_some() {
+        return Err(invalid());
+    }
 
+    let method_id = format!("{}#0", did);
     Ok(DidDocument {
-        id: did_id.clone(),
+        id: did.to_string(),
         context: vec![
             "https://www.w3.org/ns/did/v1".to_string(),
-            "https://w3id.org/security/suites/ed25519-2020/v1".to_string(),
+            "https://w3id.org/security/suites/jws-2020/v1".to_string(),
         ],
         verification_method: vec![VerificationMethod {
-            id: vm_id.clone(),
-            r#type: "Ed25519VerificationKey2020".to_string(),
-            controller: did_id.clone(),
-            public_key_base58: Some(public_key_b58),
-            public_key_jwk: None,
+            id: method_id.clone(),
+            r#type: "JsonWebKey2020".to_string(),
+            controller: did.to_string(),
+            public_key_multibase: None,
+            public_key_base58: None,
+            public_key_jwk: Some(jwk),
         }],
-        authentication: vec![vm_id],
-        created: Utc::now().to_rfc3339(),
-        updated: Utc::now().to_rfc3339(),
+        authentication: vec![VerificationRelationship::Reference(method_id)],
+        also_known_as: Vec::new(),
+        created: None,
+        updated: None,
     })
 }
 
-async fn resolve_did_key(did: &str) -> Result<ResponseJson<DidDocument>, AppError> {
-    // Extract public key from DID
-    if !did.starts_with("did:key:z") {
-        return Err(AppError::NotFound("Invalid DID:key format".to_string()));
-    }
+// did:web:<host>[:path...] is fetched from https://<host>/<path>/did.json,
+// or /.well-known/did.json without a path
+async fn resolve_did_web(did: &str) -> Result<DidDocument, AppError> {
+    let not_found = || AppError::NotFound("DID not found".to_string());
 
-    let public_key_b58 = &did[9..]; // Remove "did:key:z" prefix
-    let vm_id = format!("{}#key-1", did);
+    let mut segments = did["did:web:".len()..].split(':');
+    let host = segments.next().filter(|host| !host.is_empty()).ok_or_else(not_found)?;
+    let host = host.replace("%3A", ":").replace("%3a", ":");
+    let path: Vec<&str> = segments.collect();
 
-    let doc = DidDocument {
-        id: did.to_string(),
-        context: vec![
-            "https://www.w3.org/ns/did/v1".to_string(),
-            "https://w3id.org/security/suites/ed25519-2020/v1".to_string(),
-        ],
-        verification_method: vec![VerificationMethod {
-            id: vm_id.clone(),
-            r#type: "Ed25519VerificationKey2020".to_string(),
-            controller: did.to_string(),
-            public_key_base58: Some(public_key_b58.to_string()),
-            public_key_jwk: None,
-        }],
-        authentication: vec![vm_id],
-        created: Utc::now().to_rfc3339(),
-        updated: Utc::now().to_rfc3339(),
+    let path = if path.is_empty() {
+        ".well-known".to_string()
+    } else {
+        path.join("/")
     };
+    let url = Url::parse(&format!("https://{}/{}/did.json", host, path)).map_err(|_| not_found())?;
 
-    Ok(ResponseJson(doc))
+    let body = fetch_public(&url)
+        .await
+        .ok_or_else(|| AppError::NotFound("DID document could not be fetched".to_string()))?;
+    let document: DidDocument = serde_json::from_slice(&body)
+        .map_err(|_| AppError::Validation("Invalid DID document".to_string()))?;
+    if document.id != did {
+        return Err(AppError::Validation("DID document is for another DID".to_string()));
+    }
+    Ok(document)
+}
+
+// GET a small document from a host named by a client. The host must only
+// resolve to public addresses, and the connection is pinned to the address
+// checked, so a second lookup cannot point it inside the network. Redirects
+// are not followed. Failures are not detailed to the caller.
+pub async fn fetch_public(url: &Url) -> Option<Vec<u8>> {
+    let host = url.host_str()?.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default()?;
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await.ok()?.collect();
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public(addr.ip())) {
+        warn!("Refused to fetch {}: not a public address", url);
+        return None;
+    }
+
+    let client = reqwest::Client::builder()
+        .resolve(host, addrs[0])
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(std::time::Duration::from_secs(10))
+        .build()
+        .ok()?;
+    let mut response = client.get(url.clone()).send().await.ok()?;
+    if !response.status().is_success()
+        || response.content_length().is_some_and(|len| len > MAX_DOCUMENT_BYTES as u64)
+    {
+        return None;
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.ok()? {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_DOCUMENT_BYTES {
+            return None;
+        }
+    }
+    Some(body)
 }
 
-async fn resolve_did_web(did: &str) -> Result<ResponseJson<DidDocument>, AppError> {
-    // This is a simplified implementation
-    // In practice, you'd fetch the DID document from the web location
-    Err(AppError::NotFound("DID:web resolution not fully implemented".to_string()))
+// Globally routable unicast addresses only
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || a >= 240
+                || (a == 100 && (64..128).contains(&b)) // Shared address space
+                || (a == 192 && b == 0 && ip.octets()[2] == 0) // IETF protocol assignments
+                || (a == 198 && (18..20).contains(&b))) // Benchmarking
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || (first & 0xfe00) == 0xfc00 // Unique local
+                || (first & 0xffc0) == 0xfe80 // Link local
+                || (first == 0x2001 && ip.segments()[1] == 0x0db8) // Documentation
+                || (first == 0x0064 && ip.segments()[1] == 0xff9b)) // NAT64, embeds IPv4
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_legacy_did_key() {
+        let public_key = [7u8; 32];
+        let did = format!("did:key:z{}", URL_SAFE_NO_PAD.encode(public_key));
+
+        let document = resolve_did_key(&did).unwrap();
+        let method = authentication_method(&document, "#key-1").unwrap();
+        assert_eq!(decode_ed25519_multibase(method.public_key_multibase.as_deref().unwrap()).unwrap(), public_key);
+    }
+
+    #[test]
+    fn reads_snake_case_documents() {
+        let document: DidDocument = serde_json::from_value(serde_json::json!({
+            "id": "did:web:example.com",
+            "context": ["https://www.w3.org/ns/did/v1"],
+            "verification_method": [{
+                "id": "did:web:example.com#key-1",
+                "type": "Ed25519VerificationKey2020",
+                "controller": "did:web:example.com",
+                "public_key_base58": "11111111111111111111111111111111",
+                "public_key_jwk": null
+            }],
+            "authentication": ["did:web:example.com#key-1"],
+            "created": "2024-01-01T00:00:00Z",
+            "updated": "2024-01-01T00:00:00Z"
+        }))
+        .unwrap();
+
+        let method = authentication_method(&document, "#key-1").unwrap();
+        assert!(method.public_key_base58.is_some());
+        assert_eq!(document.context.len(), 1);
+    }
+
+    #[test]
+    fn multibase_keys_round_trip() {
+        let public_key = [42u8; 32];
+        let multibase = encode_ed25519_multibase(&public_key);
+
+        assert!(multibase.starts_with("z6Mk"));
+        assert_eq!(decode_ed25519_multibase(&multibase).unwrap(), public_key);
+        assert!(decode_ed25519_multibase(&multibase[1..]).is_err());
+        assert!(decode_ed25519_multibase(&encode_ed25519_multibase(&[42u8; 31])).is_err());
+    }
+
+    #[tokio::test]
+    async fn created_did_keys_resolve_to_their_document() {
+        let (created, _) = create_did_key().await.unwrap();
+        let resolved = resolve_did_key(&created.id).unwrap();
+
+        assert_eq!(resolved.id, created.id);
+        assert_eq!(resolved.verification_method[0].id, created.verification_method[0].id);
+        assert_eq!(resolved.verification_method[0].public_key_multibase, created.verification_method[0].public_key_multibase);
+    }
+
+    #[test]
+    fn web_dids_follow_the_issuer_url() {
+        let mut config = crate::test_support::config();
+        assert_eq!(web_did(&config, "u1"), "did:web:id.example.com:users:u1");
+
+        config.issuer = "https://id.example.com:8443/idp/".to_string();
+        assert_eq!(web_did(&config, "u1"), "did:web:id.example.com%3A8443:idp:users:u1");
+        assert!(is_local_web_did(&config, "did:web:ID.example.com%3A8443:idp:users:u2"));
+        assert!(!is_local_web_did(&config, "did:web:id.example.com:users:u2"));
+        assert!(!is_local_web_did(&config, "did:web:other.example:users:u2"));
+    }
+
+    #[tokio::test]
+    async fn creating_a_did_again_replaces_the_previous_one() {
+        let state = crate::test_support::state().await;
+        crate::test_support::user(&state, "u1").await;
+
+        replace_managed_did(&state, "u1", "key").await.unwrap();
+        replace_managed_did(&state, "u1", "web").await.unwrap();
+        let last = replace_managed_did(&state, "u1", "web").await.unwrap();
+        assert_eq!(last.id, web_did(&state.config, "u1"));
+
+        let keys: Vec<(String,)> = sqlx::query_as("SELECT did FROM managed_did_keys WHERE user_id = 'u1'")
+            .fetch_all(state.database.pool())
+            .await
+            .unwrap();
+        assert_eq!(keys, vec![(last.id.clone(),)]);
+
+        let (did,): (Option<String>,) = sqlx::query_as("SELECT did FROM users WHERE id = 'u1'")
+            .fetch_one(state.database.pool())
+            .await
+            .unwrap();
+        assert_eq!(did, Some(last.id));
+    }
+
+    #[test]
+    fn only_public_addresses_are_fetched() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+                   "::1", "fe80::1", "fd00::1", "::ffff:127.0.0.1", "64:ff9b::a00:1"] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
 }
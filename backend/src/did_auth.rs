@@ -0,0 +1,210 @@
+use axum::{
+    extract::{ConnectInfo, Json, State},
+    http::HeaderMap,
+    response::Json as ResponseJson,
+};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::{
+    auth::finish_first_factor,
+    auth_user::FirstPartyUser,
+    crypto::generate_token,
+    did::{authentication_method, resolve, verify_signature},
+    error::AppError,
+    lockout::LoginAttempt,
+    models::{LoginOutcome, User, UserResponse},
+    oauth::find_active_user,
+    sessions::SessionMetadata,
+    AppState,
+};
+
+// Proof of DID control: the server issues a message with a nonce, and the
+// holder signs it with a key from the DID document's `authentication`
+// relationship. Used to sign in with a linked DID and to link one.
+const CHALLENGE_EXPIRY_SECONDS: i64 = 300;
+
+const PURPOSE_LOGIN: &str = "login";
+const PURPOSE_LINK: &str = "link";
+
+#[derive(Debug, Deserialize)]
+pub struct DidChallengeRequest {
+    pub did: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DidChallengeResponse {
+    pub challenge_id: String,
+    pub message: String, // Signed as UTF-8 bytes
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DidProofRequest {
+    pub challenge_id: String,
+    pub verification_method: String, // Id of the signing key in the DID document
+    pub signature: String,           // base64url
+    pub device_name: Option<String>,
+}
+
+pub async fn start_login(
+    State(state): State<AppState>,
+    Json(payload): Json<DidChallengeRequest>,
+) -> Result<ResponseJson<DidChallengeResponse>, AppError> {
+    let response = create_challenge(&state, &payload.did, PURPOSE_LOGIN, None).await?;
+    Ok(ResponseJson(response))
+}
+
+pub async fn finish_login(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<DidProofRequest>,
+) -> Result<ResponseJson<LoginOutcome>, AppError> {
+    let (did, message, _) = take_challenge(&state, &payload.challenge_id, PURPOSE_LOGIN).await?;
+
+    let mut attempt = LoginAttempt::new(&did, addr.ip());
+    attempt.begin(&state).await?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE did = ?")
+        .bind(&did)
+        .fetch_optional(state.database.pool())
+        .await?;
+
+    if let Err(e) = verify_proof(&did, &message, &payload).await {
+        attempt.record_failure(&state, user.as_ref()).await?;
+        return Err(e);
+    }
+    let user = user.ok_or_else(|| AppError::Authentication("No account is linked to this DID".to_string()))?;
+    attempt.record_success(&state).await?;
+
+    let metadata = SessionMetadata::from_request(&headers, addr, payload.device_name);
+    let outcome = finish_first_factor(&state, user, &metadata).await?;
+
+    Ok(ResponseJson(outcome))
+}
+
+pub async fn start_link(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Json(payload): Json<DidChallengeRequest>,
+) -> Result<ResponseJson<DidChallengeResponse>, AppError> {
+    let user = auth.user;
+
+    let response = create_challenge(&state, &payload.did, PURPOSE_LINK, Some(&user.id)).await?;
+    Ok(ResponseJson(response))
+}
+
+// Replace the account's DID with one the user proved control of
+pub async fn finish_link(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Json(payload): Json<DidProofRequest>,
+) -> Result<ResponseJson<UserResponse>, AppError> {
+    let user = auth.user;
+
+    let (did, message, user_id) = take_challenge(&state, &payload.challenge_id, PURPOSE_LINK).await?;
+    if user_id.as_deref() != Some(user.id.as_str()) {
+        return Err(AppError::Validation("Invalid or expired challenge".to_string()));
+    }
+    verify_proof(&did, &message, &payload).await?;
+
+    let (linked,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE did = ? AND id != ?")
+        .bind(&did)
+        .bind(&user.id)
+        .fetch_one(state.database.pool())
+        .await?;
+    if linked > 0 {
+        return Err(AppError::Validation("DID is linked to another account".to_string()));
+    }
+
+    sqlx::query("UPDATE users SET did = ?, updated_at = ? WHERE id = ?")
+        .bind(&did)
+        .bind(Utc::now())
+        .bind(&user.id)
+        .execute(state.database.pool())
+        .await?;
+
+    let user = find_active_user(&state, &user.id).await?;
+    Ok(ResponseJson(user.into()))
+}
+
+async fn create_challenge(
+    state: &AppState,
+    did: &str,
+    purpose: &str,
+    user_id: Option<&str>,
+) -> Result<DidChallengeResponse, AppError> {
+    if !did.starts_with("did:") {
+        return Err(AppError::Validation("Invalid DID".to_string()));
+    }
+
+    // The message names the issuer, DID and purpose, so a signature cannot
+    // be replayed elsewhere or for the other flow
+    let now = Utc::now();
+    let message = format!(
+        "{} asks you to prove control of {}\n\nPurpose: {}\nNonce: {}\nIssued At: {}",
+        state.config.issuer,
+        did,
+        purpose,
+        generate_token(),
+        now.to_rfc3339()
+    );
+
+    let challenge_id = Uuid::new_v4().to_string();
+    sqlx::query(
+        r#"
+        INSERT INTO did_challenges (id, did, user_id, purpose, message, expires_at, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(&challenge_id)
+    .bind(did)
+    .bind(user_id)
+    .bind(purpose)
+    .bind(&message)
+    .bind(now + Duration::seconds(CHALLENGE_EXPIRY_SECONDS))
+    .bind(now)
+    .execute(state.database.pool())
+    .await?;
+
+    Ok(DidChallengeResponse {
+        challenge_id,
+        message,
+        expires_in: CHALLENGE_EXPIRY_SECONDS as u64,
+    })
+}
+
+// Challenges are single use, whatever the outcome
+async fn take_challenge(
+    state: &AppState,
+    challenge_id: &str,
+    purpose: &str,
+) -> Result<(String, String, Option<String>), AppError> {
+    sqlx::query_as(
+        r#"
+        DELETE FROM did_challenges
+        WHERE id = ? AND purpose = ? AND expires_at > ?
+        RETURNING did, message, user_id
+        "#
+    )
+    .bind(challenge_id)
+    .bind(purpose)
+    .bind(Utc::now())
+    .fetch_optional(state.database.pool())
+    .await?
+    .ok_or_else(|| AppError::Validation("Invalid or expired challenge".to_string()))
+}
+
+async fn verify_proof(did: &str, message: &str, proof: &DidProofRequest) -> Result<(), AppError> {
+    let signature = URL_SAFE_NO_PAD
+        .decode(&proof.signature)
+        .map_err(|_| AppError::Validation("Invalid signature encoding".to_string()))?;
+
+    let document = resolve(did).await?;
+    let method = authentication_method(&document, &proof.verification_method)?;
+    verify_signature(&method, message.as_bytes(), &signature)
+}
@@ -0,0 +1,87 @@
+use axum::{extract::State, response::Json as ResponseJson};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
+
+use crate::{ciba, claims, error::AppError, oauth, AppState};
+
+// OpenID Connect Discovery 1.0
+pub async fn openid_configuration(
+    State(state): State<AppState>,
+) -> Result<ResponseJson<Value>, AppError> {
+    let mut metadata = server_metadata(&state).await?;
+    let issuer = &state.config.issuer;
+
+    metadata.extend(
+        json!({
+            "userinfo_endpoint": format!("{}/oauth/userinfo", issuer),
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": oauth::SIGNING_ALGS_SUPPORTED,
+            "claims_supported": claims::CLAIMS_SUPPORTED,
+            "claims_parameter_supported": true,
+            "backchannel_logout_supported": true,
+            "backchannel_logout_session_supported": false,
+        })
+        .as_object()
+        .cloned()
+        .unwrap_or_default(),
+    );
+
+    Ok(ResponseJson(Value::Object(metadata)))
+}
+
+// OAuth 2.0 Authorization Server Metadata (RFC 8414)
+pub async fn authorization_server_metadata(
+    State(state): State<AppState>,
+) -> Result<ResponseJson<Value>, AppError> {
+    Ok(ResponseJson(Value::Object(server_metadata(&state).await?)))
+}
+
+// Fields shared by both documents, derived from what this server implements
+async fn server_metadata(state: &AppState) -> Result<Map<String, Value>, AppError> {
+    let issuer = &state.config.issuer;
+
+    let mut metadata = json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/oauth/authorize", issuer),
+        "token_endpoint": format!("{}/oauth/token", issuer),
+        "pushed_authorization_request_endpoint": format!("{}/oauth/par", issuer),
+        "introspection_endpoint": format!("{}/oauth/introspect", issuer),
+        "revocation_endpoint": format!("{}/oauth/revoke", issuer),
+        "backchannel_authentication_endpoint": format!("{}/oauth/bc-authorize", issuer),
+        "scopes_supported": claims::SCOPES_SUPPORTED,
+        "response_types_supported": oauth::RESPONSE_TYPES_SUPPORTED,
+        "grant_types_supported": oauth::GRANT_TYPES_SUPPORTED,
+        "token_endpoint_auth_methods_supported": oauth::TOKEN_ENDPOINT_AUTH_METHODS_SUPPORTED,
+        "introspection_endpoint_auth_methods_supported": oauth::CLIENT_AUTH_METHODS_SUPPORTED,
+        "revocation_endpoint_auth_methods_supported": oauth::CLIENT_AUTH_METHODS_SUPPORTED,
+        "backchannel_token_delivery_modes_supported": ciba::DELIVERY_MODES_SUPPORTED,
+        "code_challenge_methods_supported": oauth::CODE_CHALLENGE_METHODS_SUPPORTED,
+    });
+
+    if state.config.client_registration_enabled {
+        metadata["registration_endpoint"] = json!(format!("{}/oauth/register", issuer));
+    }
+
+    let authorization_details_types = authorization_details_types(state).await?;
+    if !authorization_details_types.is_empty() {
+        metadata["authorization_details_types_supported"] = json!(authorization_details_types);
+    }
+
+    Ok(metadata.as_object().cloned().unwrap_or_default())
+}
+
+// Union of the RAR types registered by active clients
+async fn authorization_details_types(state: &AppState) -> Result<BTreeSet<String>, AppError> {
+    let rows: Vec<(Option<String>,)> = sqlx::query_as(
+        "SELECT authorization_details_types FROM oauth_clients WHERE is_active = TRUE"
+    )
+    .fetch_all(state.database.pool())
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|(types,)| types)
+        .filter_map(|types| serde_json::from_str::<Map<String, Value>>(&types).ok())
+        .flat_map(|types| types.into_iter().map(|(name, _)| name))
+        .collect())
+}
@@ -24,6 +24,9 @@ pub enum AppError {
     
     #[error("Not found: {0}")]
     NotFound(String),
+
+    #[error("Too many requests: {0}")]
+    RateLimited(String),
     
     #[error("Internal server error: {0}")]
     Internal(String),
@@ -38,6 +41,7 @@ impl IntoResponse for AppError {
             AppError::Authentication(e) => (StatusCode::UNAUTHORIZED, e),
             AppError::Authorization(e) => (StatusCode::FORBIDDEN, e),
             AppError::NotFound(e) => (StatusCode::NOT_FOUND, e),
+            AppError::RateLimited(e) => (StatusCode::TOO_MANY_REQUESTS, e),
             AppError::Internal(e) => (StatusCode::INTERNAL_SERVER_ERROR, e),
         };
 
@@ -1,86 +1,228 @@
+use axum::http::{header::AUTHORIZATION, HeaderMap};
 use jsonwebtoken::{encode, decode, Header, Algorithm, Validation, EncodingKey, DecodingKey};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use chrono::{Utc, Duration};
-use crate::error::AppError;
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: String, // Subject (user ID)
-    exp: usize,  // Expiration time
-    iat: usize,  // Issued at
-    token_type: String, // "access" or "refresh"
+use uuid::Uuid;
+use crate::{config::Config, error::AppError};
+
+// JWT profile for OAuth 2.0 access tokens (RFC 9068)
+pub const ACCESS_TOKEN_TYP: &str = "at+jwt";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub iss: String,       // Issuer
+    pub sub: String,       // Subject (user ID)
+    pub aud: String,       // Audience (resource server)
+    pub exp: usize,        // Expiration time
+    pub nbf: usize,        // Not before
+    pub iat: usize,        // Issued at
+    pub jti: String,       // Unique token identifier
+    pub client_id: String, // Client the token was issued to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>, // First-party session the token belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>, // Space-separated granted scopes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authorization_details: Option<Value>, // Granted RAR details (RFC 9396)
 }
 
-pub fn create_access_token(user_id: &str, secret: &str, expiration_minutes: u64) -> Result<String, AppError> {
+pub fn create_access_token(
+    user_id: &str,
+    client_id: &str,
+    session_id: Option<&str>,
+    scope: Option<&str>,
+    authorization_details: Option<Value>,
+    lifetime: Duration,
+    config: &Config,
+) -> Result<String, AppError> {
     let now = Utc::now();
-    let exp = now + Duration::minutes(expiration_minutes as i64);
+    let exp = now + lifetime;
 
     let claims = Claims {
+        iss: config.issuer.clone(),
         sub: user_id.to_string(),
+        aud: config.access_token_audience.clone(),
         exp: exp.timestamp() as usize,
+        nbf: now.timestamp() as usize,
         iat: now.timestamp() as usize,
-        token_type: "access".to_string(),
+        jti: Uuid::new_v4().to_string(),
+        client_id: client_id.to_string(),
+        sid: session_id.map(str::to_string),
+        scope: scope.filter(|s| !s.is_empty()).map(str::to_string),
+        authorization_details,
     };
 
+    let mut header = Header::new(Algorithm::HS256);
+    header.typ = Some(ACCESS_TOKEN_TYP.to_string());
+
     let token = encode(
-        &Header::default(),
+        &header,
         &claims,
-        &EncodingKey::from_secret(secret.as_ref()),
+        &EncodingKey::from_secret(config.jwt_secret.as_ref()),
     )?;
 
     Ok(token)
 }
 
-pub fn create_refresh_token(user_id: &str, secret: &str) -> Result<String, AppError> {
+// ID tokens are signed with the client secret (OpenID Connect Core 1.0,
+// section 10.1) so relying parties can verify them without extra keys
+pub fn create_id_token(
+    user_claims: Map<String, Value>,
+    client_id: &str,
+    client_secret: &str,
+    nonce: Option<&str>,
+    lifetime: Duration,
+    config: &Config,
+) -> Result<String, AppError> {
     let now = Utc::now();
-    let exp = now + Duration::days(30); // Refresh tokens last 30 days
+    let exp = now + lifetime;
+
+    let mut claims = user_claims;
+    claims.insert("iss".to_string(), Value::String(config.issuer.clone()));
+    claims.insert("aud".to_string(), Value::String(client_id.to_string()));
+    claims.insert("exp".to_string(), Value::from(exp.timestamp()));
+    claims.insert("iat".to_string(), Value::from(now.timestamp()));
+    if let Some(nonce) = nonce {
+        claims.insert("nonce".to_string(), Value::String(nonce.to_string()));
+    }
 
-    let claims = Claims {
+    let token = encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(client_secret.as_ref()),
+    )?;
+
+    Ok(token)
+}
+
+// OpenID Connect Back-Channel Logout 1.0, section 2.4. Sent when a user
+// is gone for good, so it names the subject rather than a session; signed
+// like ID tokens.
+pub const LOGOUT_TOKEN_TYP: &str = "logout+jwt";
+pub const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+pub fn create_logout_token(
+    user_id: &str,
+    client_id: &str,
+    client_secret: &str,
+    lifetime: Duration,
+    config: &Config,
+) -> Result<String, AppError> {
+    let now = Utc::now();
+    let claims = serde_json::json!({
+        "iss": config.issuer,
+        "sub": user_id,
+        "aud": client_id,
+        "iat": now.timestamp(),
+        "exp": (now + lifetime).timestamp(),
+        "jti": Uuid::new_v4().to_string(),
+        "events": { BACKCHANNEL_LOGOUT_EVENT: {} },
+    });
+
+    let mut header = Header::new(Algorithm::HS256);
+    header.typ = Some(LOGOUT_TOKEN_TYP.to_string());
+
+    let token = encode(
+        &header,
+        &claims,
+        &EncodingKey::from_secret(client_secret.as_ref()),
+    )?;
+
+    Ok(token)
+}
+
+// Short-lived token proving the first factor of a login, exchanged for a
+// session once the second factor is verified
+pub const MFA_TOKEN_TYP: &str = "mfa+jwt";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MfaClaims {
+    iss: String,
+    sub: String, // User ID
+    aud: String, // The issuer itself, never a resource server
+    exp: usize,
+    iat: usize,
+    jti: String,
+}
+
+pub fn create_mfa_token(user_id: &str, lifetime: Duration, config: &Config) -> Result<String, AppError> {
+    let now = Utc::now();
+    let claims = MfaClaims {
+        iss: config.issuer.clone(),
         sub: user_id.to_string(),
-        exp: exp.timestamp() as usize,
+        aud: config.issuer.clone(),
+        exp: (now + lifetime).timestamp() as usize,
         iat: now.timestamp() as usize,
-        token_type: "refresh".to_string(),
+        jti: Uuid::new_v4().to_string(),
     };
 
+    let mut header = Header::new(Algorithm::HS256);
+    header.typ = Some(MFA_TOKEN_TYP.to_string());
+
     let token = encode(
-        &Header::default(),
+        &header,
         &claims,
-        &EncodingKey::from_secret(secret.as_ref()),
+        &EncodingKey::from_secret(config.jwt_secret.as_ref()),
     )?;
 
     Ok(token)
 }
 
-pub fn verify_access_token(token: &str, secret: &str) -> Result<String, AppError> {
-    let mut validation = Validation::default();
-    validation.algorithms = vec![Algorithm::HS256];
+// Returns the user ID the first factor was verified for
+pub fn verify_mfa_token(token: &str, config: &Config) -> Result<String, AppError> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.leeway = config.jwt_leeway_seconds;
+    validation.set_issuer(&[&config.issuer]);
+    validation.set_audience(&[&config.issuer]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
 
-    let token_data = decode::<Claims>(
+    let token_data = decode::<MfaClaims>(
         token,
-        &DecodingKey::from_secret(secret.as_ref()),
+        &DecodingKey::from_secret(config.jwt_secret.as_ref()),
         &validation,
     )?;
 
-    if token_data.claims.token_type != "access" {
+    if token_data.header.typ.as_deref() != Some(MFA_TOKEN_TYP) {
         return Err(AppError::Authentication("Invalid token type".to_string()));
     }
 
     Ok(token_data.claims.sub)
 }
 
-pub fn verify_refresh_token(token: &str, secret: &str) -> Result<String, AppError> {
-    let mut validation = Validation::default();
-    validation.algorithms = vec![Algorithm::HS256];
+pub fn verify_access_token(token: &str, config: &Config) -> Result<Claims, AppError> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.leeway = config.jwt_leeway_seconds;
+    validation.validate_nbf = true;
+    validation.set_issuer(&[&config.issuer]);
+    validation.set_audience(&[&config.access_token_audience]);
+    validation.set_required_spec_claims(&["exp", "nbf", "iss", "aud", "sub"]);
 
     let token_data = decode::<Claims>(
         token,
-        &DecodingKey::from_secret(secret.as_ref()),
+        &DecodingKey::from_secret(config.jwt_secret.as_ref()),
         &validation,
     )?;
 
-    if token_data.claims.token_type != "refresh" {
+    // Reject anything that is not explicitly typed as an access token,
+    // e.g. ID tokens or refresh tokens signed with the same key
+    let is_access_token = token_data.header.typ.as_deref().is_some_and(|typ| {
+        typ.eq_ignore_ascii_case(ACCESS_TOKEN_TYP)
+            || typ.eq_ignore_ascii_case("application/at+jwt")
+    });
+    if !is_access_token {
         return Err(AppError::Authentication("Invalid token type".to_string()));
     }
 
-    Ok(token_data.claims.sub)
+    Ok(token_data.claims)
+}
+
+pub fn bearer_token(headers: &HeaderMap) -> Result<&str, AppError> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| AppError::Authentication("Missing bearer token".to_string()))
 }
@@ -0,0 +1,294 @@
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use sqlx::{Sqlite, Transaction};
+use std::net::IpAddr;
+use tracing::warn;
+
+use crate::{
+    crypto::{generate_token, hash_token},
+    error::AppError,
+    mailer::Email,
+    models::User,
+    AppState,
+};
+
+// Failed logins are counted per account and per client IP. Past a few free
+// attempts every failure doubles the wait before the next attempt; enough
+// failures on one account lock it until the lockout expires or the owner
+// unlocks it from the emailed link.
+//
+// Accounts are keyed by the email as typed, so unknown emails are throttled
+// exactly like real ones and responses do not reveal which exist.
+const FAILURE_WINDOW_MINUTES: i64 = 60; // Counters reset after this long without failures
+const BASE_DELAY_SECONDS: i64 = 1;
+const MAX_DELAY_SECONDS: i64 = 15 * 60;
+const LOCKOUT_MINUTES: i64 = 60;
+const UNLOCK_EXPIRY_MINUTES: i64 = 60;
+
+struct Policy {
+    free_attempts: i64,
+    lockout_after: Option<i64>,
+}
+
+const ACCOUNT_POLICY: Policy = Policy { free_attempts: 3, lockout_after: Some(10) };
+const IP_POLICY: Policy = Policy { free_attempts: 10, lockout_after: None };
+const REQUEST_POLICY: Policy = Policy { free_attempts: 5, lockout_after: None };
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockRequest {
+    pub token: String,
+}
+
+// The counters consulted for one login attempt. An attempt is counted as a
+// failure before the credentials are checked, in one transaction with the
+// lockout check, so parallel guesses cannot all slip past the limit.
+pub struct LoginAttempt {
+    account_key: String,
+    ip_key: String,
+    account_failures: i64,
+}
+
+impl LoginAttempt {
+    pub fn new(email: &str, ip: IpAddr) -> Self {
+        LoginAttempt {
+            account_key: account_key(email),
+            ip_key: format!("ip:{}", ip),
+            account_failures: 0,
+        }
+    }
+
+    // Refuse early, without counting, while either counter is backing off
+    pub async fn check(&self, state: &AppState) -> Result<(), AppError> {
+        let now = Utc::now();
+        for key in [&self.account_key, &self.ip_key] {
+            let locked = locked_until(state, key)
+                .await?
+                .is_some_and(|locked_until| locked_until > now);
+            if locked {
+                return Err(AppError::RateLimited("Too many failed attempts, try again later".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    // Count the attempt against both keys, refusing it while either is
+    // backing off. Must precede the credential check.
+    pub async fn begin(&mut self, state: &AppState) -> Result<(), AppError> {
+        let now = Utc::now();
+        let mut tx = state.database.pool().begin().await?;
+
+        let mut counts = Vec::with_capacity(2);
+        for (key, policy) in [(&self.ip_key, &IP_POLICY), (&self.account_key, &ACCOUNT_POLICY)] {
+            match count_attempt(&mut tx, key, policy, now).await? {
+                Some(failures) => counts.push(failures),
+                // Dropping the transaction rolls back the other counter
+                None => return Err(AppError::RateLimited("Too many failed attempts, try again later".to_string())),
+            }
+        }
+        tx.commit().await?;
+
+        self.account_failures = counts[1];
+        Ok(())
+    }
+
+    // The failure was counted by `begin`. `user` is the account the email
+    // belongs to, if any; it is emailed an unlock link when this failure
+    // locks it.
+    pub async fn record_failure(&self, state: &AppState, user: Option<&User>) -> Result<(), AppError> {
+        warn!("Failed login from {}", self.ip_key);
+
+        if ACCOUNT_POLICY.lockout_after == Some(self.account_failures) {
+            if let Some(user) = user {
+                warn!("Account {} locked after {} failed logins", user.id, self.account_failures);
+                // Sent after the response, so a locking failure answers as
+                // fast as any other
+                let (state, user) = (state.clone(), user.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = send_unlock_email(&state, &user).await {
+                        warn!("Unlock email for user {} failed: {}", user.id, e);
+                    }
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Take back what `begin` counted: the account starts over, the IP gets
+    // its attempt back
+    pub async fn record_success(&self, state: &AppState) -> Result<(), AppError> {
+        clear(state, &self.account_key).await?;
+
+        let now = Utc::now();
+        let mut tx = state.database.pool().begin().await?;
+        let failures: Option<(i64,)> = sqlx::query_as(
+            "UPDATE login_throttles SET failures = MAX(failures - 1, 0) WHERE key = ? RETURNING failures"
+        )
+        .bind(&self.ip_key)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some((failures,)) = failures {
+            sqlx::query("UPDATE login_throttles SET locked_until = ? WHERE key = ?")
+                .bind(backoff(failures, &IP_POLICY, now))
+                .bind(&self.ip_key)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+pub async fn unlock_account(
+    State(state): State<AppState>,
+    Json(payload): Json<UnlockRequest>,
+) -> Result<StatusCode, AppError> {
+    let (user_id,): (String,) = sqlx::query_as(
+        "SELECT user_id FROM account_unlock_tokens WHERE token_hash = ? AND expires_at > ?"
+    )
+    .bind(hash_token(&payload.token))
+    .bind(Utc::now())
+    .fetch_optional(state.database.pool())
+    .await?
+    .ok_or_else(|| AppError::Validation("Invalid or expired unlock token".to_string()))?;
+
+    sqlx::query("DELETE FROM account_unlock_tokens WHERE user_id = ?")
+        .bind(&user_id)
+        .execute(state.database.pool())
+        .await?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(&user_id)
+        .fetch_one(state.database.pool())
+        .await?;
+    clear(&state, &account_key(&user.email)).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Forget the failure counter of an account, e.g. when it is erased
+pub async fn clear_account(state: &AppState, email: &str) -> Result<(), AppError> {
+    clear(state, &account_key(email)).await
+}
+
+// Costly unauthenticated requests, such as bundle imports, are counted per
+// client IP whatever their outcome, with the same backoff as failed logins
+pub async fn throttle_request(state: &AppState, scope: &str, ip: IpAddr) -> Result<(), AppError> {
+    let mut tx = state.database.pool().begin().await?;
+    let counted = count_attempt(&mut tx, &format!("{}:ip:{}", scope, ip), &REQUEST_POLICY, Utc::now()).await?;
+    tx.commit().await?;
+
+    if counted.is_none() {
+        return Err(AppError::RateLimited("Too many requests, try again later".to_string()));
+    }
+    Ok(())
+}
+
+fn account_key(email: &str) -> String {
+    format!("account:{}", email.trim().to_lowercase())
+}
+
+async fn locked_until(state: &AppState, key: &str) -> Result<Option<DateTime<Utc>>, AppError> {
+    let row: Option<(Option<DateTime<Utc>>,)> = sqlx::query_as("SELECT locked_until FROM login_throttles WHERE key = ?")
+        .bind(key)
+        .fetch_optional(state.database.pool())
+        .await?;
+    Ok(row.and_then(|(locked_until,)| locked_until))
+}
+
+// Count an attempt and set the resulting backoff, returning the failure
+// count, or None without counting while the key is backing off. The upsert
+// comes first, so the transaction holds the write lock from then on.
+async fn count_attempt(
+    tx: &mut Transaction<'_, Sqlite>,
+    key: &str,
+    policy: &Policy,
+    now: DateTime<Utc>,
+) -> Result<Option<i64>, AppError> {
+    let (failures, locked_until): (i64, Option<DateTime<Utc>>) = sqlx::query_as(
+        r#"
+        INSERT INTO login_throttles (key, failures, last_failure_at)
+        VALUES (?, 1, ?)
+        ON CONFLICT (key) DO UPDATE SET
+            failures = CASE
+                WHEN locked_until > ? THEN failures
+                WHEN last_failure_at < ? THEN 1
+                ELSE failures + 1
+            END,
+            last_failure_at = CASE WHEN locked_until > ? THEN last_failure_at ELSE excluded.last_failure_at END
+        RETURNING failures, locked_until
+        "#
+    )
+    .bind(key)
+    .bind(now)
+    .bind(now)
+    .bind(now - Duration::minutes(FAILURE_WINDOW_MINUTES))
+    .bind(now)
+    .fetch_one(&mut **tx)
+    .await?;
+
+    if locked_until.is_some_and(|locked_until| locked_until > now) {
+        return Ok(None);
+    }
+
+    sqlx::query("UPDATE login_throttles SET locked_until = ? WHERE key = ?")
+        .bind(backoff(failures, policy, now))
+        .bind(key)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(Some(failures))
+}
+
+fn backoff(failures: i64, policy: &Policy, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if policy.lockout_after.is_some_and(|limit| failures >= limit) {
+        Some(now + Duration::minutes(LOCKOUT_MINUTES))
+    } else if failures > policy.free_attempts {
+        let exponent = (failures - policy.free_attempts - 1).min(20) as u32;
+        let delay = (BASE_DELAY_SECONDS << exponent).min(MAX_DELAY_SECONDS);
+        Some(now + Duration::seconds(delay))
+    } else {
+        None
+    }
+}
+
+async fn clear(state: &AppState, key: &str) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM login_throttles WHERE key = ?")
+        .bind(key)
+        .execute(state.database.pool())
+        .await?;
+    Ok(())
+}
+
+async fn send_unlock_email(state: &AppState, user: &User) -> Result<(), AppError> {
+    let token = generate_token();
+    let now = Utc::now();
+
+    sqlx::query(
+        "INSERT INTO account_unlock_tokens (token_hash, user_id, expires_at, created_at) VALUES (?, ?, ?, ?)"
+    )
+    .bind(hash_token(&token))
+    .bind(&user.id)
+    .bind(now + Duration::minutes(UNLOCK_EXPIRY_MINUTES))
+    .bind(now)
+    .execute(state.database.pool())
+    .await?;
+
+    let link = format!("{}/unlock?token={}", state.config.frontend_url, token);
+    state
+        .mailer
+        .send(Email {
+            to: user.email.clone(),
+            subject: "Your account has been locked".to_string(),
+            body: format!(
+                "Hello {},\n\nYour account was locked after repeated failed sign-in attempts. \
+                 It unlocks by itself in {} minutes, or right away from this link:\n\n{}\n\n\
+                 If these attempts were not yours, consider changing your password.\n",
+                user.username, LOCKOUT_MINUTES, link
+            ),
+        })
+        .await
+}
@@ -0,0 +1,137 @@
+use async_trait::async_trait;
+use lettre::{
+    message::Mailbox,
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use chrono::{Duration, Utc};
+use std::{path::PathBuf, sync::Arc};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{config::Config, error::AppError, AppState};
+
+// Emails carrying a token go out at most once a minute and five times an
+// hour per user, counted from the rows of the token table
+const MIN_EMAIL_INTERVAL_SECONDS: i64 = 60;
+const MAX_EMAILS_PER_HOUR: i64 = 5;
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String, // Plain text
+}
+
+// Outgoing mail. Implementations are chosen by the MAILER setting.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), AppError>;
+}
+
+pub async fn is_throttled(state: &AppState, token_table: &str, user_id: &str) -> Result<bool, AppError> {
+    let now = Utc::now();
+
+    let (recent, last_hour): (i64, i64) = sqlx::query_as(&format!(
+        "SELECT COALESCE(SUM(created_at > ?), 0), COUNT(*) FROM {} WHERE user_id = ? AND created_at > ?",
+        token_table
+    ))
+    .bind(now - Duration::seconds(MIN_EMAIL_INTERVAL_SECONDS))
+    .bind(user_id)
+    .bind(now - Duration::hours(1))
+    .fetch_one(state.database.pool())
+    .await?;
+
+    Ok(recent > 0 || last_hour >= MAX_EMAILS_PER_HOUR)
+}
+
+pub fn from_config(config: &Config) -> Result<Arc<dyn Mailer>, AppError> {
+    let from: Mailbox = config
+        .mail_from
+        .parse()
+        .map_err(|e| AppError::Internal(format!("Invalid MAIL_FROM: {}", e)))?;
+
+    match config.mailer.as_str() {
+        "smtp" => Ok(Arc::new(SmtpMailer::new(config, from)?)),
+        "file" => Ok(Arc::new(FileOutboxMailer {
+            from,
+            directory: PathBuf::from(&config.mail_outbox_dir),
+        })),
+        other => Err(AppError::Internal(format!("Unknown MAILER: {}", other))),
+    }
+}
+
+fn build_message(from: &Mailbox, email: Email) -> Result<Message, AppError> {
+    let to: Mailbox = email
+        .to
+        .parse()
+        .map_err(|e| AppError::Validation(format!("Invalid recipient address: {}", e)))?;
+
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(email.subject)
+        .body(email.body)
+        .map_err(|e| AppError::Internal(format!("Building email failed: {}", e)))
+}
+
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+    fn new(config: &Config, from: Mailbox) -> Result<Self, AppError> {
+        let builder = if config.smtp_starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)
+                .map_err(|e| AppError::Internal(format!("SMTP setup failed: {}", e)))?
+        } else {
+            // Plain connection, e.g. a local SMTP stand-in during development
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_host)
+        };
+
+        let mut builder = builder.port(config.smtp_port);
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(SmtpMailer { from, transport: builder.build() })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        let message = build_message(&self.from, email)?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| AppError::Internal(format!("Sending email failed: {}", e)))?;
+        Ok(())
+    }
+}
+
+// Writes each message as an .eml file instead of sending it
+pub struct FileOutboxMailer {
+    from: Mailbox,
+    directory: PathBuf,
+}
+
+#[async_trait]
+impl Mailer for FileOutboxMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        let message = build_message(&self.from, email)?;
+
+        tokio::fs::create_dir_all(&self.directory)
+            .await
+            .map_err(|e| AppError::Internal(format!("Creating mail outbox failed: {}", e)))?;
+
+        let path = self.directory.join(format!("{}.eml", Uuid::new_v4()));
+        tokio::fs::write(&path, message.formatted())
+            .await
+            .map_err(|e| AppError::Internal(format!("Writing email failed: {}", e)))?;
+
+        info!("Email written to {}", path.display());
+        Ok(())
+    }
+}
@@ -1,27 +1,53 @@
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
+    middleware,
     response::Json,
-    routing::{get, post},
+    routing::{delete, get, patch, post, put},
     Router,
 };
-use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 use tower_http::cors::CorsLayer;
-use tracing::{info, warn};
+use tracing::info;
 
+mod admin;
 mod auth;
+mod auth_user;
+mod ciba;
+mod claims;
+mod cli;
+mod clients;
 mod config;
+mod crypto;
 mod database;
+mod deletion;
 mod did;
+mod did_auth;
+mod discovery;
 mod error;
 mod jwt;
+mod lockout;
+mod mailer;
+mod mfa;
+mod migration;
 mod models;
 mod oauth;
+mod opaque;
+mod password_hash;
+mod passwords;
+mod portability;
+mod profile;
+mod rar;
+mod reauth;
+mod roles;
+mod sessions;
+mod siop;
+mod verification;
+mod webauthn;
+
+#[cfg(test)]
+mod test_support;
 
 use config::Config;
 use database::Database;
-use error::AppError;
 
 type AppState = Arc<AppContext>;
 
@@ -29,6 +55,8 @@ type AppState = Arc<AppContext>;
 pub struct AppContext {
     pub config: Config,
     pub database: Database,
+    pub mailer: Arc<dyn mailer::Mailer>,
+    pub password_hasher: password_hash::PasswordHasher,
 }
 
 #[tokio::main]
@@ -43,22 +71,110 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize database
     let database = Database::new(&config.database_url).await?;
     database.migrate().await?;
+    admin::grant_configured_admins(&database, &config.admin_emails).await?;
+    crypto::reseal_with_user_keys(&database, &config).await?;
+    profile::seal_existing_profiles(&database, &config).await?;
+
+    let mailer = mailer::from_config(&config)?;
+    let password_hasher = password_hash::PasswordHasher::from_config(&config)?;
 
     // Create application state
-    let state = Arc::new(AppContext { config, database });
+    let state = Arc::new(AppContext { config, database, mailer, password_hasher });
+
+    // Operator commands run instead of the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&state, &args).await;
+    }
+
+    // Erase accounts once their deletion grace period is over
+    tokio::spawn(deletion::run_purge(state.clone()));
+
+    // Routes acting on behalf of the signed-in user
+    let user_routes = Router::new()
+        .route("/auth/logout", post(auth::logout))
+        .route("/auth/password/change", post(passwords::change_password))
+        .route("/auth/mfa/totp", post(mfa::enroll_totp).delete(mfa::disable_totp))
+        .route("/auth/mfa/totp/confirm", post(mfa::confirm_totp))
+        .route("/auth/mfa/recovery-codes", post(mfa::regenerate_recovery_codes))
+        .route("/auth/webauthn/register/start", post(webauthn::start_registration))
+        .route("/auth/webauthn/register/finish", post(webauthn::finish_registration))
+        .route("/auth/webauthn/credentials", get(webauthn::list_credentials))
+        .route("/auth/webauthn/credentials/:id", patch(webauthn::rename_credential).delete(webauthn::delete_credential))
+        .route("/auth/sessions", get(sessions::list_sessions).delete(sessions::delete_all_sessions))
+        .route("/auth/sessions/:id", delete(sessions::delete_session))
+        .route("/auth/did/link/start", post(did_auth::start_link))
+        .route("/auth/did/link/finish", post(did_auth::finish_link))
+        .route("/auth/siop/link/start", post(siop::start_link))
+        .route("/auth/siop/link/finish", post(siop::finish_link))
+        .route("/oauth/authorize/approve", post(oauth::approve))
+        .route("/oauth/userinfo", get(oauth::userinfo))
+        .route("/ciba/requests", get(ciba::list_requests))
+        .route("/ciba/requests/:id/approve", post(ciba::approve_request))
+        .route("/ciba/requests/:id/deny", post(ciba::deny_request))
+        .route("/did/create", post(did::create_did))
+        .route("/siop/authorize", post(siop::authorize))
+        .route("/me", get(profile::get_profile).patch(profile::update_profile))
+        .route("/me/email", post(profile::request_email_change))
+        .route("/me/delete", post(deletion::request_deletion))
+        .route("/me/export", post(portability::export_account))
+        .route("/me/migrate", post(migration::migrate_out))
+        .route("/admin/users", get(admin::list_users))
+        .route("/admin/users/:id", get(admin::get_user))
+        .route("/admin/users/:id/disable", post(admin::disable_user))
+        .route("/admin/users/:id/enable", post(admin::enable_user))
+        .route("/admin/users/:id/role", put(admin::set_role))
+        .route("/admin/users/:id/delete", post(admin::delete_user))
+        .route("/admin/users/:id/sessions", get(admin::list_user_sessions).delete(admin::delete_user_sessions))
+        .route("/admin/users/:id/sessions/:session_id", delete(admin::delete_user_session))
+        .route("/admin/clients", get(admin::list_clients).post(admin::create_client))
+        .route("/admin/clients/:id", get(admin::get_client).patch(admin::update_client))
+        .route("/admin/clients/:id/secret", post(admin::rotate_client_secret))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth_user::require_auth));
 
     // Build our application with routes
     let app = Router::new()
         .route("/health", get(health_check))
         .route("/auth/login", post(auth::login))
+        .route("/auth/opaque/register/start", post(opaque::start_registration))
+        .route("/auth/opaque/register/finish", post(opaque::finish_registration))
+        .route("/auth/opaque/login/start", post(opaque::start_login))
+        .route("/auth/opaque/login/finish", post(opaque::finish_login))
+        .route("/auth/did/login/start", post(did_auth::start_login))
+        .route("/auth/did/login/finish", post(did_auth::finish_login))
+        .route("/auth/siop/login/start", post(siop::start_login))
+        .route("/auth/siop/login/finish", post(siop::finish_login))
+        .route("/auth/magic-link", post(auth::request_magic_link))
+        .route("/auth/magic-link/verify", post(auth::magic_link_login))
         .route("/auth/register", post(auth::register))
         .route("/auth/refresh", post(auth::refresh_token))
+        .route("/auth/verify-email", post(verification::verify_email))
+        .route("/auth/verify-email/resend", post(verification::resend_verification))
+        .route("/auth/email/change/confirm", post(profile::confirm_email_change))
+        .route("/auth/account/deletion/cancel", post(deletion::cancel_deletion))
+        .route("/auth/import", post(portability::import_account))
+        .route("/auth/migrate", post(migration::migrate_in))
+        .route("/auth/password/forgot", post(passwords::forgot_password))
+        .route("/auth/password/reset", post(passwords::reset_password))
+        .route("/auth/unlock", post(lockout::unlock_account))
+        .route("/auth/mfa/verify", post(mfa::verify_login))
+        .route("/auth/webauthn/login/start", post(webauthn::start_authentication))
+        .route("/auth/webauthn/login/finish", post(webauthn::finish_authentication))
         .route("/oauth/authorize", get(oauth::authorize))
+        .route("/oauth/par", post(oauth::pushed_authorization_request))
+        .route("/oauth/register", post(clients::register_client))
         .route("/oauth/token", post(oauth::token))
-        .route("/oauth/userinfo", get(oauth::userinfo))
-        .route("/.well-known/openid_configuration", get(oauth::openid_configuration))
-        .route("/did/create", post(did::create_did))
+        .route("/oauth/bc-authorize", post(ciba::backchannel_authentication))
+        .route("/oauth/introspect", post(oauth::introspect))
+        .route("/oauth/revoke", post(oauth::revoke))
+        .route("/.well-known/openid-configuration", get(discovery::openid_configuration))
+        .route("/.well-known/oauth-authorization-server", get(discovery::authorization_server_metadata))
+        .route("/.well-known/idryos-export-keys", get(portability::export_keys))
         .route("/did/resolve/:did", get(did::resolve_did))
+        .route("/users/:id/did.json", get(did::web_did_document))
+        .route("/users/:id/forwarding", get(migration::get_forwarding))
+        .route("/siop/.well-known/openid-configuration", get(siop::provider_metadata))
+        .merge(user_routes)
         .layer(CorsLayer::permissive())
         .with_state(state);
 
@@ -66,7 +182,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", 8000)).await?;
     info!("Auth service listening on {}", listener.local_addr()?);
     
-    axum::serve(listener, app).await?;
+    // Client addresses are recorded with sessions
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
@@ -0,0 +1,412 @@
+use axum::{
+    extract::{ConnectInfo, Json, State},
+    http::{HeaderMap, StatusCode},
+    response::Json as ResponseJson,
+};
+use chrono::{Duration, Utc};
+use rand::Rng;
+use reqwest::Url;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::{
+    auth::complete_login,
+    auth_user::FirstPartyUser,
+    crypto::{decrypt_user_secret, encrypt_user_secret, hash_token, secrets_match},
+    error::AppError,
+    jwt::{create_mfa_token, verify_mfa_token},
+    lockout::LoginAttempt,
+    models::{LoginResponse, MfaRequiredResponse, User, UserTotp},
+    oauth::find_active_user,
+    sessions::SessionMetadata,
+    webauthn, AppState,
+};
+
+// TOTP (RFC 6238) with the parameters authenticator apps assume by default
+const TOTP_PERIOD_SECONDS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+const TOTP_SKEW_STEPS: i64 = 1; // Accept one step of clock drift either way
+const TOTP_SECRET_BYTES: usize = 20;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const MFA_TOKEN_EXPIRY_MINUTES: i64 = 5;
+
+#[derive(Debug, Serialize)]
+pub struct TotpEnrollmentResponse {
+    pub secret: String, // Base32, for manual entry
+    pub provisioning_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaVerifyRequest {
+    pub mfa_token: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+    pub device_name: Option<String>,
+}
+
+// Second factors the user has to complete after their password, empty if
+// the password alone is enough
+pub async fn second_factors(state: &AppState, user_id: &str) -> Result<Vec<String>, AppError> {
+    let mut methods = Vec::new();
+    if find_totp(state, user_id).await?.is_some_and(|totp| totp.confirmed_at.is_some()) {
+        methods.push("totp".to_string());
+        methods.push("recovery_code".to_string());
+    }
+    // Completed through the WebAuthn authentication endpoints
+    if webauthn::has_credentials(state, user_id).await? {
+        methods.push("webauthn".to_string());
+    }
+    Ok(methods)
+}
+
+pub fn mfa_required(state: &AppState, user: &User, methods: Vec<String>) -> Result<MfaRequiredResponse, AppError> {
+    let lifetime = Duration::minutes(MFA_TOKEN_EXPIRY_MINUTES);
+    Ok(MfaRequiredResponse {
+        mfa_required: true,
+        mfa_token: create_mfa_token(&user.id, lifetime, &state.config)?,
+        methods,
+        expires_in: lifetime.num_seconds() as u64,
+    })
+}
+
+// Second step of a login that returned mfa_required
+pub async fn verify_login(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<MfaVerifyRequest>,
+) -> Result<ResponseJson<LoginResponse>, AppError> {
+    let user_id = verify_mfa_token(&payload.mfa_token, &state.config)?;
+    let user = find_active_user(&state, &user_id).await?;
+
+    // Codes are short, so guesses count against the same limits as passwords
+    let mut attempt = LoginAttempt::new(&user.email, addr.ip());
+    attempt.begin(&state).await?;
+
+    let verified = match (payload.code.as_deref(), payload.recovery_code.as_deref()) {
+        (Some(code), _) => verify_totp(&state, &user.id, code).await?,
+        (None, Some(recovery_code)) => use_recovery_code(&state, &user.id, recovery_code).await?,
+        (None, None) => return Err(AppError::Validation("code or recovery_code is required".to_string())),
+    };
+    if !verified {
+        attempt.record_failure(&state, Some(&user)).await?;
+        return Err(AppError::Authentication("Invalid verification code".to_string()));
+    }
+    attempt.record_success(&state).await?;
+
+    let metadata = SessionMetadata::from_request(&headers, addr, payload.device_name);
+    Ok(ResponseJson(complete_login(&state, user, &metadata).await?))
+}
+
+// Start enrollment. The secret stays unconfirmed, and is not asked for at
+// login, until a code generated from it is confirmed.
+pub async fn enroll_totp(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+) -> Result<ResponseJson<TotpEnrollmentResponse>, AppError> {
+    let user = auth.user;
+
+    if find_totp(&state, &user.id).await?.is_some_and(|totp| totp.confirmed_at.is_some()) {
+        return Err(AppError::Validation("TOTP is already enabled".to_string()));
+    }
+
+    let secret: [u8; TOTP_SECRET_BYTES] = rand::thread_rng().gen();
+    let secret_encrypted = encrypt_user_secret(&state.config, &user.id, &totp_context(&user.id), &secret).await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO user_totp (user_id, secret_encrypted, created_at)
+        VALUES (?, ?, ?)
+        ON CONFLICT (user_id) DO UPDATE SET
+            secret_encrypted = excluded.secret_encrypted,
+            confirmed_at = NULL,
+            last_used_step = NULL,
+            created_at = excluded.created_at
+        "#
+    )
+    .bind(&user.id)
+    .bind(&secret_encrypted)
+    .bind(Utc::now())
+    .execute(state.database.pool())
+    .await?;
+
+    let secret = base32_encode(&secret);
+    let provisioning_uri = provisioning_uri(&state.config.totp_issuer, &user.email, &secret)?;
+
+    Ok(ResponseJson(TotpEnrollmentResponse { secret, provisioning_uri }))
+}
+
+pub async fn confirm_totp(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Json(payload): Json<TotpCodeRequest>,
+) -> Result<ResponseJson<RecoveryCodesResponse>, AppError> {
+    let totp = find_totp(&state, &auth.user.id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("No TOTP enrollment in progress".to_string()))?;
+    if totp.confirmed_at.is_some() {
+        return Err(AppError::Validation("TOTP is already enabled".to_string()));
+    }
+
+    if !verify_totp(&state, &auth.user.id, &payload.code).await? {
+        return Err(AppError::Validation("Invalid verification code".to_string()));
+    }
+
+    sqlx::query("UPDATE user_totp SET confirmed_at = ? WHERE user_id = ?")
+        .bind(Utc::now())
+        .bind(&auth.user.id)
+        .execute(state.database.pool())
+        .await?;
+
+    let recovery_codes = replace_recovery_codes(&state, &auth.user.id).await?;
+    Ok(ResponseJson(RecoveryCodesResponse { recovery_codes }))
+}
+
+pub async fn disable_totp(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Json(payload): Json<TotpCodeRequest>,
+) -> Result<StatusCode, AppError> {
+    require_totp_code(&state, &auth.user.id, &payload.code).await?;
+
+    sqlx::query("DELETE FROM user_totp WHERE user_id = ?")
+        .bind(&auth.user.id)
+        .execute(state.database.pool())
+        .await?;
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(&auth.user.id)
+        .execute(state.database.pool())
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// New recovery codes invalidate all previous ones
+pub async fn regenerate_recovery_codes(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Json(payload): Json<TotpCodeRequest>,
+) -> Result<ResponseJson<RecoveryCodesResponse>, AppError> {
+    require_totp_code(&state, &auth.user.id, &payload.code).await?;
+
+    let recovery_codes = replace_recovery_codes(&state, &auth.user.id).await?;
+    Ok(ResponseJson(RecoveryCodesResponse { recovery_codes }))
+}
+
+async fn find_totp(state: &AppState, user_id: &str) -> Result<Option<UserTotp>, AppError> {
+    Ok(sqlx::query_as::<_, UserTotp>("SELECT * FROM user_totp WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_optional(state.database.pool())
+        .await?)
+}
+
+pub async fn totp_enabled(state: &AppState, user_id: &str) -> Result<bool, AppError> {
+    Ok(find_totp(state, user_id).await?.is_some_and(|totp| totp.confirmed_at.is_some()))
+}
+
+async fn require_totp_code(state: &AppState, user_id: &str, code: &str) -> Result<(), AppError> {
+    if !totp_enabled(state, user_id).await? {
+        return Err(AppError::NotFound("TOTP is not enabled".to_string()));
+    }
+    if !verify_totp(state, user_id, code).await? {
+        return Err(AppError::Validation("Invalid verification code".to_string()));
+    }
+    Ok(())
+}
+
+// Check a code against the user's secret. Each time step can be used once,
+// so an observed code cannot be replayed within its validity window.
+pub async fn verify_totp(state: &AppState, user_id: &str, code: &str) -> Result<bool, AppError> {
+    let Some(totp) = find_totp(state, user_id).await? else {
+        return Ok(false);
+    };
+    let secret = decrypt_user_secret(&state.config, user_id, &totp_context(user_id), &totp.secret_encrypted).await?;
+
+    let code = code.trim();
+    let current_step = Utc::now().timestamp() / TOTP_PERIOD_SECONDS;
+    let last_used_step = totp.last_used_step.unwrap_or(i64::MIN);
+    let matched_step = (current_step - TOTP_SKEW_STEPS..=current_step + TOTP_SKEW_STEPS)
+        .filter(|step| *step > last_used_step)
+        .find(|step| secrets_match(&totp_code(&secret, *step as u64), code));
+
+    let Some(step) = matched_step else {
+        return Ok(false);
+    };
+
+    // Conditional update, so concurrent requests cannot both spend the step
+    let result = sqlx::query(
+        "UPDATE user_totp SET last_used_step = ? WHERE user_id = ? AND (last_used_step IS NULL OR last_used_step < ?)"
+    )
+    .bind(step)
+    .bind(user_id)
+    .bind(step)
+    .execute(state.database.pool())
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+async fn use_recovery_code(state: &AppState, user_id: &str, recovery_code: &str) -> Result<bool, AppError> {
+    let result = sqlx::query(
+        "UPDATE recovery_codes SET used_at = ? WHERE user_id = ? AND code_hash = ? AND used_at IS NULL"
+    )
+    .bind(Utc::now())
+    .bind(user_id)
+    .bind(hash_token(&normalize_recovery_code(recovery_code)))
+    .execute(state.database.pool())
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+async fn replace_recovery_codes(state: &AppState, user_id: &str) -> Result<Vec<String>, AppError> {
+    let mut tx = state.database.pool().begin().await?;
+
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let bytes: [u8; 10] = rand::thread_rng().gen();
+        let code = base32_encode(&bytes).to_lowercase();
+        let code = format!("{}-{}-{}-{}", &code[0..4], &code[4..8], &code[8..12], &code[12..16]);
+
+        sqlx::query("INSERT INTO recovery_codes (id, user_id, code_hash, created_at) VALUES (?, ?, ?, ?)")
+            .bind(Uuid::new_v4().to_string())
+            .bind(user_id)
+            .bind(hash_token(&normalize_recovery_code(&code)))
+            .bind(Utc::now())
+            .execute(&mut *tx)
+            .await?;
+
+        codes.push(code);
+    }
+
+    tx.commit().await?;
+    Ok(codes)
+}
+
+// Users retype recovery codes, so case and separators do not matter
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+pub fn totp_context(user_id: &str) -> String {
+    format!("totp:{}", user_id)
+}
+
+// HOTP (RFC 4226, section 5.3) for the given time step
+fn totp_code(secret: &[u8], step: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &step.to_be_bytes());
+    let digest = tag.as_ref();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    format!("{:0width$}", binary % 10u32.pow(TOTP_DIGITS), width = TOTP_DIGITS as usize)
+}
+
+// otpauth:// URI understood by authenticator apps (usually shown as a QR code)
+fn provisioning_uri(issuer: &str, account: &str, secret: &str) -> Result<String, AppError> {
+    let mut uri = Url::parse("otpauth://totp/")
+        .map_err(|e| AppError::Internal(format!("Provisioning URI failed: {}", e)))?;
+    uri.path_segments_mut()
+        .map_err(|_| AppError::Internal("Provisioning URI failed".to_string()))?
+        .pop_if_empty()
+        .push(&format!("{}:{}", issuer, account));
+    uri.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &TOTP_DIGITS.to_string())
+        .append_pair("period", &TOTP_PERIOD_SECONDS.to_string());
+    Ok(uri.to_string())
+}
+
+// RFC 4648 base32 without padding, the encoding authenticator apps expect
+fn base32_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut encoded = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238, appendix B (SHA-1), keeping the low six of the eight digits
+    #[test]
+    fn totp_matches_rfc_6238_vectors() {
+        let secret = b"12345678901234567890";
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(totp_code(secret, time / TOTP_PERIOD_SECONDS as u64), code, "T = {}", time);
+        }
+    }
+
+    // RFC 4648, section 10, without padding
+    #[test]
+    fn base32_matches_rfc_4648_vectors() {
+        for (input, encoded) in [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(base32_encode(input.as_bytes()), encoded);
+        }
+        assert_eq!(base32_encode(b"12345678901234567890"), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn recovery_codes_ignore_case_and_separators() {
+        assert_eq!(normalize_recovery_code(" AbCd-1234 efgh "), "abcd1234efgh");
+    }
+}
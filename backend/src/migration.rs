@@ -0,0 +1,255 @@
+use axum::{
+    extract::{ConnectInfo, Json, Path, State},
+    http::StatusCode,
+    response::Json as ResponseJson,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    auth_user::FirstPartyUser,
+    deletion::schedule_deletion,
+    did::{managed_did_document, web_did},
+    error::AppError,
+    lockout::throttle_request,
+    models::UserResponse,
+    portability::{import_data, open_bundle, sign_jws, verify_jws, Bundle, ExportedDid},
+    reauth::{self, Reauthentication},
+    AppState,
+};
+
+// Moving a user between Idryos instances that list each other in
+// TRUSTED_IMPORT_ISSUERS:
+//
+// 1. The user exports a bundle at the source (`/me/export`), which records
+//    the export's jti.
+// 2. The destination imports it (`/auth/migrate`) and returns a receipt
+//    signed with its export key, naming the exported account and jti. A
+//    managed did:web identifier is re-hosted at the destination, keeping
+//    its key and listing the old identifier under alsoKnownAs.
+// 3. The user hands the receipt to the source (`/me/migrate`), confirming
+//    with their password or TOTP code. The source consumes the jti,
+//    publishes a signed forwarding record, freezes the old did:web document
+//    with alsoKnownAs pointing at the new identifier, and schedules the
+//    account for deletion. Cancelling the deletion withdraws the record.
+const RECEIPT_TYP: &str = "idryos-migration+jws";
+const FORWARDING_TYP: &str = "idryos-forwarding+jws";
+const RECEIPT_MAX_AGE_SECONDS: i64 = 86400;
+
+// Issued by the destination
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationReceipt {
+    pub iss: String,
+    pub sub: String,
+    pub iat: i64,
+    pub did: Option<String>,
+    pub from: AccountRef,
+}
+
+// Issued by the source, naming where the account went
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForwardingRecord {
+    pub iss: String,
+    pub sub: String,
+    pub iat: i64,
+    pub did: Option<String>,
+    pub moved_to: AccountRef,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountRef {
+    pub iss: String,
+    pub sub: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub did: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>, // Of the export the account was moved with
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MigrateInRequest {
+    pub bundle: Bundle,
+    pub passphrase: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrateInResponse {
+    pub user: UserResponse,
+    pub receipt: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MigrateOutRequest {
+    pub receipt: String,
+    #[serde(flatten)]
+    pub reauthentication: Reauthentication,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrateOutResponse {
+    pub record: String,
+    pub deletion_scheduled_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ForwardingResponse {
+    pub record: String,
+    pub moved_to: AccountRef,
+}
+
+// Destination side: create the account from a bundle of another instance
+pub async fn migrate_in(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<MigrateInRequest>,
+) -> Result<(StatusCode, ResponseJson<MigrateInResponse>), AppError> {
+    throttle_request(&state, "import", addr.ip()).await?;
+
+    let mut data = open_bundle(&state, &payload.bundle, payload.passphrase.as_deref()).await?;
+    if data.iss == state.config.issuer {
+        return Err(AppError::Validation("The bundle was exported from this instance".to_string()));
+    }
+
+    let user_id = Uuid::new_v4().to_string();
+
+    // did:key and did:jwk move as they are. A did:web names the host it is
+    // served from, so one whose key came along is re-hosted here.
+    if let Some(ExportedDid { did, document, managed_key: Some(key) }) = &mut data.did {
+        if did.starts_with("did:web:") && key.private_key.is_some() {
+            let new_did = web_did(&state.config, &user_id);
+            if !key.also_known_as.contains(did) {
+                key.also_known_as.push(did.clone());
+            }
+            key.verification_method = format!("{}#key-1", new_did);
+            *did = new_did;
+            *document = None;
+        }
+    }
+
+    let user = import_data(&state, &data, &user_id, true).await?;
+
+    let receipt = MigrationReceipt {
+        iss: state.config.issuer.clone(),
+        sub: user.id.clone(),
+        iat: Utc::now().timestamp(),
+        did: user.did.clone(),
+        from: AccountRef { iss: data.iss.clone(), sub: data.sub.clone(), did: None, jti: data.jti.clone() },
+    };
+    let receipt = sign_jws(&state, RECEIPT_TYP, &receipt).await?;
+
+    info!("User {} migrated in from {} (subject {})", user.id, data.iss, data.sub);
+    Ok((StatusCode::CREATED, ResponseJson(MigrateInResponse { user: user.into(), receipt })))
+}
+
+// Source side: record where the account went and retire it here
+pub async fn migrate_out(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<MigrateOutRequest>,
+) -> Result<(StatusCode, ResponseJson<MigrateOutResponse>), AppError> {
+    reauth::confirm(&state, &auth, &payload.reauthentication, addr.ip()).await?;
+
+    let user = auth.user;
+    let receipt: MigrationReceipt = verify_jws(&state, &payload.receipt, RECEIPT_TYP).await?;
+
+    if receipt.iss == state.config.issuer
+        || receipt.from.iss != state.config.issuer
+        || receipt.from.sub != user.id
+    {
+        return Err(AppError::Validation("The receipt is not for this account".to_string()));
+    }
+    if receipt.iat < Utc::now().timestamp() - RECEIPT_MAX_AGE_SECONDS {
+        return Err(AppError::Validation("The receipt has expired".to_string()));
+    }
+
+    // The receipt must be for a bundle exported here, and each export moves
+    // the account once
+    let consumed = sqlx::query(
+        "UPDATE account_exports SET used_at = ? WHERE jti = ? AND user_id = ? AND used_at IS NULL AND expires_at > ?"
+    )
+    .bind(Utc::now())
+    .bind(&receipt.from.jti)
+    .bind(&user.id)
+    .bind(Utc::now())
+    .execute(state.database.pool())
+    .await?;
+    if receipt.from.jti.is_none() || consumed.rows_affected() == 0 {
+        return Err(AppError::Validation("The receipt is not for an export of this account".to_string()));
+    }
+
+    // The did:web document stays served after the account is erased
+    let did_document = match &user.did {
+        Some(did) if *did == web_did(&state.config, &user.id) => match managed_did_document(&state, did).await? {
+            Some(mut document) => {
+                if let Some(new_did) = receipt.did.as_ref().filter(|new_did| !document.also_known_as.contains(new_did)) {
+                    document.also_known_as.push(new_did.clone());
+                }
+                document.updated = Some(Utc::now().to_rfc3339());
+                Some(serde_json::to_string(&document)
+                    .map_err(|e| AppError::Internal(format!("DID document serialization failed: {}", e)))?)
+            }
+            None => None,
+        },
+        _ => None,
+    };
+
+    let moved_to = AccountRef { iss: receipt.iss, sub: receipt.sub, did: receipt.did, jti: None };
+    let record = ForwardingRecord {
+        iss: state.config.issuer.clone(),
+        sub: user.id.clone(),
+        iat: Utc::now().timestamp(),
+        did: user.did.clone(),
+        moved_to: moved_to.clone(),
+    };
+    let record = sign_jws(&state, FORWARDING_TYP, &record).await?;
+
+    let result = sqlx::query(
+        r#"
+        INSERT OR IGNORE INTO account_forwards
+            (subject, did, destination_issuer, destination_subject, destination_did, record, did_document, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(&user.id)
+    .bind(&user.did)
+    .bind(&moved_to.iss)
+    .bind(&moved_to.sub)
+    .bind(&moved_to.did)
+    .bind(&record)
+    .bind(&did_document)
+    .bind(Utc::now())
+    .execute(state.database.pool())
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::Validation("The account has already moved".to_string()));
+    }
+
+    let deletion_scheduled_at = schedule_deletion(&state, &user).await?;
+
+    info!("User {} moved to {} (subject {})", user.id, moved_to.iss, moved_to.sub);
+    Ok((StatusCode::ACCEPTED, ResponseJson(MigrateOutResponse { record, deletion_scheduled_at })))
+}
+
+// Lets relying parties holding the old subject find the account
+pub async fn get_forwarding(
+    State(state): State<AppState>,
+    Path(subject): Path<String>,
+) -> Result<ResponseJson<ForwardingResponse>, AppError> {
+    let forward: (String, String, String, Option<String>) = sqlx::query_as(
+        r#"
+        SELECT record, destination_issuer, destination_subject, destination_did
+        FROM account_forwards WHERE subject = ?
+        "#
+    )
+    .bind(&subject)
+    .fetch_optional(state.database.pool())
+    .await?
+    .ok_or_else(|| AppError::NotFound("No forwarding record".to_string()))?;
+
+    let (record, iss, sub, did) = forward;
+    Ok(ResponseJson(ForwardingResponse { record, moved_to: AccountRef { iss, sub, did, jti: None } }))
+}
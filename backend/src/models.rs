@@ -1,7 +1,11 @@
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
-use uuid::Uuid;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{
+    config::{Config, TokenLifetimes},
+    roles::{self, Permission},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 pub struct User {
@@ -13,6 +17,50 @@ pub struct User {
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub is_active: bool,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub opaque_record: Option<String>, // Set for accounts registered with OPAQUE
+    pub role: String,
+    // OIDC standard claims, editable by the user
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub locale: Option<String>,       // BCP 47 language tag
+    pub zoneinfo: Option<String>,     // IANA time zone
+    pub picture: Option<String>,      // URL
+    pub phone_number: Option<String>, // E.164
+    pub address: Option<String>,      // JSON address claim
+    pub deletion_scheduled_at: Option<DateTime<Utc>>, // Erased at this time unless cancelled
+    pub profile_sealed: bool, // Names, phone and address are encrypted (see `profile::open_profile`)
+}
+
+// OIDC address claim (OpenID Connect Core 1.0, section 5.1.1)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Address {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formatted: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub street_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locality: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub postal_code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+}
+
+impl User {
+    pub fn address(&self) -> Option<Address> {
+        self.address.as_deref().and_then(|raw| serde_json::from_str(raw).ok())
+    }
+
+    pub fn permissions(&self) -> &'static [Permission] {
+        roles::permissions(&self.role)
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,6 +74,7 @@ pub struct CreateUserRequest {
 pub struct LoginRequest {
     pub email: String,
     pub password: String,
+    pub device_name: Option<String>, // Shown in the session list
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,12 +86,30 @@ pub struct LoginResponse {
     pub user: UserResponse,
 }
 
+// Password was correct but a second factor is needed before a session starts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaRequiredResponse {
+    pub mfa_required: bool,
+    pub mfa_token: String,
+    pub methods: Vec<String>,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LoginOutcome {
+    Session(LoginResponse),
+    MfaRequired(MfaRequiredResponse),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserResponse {
     pub id: String,
     pub username: String,
     pub email: String,
+    pub email_verified: bool,
     pub did: Option<String>,
+    pub role: String,
     pub created_at: DateTime<Utc>,
 }
 
@@ -52,7 +119,9 @@ impl From<User> for UserResponse {
             id: user.id,
             username: user.username,
             email: user.email,
+            email_verified: user.email_verified_at.is_some(),
             did: user.did,
+            role: user.role,
             created_at: user.created_at,
         }
     }
@@ -67,15 +136,201 @@ pub struct OAuthClient {
     pub scopes: String, // Space-separated scopes
     pub created_at: DateTime<Utc>,
     pub is_active: bool,
+    pub access_token_format: String, // "jwt" or "opaque"
+    pub claim_mappings: Option<String>, // JSON array of claim mapping rules
+    pub backchannel_token_delivery_mode: Option<String>, // "poll", "ping" or "push", CIBA disabled if unset
+    pub backchannel_client_notification_endpoint: Option<String>,
+    pub authorization_details_types: Option<String>, // JSON object of RAR type -> JSON schema
+    pub backchannel_logout_uri: Option<String>, // Receives logout tokens when a user is erased
+    pub application_type: String, // "web" or "native"
+    pub token_endpoint_auth_method: String, // "client_secret_post" or "none" for public clients
+    // Lifetime overrides, the global defaults apply when unset
+    pub access_token_lifetime_seconds: Option<i64>,
+    pub id_token_lifetime_seconds: Option<i64>,
+    pub refresh_token_idle_seconds: Option<i64>,
+    pub refresh_token_absolute_seconds: Option<i64>,
+}
+
+impl OAuthClient {
+    pub fn uses_opaque_access_tokens(&self) -> bool {
+        self.access_token_format == "opaque"
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.token_endpoint_auth_method == "none"
+    }
+
+    pub fn token_lifetimes(&self, config: &Config) -> TokenLifetimes {
+        let defaults = config.default_token_lifetimes();
+        let or_default = |seconds: Option<i64>, default| seconds.map(Duration::seconds).unwrap_or(default);
+
+        TokenLifetimes {
+            access: or_default(self.access_token_lifetime_seconds, defaults.access),
+            id_token: or_default(self.id_token_lifetime_seconds, defaults.id_token),
+            refresh_idle: or_default(self.refresh_token_idle_seconds, defaults.refresh_idle),
+            refresh_absolute: or_default(self.refresh_token_absolute_seconds, defaults.refresh_absolute),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct AuthorizationCode {
+    pub code: String,
+    pub client_id: String,
+    pub user_id: String,
+    pub redirect_uri: String,
+    pub scopes: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub nonce: Option<String>,
+    pub claims: Option<String>, // OIDC claims request parameter (JSON)
+    pub authorization_details: Option<String>, // Granted RAR details (JSON array)
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct RefreshTokenRecord {
+    pub token: String,
+    pub client_id: String,
+    pub user_id: String,
+    pub scopes: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub authorization_details: Option<String>,
+    pub absolute_expires_at: Option<DateTime<Utc>>, // Hard limit, expires_at slides with use
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Consent {
+    pub user_id: String,
+    pub client_id: String,
+    pub scopes: Option<String>,
+    pub claims: Option<String>, // Last OIDC claims request parameter (JSON)
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct AccessTokenRecord {
+    pub token_hash: String,
+    pub client_id: String,
+    pub user_id: String,
+    pub scopes: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub authorization_details: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct PushedAuthorization {
+    pub request_uri: String,
+    pub client_id: String,
+    pub parameters: String, // AuthorizeRequest as JSON
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+// First-party login session, holding the current refresh token
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub refresh_token_hash: String,
+    pub previous_token_hash: Option<String>, // Rotated-out token, kept to detect reuse
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,          // Idle expiry, slides with each refresh
+    pub absolute_expires_at: DateTime<Utc>, // Hard limit
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct UserTotp {
+    pub user_id: String,
+    pub secret_encrypted: String,
+    pub confirmed_at: Option<DateTime<Utc>>, // Unconfirmed until the first valid code
+    pub last_used_step: Option<i64>,         // Codes at or before this step are spent
+    pub created_at: DateTime<Utc>,
+}
+
+// Registered WebAuthn credential (passkey or security key)
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct WebAuthnCredential {
+    pub id: String,
+    pub user_id: String,
+    pub credential_id: String, // Base64url, as chosen by the authenticator
+    pub public_key: String,    // COSE_Key, base64url
+    pub sign_count: i64,
+    pub name: String,
+    pub transports: Option<String>, // JSON array of transport hints
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+// DID key held for a user, so the server can sign on their behalf
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct ManagedDidKey {
+    pub did: String,
+    pub user_id: String,
+    pub verification_method: String,
+    pub private_key_encrypted: String,
+    pub also_known_as: Option<String>, // JSON array of earlier identifiers
+    pub created_at: DateTime<Utc>,
+}
+
+// Outstanding WebAuthn challenge, single use
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct WebAuthnChallenge {
+    pub id: String,
+    pub user_id: Option<String>, // Unset for username-less passkey login
+    pub challenge: String,
+    pub ceremony: String, // "registration" or "authentication"
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct BackchannelAuthRequest {
+    pub id: String,
+    pub auth_req_id: String,
+    pub client_id: String,
+    pub user_id: String,
+    pub scopes: Option<String>,
+    pub binding_message: Option<String>,
+    pub client_notification_token: Option<String>,
+    pub status: String, // "pending", "approved" or "denied"
+    pub expires_at: DateTime<Utc>,
+    pub interval_seconds: i64,
+    pub last_polled_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthorizeRequest {
+    #[serde(default)]
     pub response_type: String,
     pub client_id: String,
+    #[serde(default)]
     pub redirect_uri: String,
     pub scope: Option<String>,
     pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub claims: Option<String>,
+    pub authorization_details: Option<String>,
+    pub request_uri: Option<String>, // Reference to a pushed authorization request
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushedAuthorizationRequest {
+    pub client_secret: String,
+    #[serde(flatten)]
+    pub request: AuthorizeRequest,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -86,6 +341,9 @@ pub struct TokenRequest {
     pub client_id: String,
     pub client_secret: Option<String>,
     pub refresh_token: Option<String>,
+    pub auth_req_id: Option<String>,
+    pub authorization_details: Option<String>,
+    pub code_verifier: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -95,4 +353,51 @@ pub struct TokenResponse {
     pub expires_in: u64,
     pub refresh_token: Option<String>,
     pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorization_details: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntrospectionRequest {
+    pub token: String,
+    pub token_type_hint: Option<String>,
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IntrospectionResponse {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorization_details: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevocationRequest {
+    pub token: String,
+    pub token_type_hint: Option<String>,
+    pub client_id: String,
+    pub client_secret: String,
 }
@@ -1,65 +1,284 @@
 use axum::{
-    extract::{Json, Query, State},
+    extract::{Form, Json, Query, State},
     response::Json as ResponseJson,
 };
-use serde::{Deserialize, Serialize};
-use uuid::Uuid;
-use chrono::{Utc, Duration};
-use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
-use rand::Rng;
+use chrono::{DateTime, Utc, Duration};
+use reqwest::Url;
 
 use crate::{
+    auth_user::{AuthUser, FirstPartyUser},
+    ciba,
+    clients,
+    claims::{build_claims, ClaimTarget, ClaimsRequest},
+    crypto::{generate_token, hash_token, secrets_match},
     error::AppError,
-    jwt::create_access_token,
-    models::{AuthorizeRequest, TokenRequest, TokenResponse, OAuthClient, User},
-    AppState,
+    jwt::{create_access_token, create_id_token, verify_access_token},
+    models::{
+        AccessTokenRecord, AuthorizationCode, AuthorizeRequest, Consent, IntrospectionRequest,
+        IntrospectionResponse, OAuthClient, PushedAuthorization, PushedAuthorizationRequest,
+        RefreshTokenRecord, RevocationRequest, TokenRequest, TokenResponse, User,
+    },
+    profile::open_profile,
+    rar, AppState,
 };
 
+const PAR_EXPIRY_SECONDS: i64 = 90;
+
+pub const RESPONSE_TYPES_SUPPORTED: &[&str] = &["code"];
+pub const GRANT_TYPES_SUPPORTED: &[&str] = &["authorization_code", "refresh_token", ciba::GRANT_TYPE];
+pub const CLIENT_AUTH_METHODS_SUPPORTED: &[&str] = &["client_secret_post"];
+pub const TOKEN_ENDPOINT_AUTH_METHODS_SUPPORTED: &[&str] = &["client_secret_post", "none"];
+pub const CODE_CHALLENGE_METHODS_SUPPORTED: &[&str] = &["S256"];
+pub const SIGNING_ALGS_SUPPORTED: &[&str] = &["HS256"];
+
 pub async fn authorize(
     State(state): State<AppState>,
     Query(params): Query<AuthorizeRequest>,
 ) -> Result<ResponseJson<serde_json::Value>, AppError> {
-    // Validate client
-    let client = sqlx::query_as::<_, OAuthClient>("SELECT * FROM oauth_clients WHERE id = ?")
-        .bind(&params.client_id)
-        .fetch_optional(state.database.pool())
-        .await?
-        .ok_or_else(|| AppError::Authentication("Invalid client".to_string()))?;
+    let request = validate_authorize_request(&state, params).await?;
+    let params = &request.params;
 
-    if !client.is_active {
-        return Err(AppError::Authentication("Client is disabled".to_string()));
+    // The frontend login page authenticates the user, shows the consent
+    // screen and then calls `approve` with the same parameters
+    let mut authorize_url = Url::parse(&format!("{}/oauth/login", state.config.frontend_url))
+        .map_err(|_| AppError::Internal("Invalid frontend URL".to_string()))?;
+    {
+        let mut query = authorize_url.query_pairs_mut();
+        query.append_pair("client_id", &params.client_id);
+
+        // Pushed requests are only passed by reference
+        if let Some(request_uri) = &params.request_uri {
+            query.append_pair("request_uri", request_uri);
+        } else {
+            query
+                .append_pair("response_type", &params.response_type)
+                .append_pair("redirect_uri", &params.redirect_uri)
+                .append_pair("scope", params.scope.as_deref().unwrap_or_default())
+                .append_pair("state", params.state.as_deref().unwrap_or_default());
+            if let Some(nonce) = &params.nonce {
+                query.append_pair("nonce", nonce);
+            }
+            if let Some(claims) = &params.claims {
+                query.append_pair("claims", claims);
+            }
+            if let Some(code_challenge) = &params.code_challenge {
+                query
+                    .append_pair("code_challenge", code_challenge)
+                    .append_pair("code_challenge_method", params.code_challenge_method.as_deref().unwrap_or("S256"));
+            }
+            if let Some(authorization_details) = &request.authorization_details {
+                query.append_pair("authorization_details", authorization_details);
+            }
+        }
     }
 
-    // Validate redirect URI
-    let redirect_uris: Vec<String> = serde_json::from_str(&client.redirect_uris)
-        .map_err(|_| AppError::Internal("Invalid redirect URIs format".to_string()))?;
-    
-    if !redirect_uris.contains(&params.redirect_uri) {
-        return Err(AppError::Authentication("Invalid redirect URI".to_string()));
+    Ok(ResponseJson(serde_json::json!({
+        "authorize_url": authorize_url.to_string(),
+        "client_name": request.client.name,
+        "scopes": granted_scopes(&request.client, params.scope.as_deref()),
+        "essential_claims": request.claims_request.essential_claims(),
+        "authorization_details": rar::to_value(request.authorization_details.as_deref()),
+    })))
+}
+
+// Called by the first-party frontend once the user has logged in and
+// consented. Issues the authorization code and returns the redirect.
+pub async fn approve(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Json(params): Json<AuthorizeRequest>,
+) -> Result<ResponseJson<serde_json::Value>, AppError> {
+    let request = validate_authorize_request(&state, params).await?;
+    let (params, client) = (&request.params, &request.client);
+    let mut user = auth.user;
+    open_profile(&state.config, &mut user).await?;
+    request.claims_request.check_subject(&user)?;
+
+    let scopes = granted_scopes(client, params.scope.as_deref());
+    let now = Utc::now();
+
+    // Remember the grant, userinfo needs the claims request later on
+    sqlx::query(
+        r#"
+        INSERT INTO oauth_consents (user_id, client_id, scopes, claims, created_at, updated_at)
+        VALUES (?, ?, ?, ?, ?, ?)
+        ON CONFLICT (user_id, client_id)
+        DO UPDATE SET scopes = excluded.scopes, claims = excluded.claims, updated_at = excluded.updated_at
+        "#
+    )
+    .bind(&user.id)
+    .bind(&client.id)
+    .bind(&scopes)
+    .bind(&params.claims)
+    .bind(now)
+    .bind(now)
+    .execute(state.database.pool())
+    .await?;
+
+    let code = generate_token();
+    sqlx::query(
+        r#"
+        INSERT INTO oauth_authorization_codes (code, client_id, user_id, redirect_uri, scopes, expires_at,
+                                               nonce, claims, authorization_details, code_challenge,
+                                               code_challenge_method)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(&code)
+    .bind(&client.id)
+    .bind(&user.id)
+    .bind(&params.redirect_uri)
+    .bind(&scopes)
+    .bind(now + Duration::minutes(10))
+    .bind(&params.nonce)
+    .bind(&params.claims)
+    .bind(&request.authorization_details)
+    .bind(&params.code_challenge)
+    .bind(params.code_challenge.as_ref().map(|_| "S256"))
+    .execute(state.database.pool())
+    .await?;
+
+    // Pushed requests can only be used once
+    if let Some(request_uri) = &params.request_uri {
+        sqlx::query("DELETE FROM oauth_par_requests WHERE request_uri = ?")
+            .bind(request_uri)
+            .execute(state.database.pool())
+            .await?;
+    }
+
+    let mut redirect_to = Url::parse(&params.redirect_uri)
+        .map_err(|_| AppError::Validation("Invalid redirect URI".to_string()))?;
+    redirect_to.query_pairs_mut().append_pair("code", &code);
+    if let Some(request_state) = &params.state {
+        redirect_to.query_pairs_mut().append_pair("state", request_state);
     }
 
-    // For demo purposes, we'll return an authorization URL
-    // In a real implementation, this would redirect to a login/consent page
     Ok(ResponseJson(serde_json::json!({
-        "authorize_url": format!(
-            "{}?response_type={}&client_id={}&redirect_uri={}&scope={}&state={}",
-            "/oauth/login",
-            params.response_type,
-            params.client_id,
-            params.redirect_uri,
-            params.scope.unwrap_or_default(),
-            params.state.unwrap_or_default()
-        )
+        "redirect_to": redirect_to.to_string()
     })))
 }
 
-pub async fn token(
+// Pushed authorization requests (RFC 9126)
+pub async fn pushed_authorization_request(
     State(state): State<AppState>,
-    Json(payload): Json<TokenRequest>,
-) -> Result<ResponseJson<TokenResponse>, AppError> {
-    // Validate client
+    Form(payload): Form<PushedAuthorizationRequest>,
+) -> Result<ResponseJson<serde_json::Value>, AppError> {
+    let client = authenticate_client(&state, &payload.request.client_id, &payload.client_secret).await?;
+
+    let mut params = payload.request;
+    if params.request_uri.is_some() {
+        return Err(AppError::Validation("request_uri is not allowed in a pushed request".to_string()));
+    }
+
+    // Validate everything up front so errors reach the client, not the user
+    let request = validate_authorize_request(&state, params.clone()).await?;
+    params.authorization_details = request.authorization_details;
+
+    let request_uri = format!("urn:ietf:params:oauth:request_uri:{}", generate_token());
+    let parameters = serde_json::to_string(&params)
+        .map_err(|e| AppError::Internal(format!("Request serialization failed: {}", e)))?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO oauth_par_requests (request_uri, client_id, parameters, expires_at)
+        VALUES (?, ?, ?, ?)
+        "#
+    )
+    .bind(&request_uri)
+    .bind(&client.id)
+    .bind(&parameters)
+    .bind(Utc::now() + Duration::seconds(PAR_EXPIRY_SECONDS))
+    .execute(state.database.pool())
+    .await?;
+
+    Ok(ResponseJson(serde_json::json!({
+        "request_uri": request_uri,
+        "expires_in": PAR_EXPIRY_SECONDS
+    })))
+}
+
+struct ValidatedAuthorizeRequest {
+    params: AuthorizeRequest,
+    client: OAuthClient,
+    claims_request: ClaimsRequest,
+    authorization_details: Option<String>,
+}
+
+async fn validate_authorize_request(
+    state: &AppState,
+    params: AuthorizeRequest,
+) -> Result<ValidatedAuthorizeRequest, AppError> {
+    let client = find_active_client(state, &params.client_id).await?;
+
+    // Expand a pushed authorization request
+    let params = match &params.request_uri {
+        Some(request_uri) => {
+            let pushed = sqlx::query_as::<_, PushedAuthorization>(
+                "SELECT * FROM oauth_par_requests WHERE request_uri = ? AND client_id = ?"
+            )
+            .bind(request_uri)
+            .bind(&client.id)
+            .fetch_optional(state.database.pool())
+            .await?
+            .filter(|pushed| pushed.expires_at > Utc::now())
+            .ok_or_else(|| AppError::Validation("Invalid or expired request_uri".to_string()))?;
+
+            let mut pushed_params: AuthorizeRequest = serde_json::from_str(&pushed.parameters)
+                .map_err(|_| AppError::Internal("Invalid pushed request format".to_string()))?;
+            pushed_params.request_uri = Some(pushed.request_uri);
+            pushed_params
+        }
+        None => params,
+    };
+
+    if params.response_type != "code" {
+        return Err(AppError::Validation("Unsupported response type".to_string()));
+    }
+
+    if !clients::redirect_uri_allowed(&client, &params.redirect_uri)? {
+        return Err(AppError::Authentication("Invalid redirect URI".to_string()));
+    }
+
+    // PKCE (RFC 7636), mandatory for public clients such as native apps
+    if params.code_challenge_method.as_deref().is_some_and(|method| method != "S256") {
+        return Err(AppError::Validation("Unsupported code_challenge_method".to_string()));
+    }
+    if client.is_public() && params.code_challenge.is_none() {
+        return Err(AppError::Validation("code_challenge is required for public clients".to_string()));
+    }
+
+    // ID tokens are signed with the client secret, which public clients lack
+    let scopes = granted_scopes(&client, params.scope.as_deref());
+    if client.is_public() && scopes.split_whitespace().any(|scope| scope == "openid") {
+        return Err(AppError::Validation("invalid_scope".to_string()));
+    }
+
+    let claims_request = ClaimsRequest::parse(params.claims.as_deref())?;
+    let authorization_details =
+        rar::parse_authorization_details(&client, params.authorization_details.as_deref())?;
+
+    Ok(ValidatedAuthorizeRequest {
+        params,
+        client,
+        claims_request,
+        authorization_details,
+    })
+}
+
+// Requested scopes restricted to the ones registered for the client
+pub fn granted_scopes(client: &OAuthClient, requested: Option<&str>) -> String {
+    let allowed: Vec<&str> = client.scopes.split_whitespace().collect();
+    requested
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter(|scope| allowed.contains(scope))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub async fn find_active_client(state: &AppState, client_id: &str) -> Result<OAuthClient, AppError> {
     let client = sqlx::query_as::<_, OAuthClient>("SELECT * FROM oauth_clients WHERE id = ?")
-        .bind(&payload.client_id)
+        .bind(client_id)
         .fetch_optional(state.database.pool())
         .await?
         .ok_or_else(|| AppError::Authentication("Invalid client".to_string()))?;
@@ -68,9 +287,42 @@ pub async fn token(
         return Err(AppError::Authentication("Client is disabled".to_string()));
     }
 
+    Ok(client)
+}
+
+pub async fn find_active_user(state: &AppState, user_id: &str) -> Result<User, AppError> {
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(state.database.pool())
+        .await?
+        .ok_or_else(|| AppError::Authentication("User not found".to_string()))?;
+
+    if !user.is_active {
+        return Err(AppError::Authentication("Account is disabled".to_string()));
+    }
+    if user.deletion_scheduled_at.is_some() {
+        return Err(AppError::Authentication("Account is scheduled for deletion".to_string()));
+    }
+
+    Ok(user)
+}
+
+pub async fn token(
+    State(state): State<AppState>,
+    Json(payload): Json<TokenRequest>,
+) -> Result<ResponseJson<TokenResponse>, AppError> {
+    // Confidential clients authenticate for every grant, public ones rely on PKCE
+    let client = find_active_client(&state, &payload.client_id).await?;
+    let client = if client.is_public() {
+        client
+    } else {
+        authenticate_client(&state, &client.id, payload.client_secret.as_deref().unwrap_or_default()).await?
+    };
+
     match payload.grant_type.as_str() {
         "authorization_code" => handle_authorization_code_grant(state, client, payload).await,
         "refresh_token" => handle_refresh_token_grant(state, client, payload).await,
+        ciba::GRANT_TYPE => ciba::handle_ciba_grant(state, client, payload).await,
         _ => Err(AppError::Authentication("Unsupported grant type".to_string())),
     }
 }
@@ -82,12 +334,13 @@ async fn handle_authorization_code_grant(
 ) -> Result<ResponseJson<TokenResponse>, AppError> {
     let code = payload.code.ok_or_else(|| AppError::Authentication("Authorization code required".to_string()))?;
     
-    // Verify authorization code
-    let auth_code = sqlx::query!(
-        "SELECT user_id, expires_at FROM oauth_authorization_codes WHERE code = ? AND client_id = ?",
-        code,
-        client.id
+    // Claim the code in one statement so it can only be redeemed once, even
+    // by concurrent requests; a failed redemption below also burns it
+    let auth_code = sqlx::query_as::<_, AuthorizationCode>(
+        "DELETE FROM oauth_authorization_codes WHERE code = ? AND client_id = ? RETURNING *"
     )
+    .bind(&code)
+    .bind(&client.id)
     .fetch_optional(state.database.pool())
     .await?
     .ok_or_else(|| AppError::Authentication("Invalid authorization code".to_string()))?;
@@ -97,37 +350,96 @@ async fn handle_authorization_code_grant(
         return Err(AppError::Authentication("Authorization code expired".to_string()));
     }
 
-    // Delete used authorization code
-    sqlx::query!("DELETE FROM oauth_authorization_codes WHERE code = ?", code)
-        .execute(state.database.pool())
-        .await?;
+    if payload.redirect_uri.as_deref() != Some(auth_code.redirect_uri.as_str()) {
+        return Err(AppError::Authentication("Redirect URI mismatch".to_string()));
+    }
 
-    // Create tokens
-    let access_token = create_access_token(&auth_code.user_id, &state.config.jwt_secret, state.config.token_expiration_minutes)?;
-    let refresh_token = generate_refresh_token();
+    if let Some(code_challenge) = &auth_code.code_challenge {
+        let code_verifier = payload
+            .code_verifier
+            .as_deref()
+            .ok_or_else(|| AppError::Authentication("code_verifier is required".to_string()))?;
+        // S256: BASE64URL(SHA256(code_verifier)), the same digest used for stored tokens
+        if !secrets_match(&hash_token(code_verifier), code_challenge) {
+            return Err(AppError::Authentication("Invalid code_verifier".to_string()));
+        }
+    }
 
-    // Store refresh token
-    let expires_at = Utc::now() + Duration::days(30);
-    sqlx::query!(
-        r#"
-        INSERT INTO oauth_refresh_tokens (token, client_id, user_id, expires_at)
-        VALUES (?, ?, ?, ?)
-        "#,
-        refresh_token,
-        client.id,
-        auth_code.user_id,
-        expires_at
+    let claims_request = ClaimsRequest::parse(auth_code.claims.as_deref())?;
+    let authorization_details = rar::narrow_authorization_details(
+        auth_code.authorization_details.as_deref(),
+        payload.authorization_details.as_deref(),
+    )?;
+    let response = issue_tokens(
+        &state,
+        &client,
+        &auth_code.user_id,
+        auth_code.scopes.as_deref(),
+        authorization_details.as_deref(),
+        &claims_request,
+        auth_code.nonce.as_deref(),
     )
-    .execute(state.database.pool())
     .await?;
 
-    Ok(ResponseJson(TokenResponse {
+    Ok(ResponseJson(response))
+}
+
+// Issue the access, refresh and (for openid grants) ID tokens of a new grant
+pub async fn issue_tokens(
+    state: &AppState,
+    client: &OAuthClient,
+    user_id: &str,
+    scopes: Option<&str>,
+    authorization_details: Option<&str>,
+    claims_request: &ClaimsRequest,
+    nonce: Option<&str>,
+) -> Result<TokenResponse, AppError> {
+    let lifetimes = client.token_lifetimes(&state.config);
+    let access_token = issue_access_token(state, client, user_id, scopes, authorization_details).await?;
+    let id_token = issue_id_token(state, client, user_id, scopes, claims_request, nonce).await?;
+
+    // Refresh tokens are only issued for offline access
+    let offline_access = scopes
+        .unwrap_or_default()
+        .split_whitespace()
+        .any(|scope| scope == "offline_access");
+
+    let refresh_token = if offline_access {
+        let refresh_token = generate_token();
+        let now = Utc::now();
+        let absolute_expires_at = now + lifetimes.refresh_absolute;
+
+        sqlx::query(
+            r#"
+            INSERT INTO oauth_refresh_tokens (token, client_id, user_id, scopes, expires_at,
+                                              authorization_details, absolute_expires_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#
+        )
+        .bind(&refresh_token)
+        .bind(&client.id)
+        .bind(user_id)
+        .bind(scopes)
+        .bind((now + lifetimes.refresh_idle).min(absolute_expires_at))
+        .bind(authorization_details)
+        .bind(absolute_expires_at)
+        .execute(state.database.pool())
+        .await?;
+
+        Some(refresh_token)
+    } else {
+        None
+    };
+
+    Ok(TokenResponse {
         access_token,
         token_type: "Bearer".to_string(),
-        expires_in: state.config.token_expiration_minutes * 60,
-        refresh_token: Some(refresh_token),
-        scope: None,
-    }))
+        expires_in: lifetimes.access.num_seconds() as u64,
+        refresh_token,
+        scope: scopes.map(str::to_string),
+        id_token,
+        authorization_details: rar::to_value(authorization_details),
+    })
 }
 
 async fn handle_refresh_token_grant(
@@ -138,65 +450,362 @@ async fn handle_refresh_token_grant(
     let refresh_token = payload.refresh_token.ok_or_else(|| AppError::Authentication("Refresh token required".to_string()))?;
     
     // Verify refresh token
-    let token_record = sqlx::query!(
-        "SELECT user_id, expires_at FROM oauth_refresh_tokens WHERE token = ? AND client_id = ?",
-        refresh_token,
-        client.id
+    let token_record = sqlx::query_as::<_, RefreshTokenRecord>(
+        "SELECT * FROM oauth_refresh_tokens WHERE token = ? AND client_id = ?"
     )
+    .bind(&refresh_token)
+    .bind(&client.id)
     .fetch_optional(state.database.pool())
     .await?
     .ok_or_else(|| AppError::Authentication("Invalid refresh token".to_string()))?;
 
-    // Check if token is expired
-    if token_record.expires_at < Utc::now() {
+    // Check both the idle and the absolute expiry
+    let now = Utc::now();
+    let absolute_expires_at = token_record.absolute_expires_at.unwrap_or(token_record.expires_at);
+    if token_record.expires_at < now || absolute_expires_at < now {
         return Err(AppError::Authentication("Refresh token expired".to_string()));
     }
 
+    // Disabled accounts and those scheduled for deletion get no new tokens,
+    // whatever the scopes
+    find_active_user(&state, &token_record.user_id).await?;
+
+    // Using the token slides its idle expiry, up to the absolute limit
+    let lifetimes = client.token_lifetimes(&state.config);
+    sqlx::query("UPDATE oauth_refresh_tokens SET expires_at = ? WHERE token = ?")
+        .bind((now + lifetimes.refresh_idle).min(absolute_expires_at))
+        .bind(&refresh_token)
+        .execute(state.database.pool())
+        .await?;
+
     // Create new access token
-    let access_token = create_access_token(&token_record.user_id, &state.config.jwt_secret, state.config.token_expiration_minutes)?;
+    let authorization_details = rar::narrow_authorization_details(
+        token_record.authorization_details.as_deref(),
+        payload.authorization_details.as_deref(),
+    )?;
+    let access_token = issue_access_token(
+        &state,
+        &client,
+        &token_record.user_id,
+        token_record.scopes.as_deref(),
+        authorization_details.as_deref(),
+    )
+    .await?;
+    let claims_request = find_consent_claims(&state, &token_record.user_id, &client.id).await?;
+    let id_token = issue_id_token(
+        &state,
+        &client,
+        &token_record.user_id,
+        token_record.scopes.as_deref(),
+        &claims_request,
+        None,
+    )
+    .await?;
 
     Ok(ResponseJson(TokenResponse {
         access_token,
         token_type: "Bearer".to_string(),
-        expires_in: state.config.token_expiration_minutes * 60,
+        expires_in: lifetimes.access.num_seconds() as u64,
         refresh_token: None,
-        scope: None,
+        scope: token_record.scopes,
+        id_token,
+        authorization_details: rar::to_value(authorization_details.as_deref()),
     }))
 }
 
 pub async fn userinfo(
     State(state): State<AppState>,
-    // Extract token from Authorization header
-    // This is a simplified version - in practice you'd use middleware
+    auth: AuthUser,
 ) -> Result<ResponseJson<serde_json::Value>, AppError> {
-    // For demo purposes, return a mock user
-    Ok(ResponseJson(serde_json::json!({
-        "sub": "user123",
-        "name": "Demo User",
-        "email": "demo@idryos.local",
-        "preferred_username": "demo"
-    })))
+    auth.require_scope("openid")?;
+
+    let scopes = auth.scopes.join(" ");
+    let client = find_active_client(&state, &auth.client_id).await?;
+    let claims_request = find_consent_claims(&state, &auth.user.id, &client.id).await?;
+
+    let mut user = auth.user;
+    open_profile(&state.config, &mut user).await?;
+    let claims = build_claims(&user, &client, &scopes, &claims_request, ClaimTarget::Userinfo)?;
+
+    Ok(ResponseJson(serde_json::Value::Object(claims)))
 }
 
-pub async fn openid_configuration(
+async fn issue_id_token(
+    state: &AppState,
+    client: &OAuthClient,
+    user_id: &str,
+    scopes: Option<&str>,
+    claims_request: &ClaimsRequest,
+    nonce: Option<&str>,
+) -> Result<Option<String>, AppError> {
+    let scopes = scopes.unwrap_or_default();
+    if !scopes.split_whitespace().any(|scope| scope == "openid") {
+        return Ok(None);
+    }
+
+    // An empty secret would make the HS256 signature forgeable
+    if client.is_public() {
+        return Err(AppError::Authorization("ID tokens are not issued to public clients".to_string()));
+    }
+
+    let mut user = find_active_user(state, user_id).await?;
+    open_profile(&state.config, &mut user).await?;
+    let claims = build_claims(&user, client, scopes, claims_request, ClaimTarget::IdToken)?;
+    let lifetime = client.token_lifetimes(&state.config).id_token;
+    let id_token = create_id_token(claims, &client.id, &client.client_secret, nonce, lifetime, &state.config)?;
+
+    Ok(Some(id_token))
+}
+
+async fn find_consent_claims(
+    state: &AppState,
+    user_id: &str,
+    client_id: &str,
+) -> Result<ClaimsRequest, AppError> {
+    let consent = sqlx::query_as::<_, Consent>(
+        "SELECT * FROM oauth_consents WHERE user_id = ? AND client_id = ?"
+    )
+    .bind(user_id)
+    .bind(client_id)
+    .fetch_optional(state.database.pool())
+    .await?;
+
+    ClaimsRequest::parse(consent.as_ref().and_then(|consent| consent.claims.as_deref()))
+}
+
+// Issue either a self-contained JWT or an opaque reference token, depending on
+// the client's configuration. Opaque tokens carry no readable claims and can
+// only be validated through introspection.
+async fn issue_access_token(
+    state: &AppState,
+    client: &OAuthClient,
+    user_id: &str,
+    scopes: Option<&str>,
+    authorization_details: Option<&str>,
+) -> Result<String, AppError> {
+    let lifetime = client.token_lifetimes(&state.config).access;
+    if !client.uses_opaque_access_tokens() {
+        return create_access_token(user_id, &client.id, None, scopes, rar::to_value(authorization_details), lifetime, &state.config);
+    }
+
+    let token = generate_token();
+    let expires_at = Utc::now() + lifetime;
+
+    sqlx::query(
+        r#"
+        INSERT INTO oauth_access_tokens (token_hash, client_id, user_id, scopes, expires_at, authorization_details)
+        VALUES (?, ?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(hash_token(&token))
+    .bind(&client.id)
+    .bind(user_id)
+    .bind(scopes)
+    .bind(expires_at)
+    .bind(authorization_details)
+    .execute(state.database.pool())
+    .await?;
+
+    Ok(token)
+}
+
+pub async fn authenticate_client(
+    state: &AppState,
+    client_id: &str,
+    client_secret: &str,
+) -> Result<OAuthClient, AppError> {
+    let client = sqlx::query_as::<_, OAuthClient>("SELECT * FROM oauth_clients WHERE id = ?")
+        .bind(client_id)
+        .fetch_optional(state.database.pool())
+        .await?
+        .filter(|client| {
+            client.is_active && !client.is_public() && secrets_match(&client.client_secret, client_secret)
+        })
+        .ok_or_else(|| AppError::Authentication("Invalid client credentials".to_string()))?;
+
+    Ok(client)
+}
+
+// Token introspection (RFC 7662)
+pub async fn introspect(
     State(state): State<AppState>,
+    Form(payload): Form<IntrospectionRequest>,
+) -> Result<ResponseJson<IntrospectionResponse>, AppError> {
+    authenticate_client(&state, &payload.client_id, &payload.client_secret).await?;
+
+    let response = match payload.token_type_hint.as_deref() {
+        Some("refresh_token") => match introspect_refresh_token(&state, &payload.token).await? {
+            Some(response) => Some(response),
+            None => introspect_access_token(&state, &payload.token).await?,
+        },
+        _ => match introspect_access_token(&state, &payload.token).await? {
+            Some(response) => Some(response),
+            None => introspect_refresh_token(&state, &payload.token).await?,
+        },
+    };
+
+    Ok(ResponseJson(response.unwrap_or_default()))
+}
+
+async fn introspect_access_token(
+    state: &AppState,
+    token: &str,
+) -> Result<Option<IntrospectionResponse>, AppError> {
+    let record = sqlx::query_as::<_, AccessTokenRecord>(
+        "SELECT * FROM oauth_access_tokens WHERE token_hash = ?"
+    )
+    .bind(hash_token(token))
+    .fetch_optional(state.database.pool())
+    .await?;
+
+    if let Some(record) = record {
+        if record.expires_at < Utc::now() {
+            return Ok(None);
+        }
+
+        return Ok(Some(IntrospectionResponse {
+            active: true,
+            scope: record.scopes,
+            client_id: Some(record.client_id),
+            token_type: Some("Bearer".to_string()),
+            exp: Some(record.expires_at.timestamp()),
+            iat: Some(record.created_at.timestamp()),
+            sub: Some(record.user_id),
+            aud: Some(state.config.access_token_audience.clone()),
+            iss: Some(state.config.issuer.clone()),
+            authorization_details: rar::to_value(record.authorization_details.as_deref()),
+            ..Default::default()
+        }));
+    }
+
+    // Not a reference token, fall back to validating it as a JWT
+    let Ok(claims) = verify_access_token(token, &state.config) else {
+        return Ok(None);
+    };
+    if is_revoked_jwt(state, &claims.jti).await? {
+        return Ok(None);
+    }
+
+    Ok(Some(IntrospectionResponse {
+        active: true,
+        scope: claims.scope,
+        client_id: Some(claims.client_id),
+        token_type: Some("Bearer".to_string()),
+        exp: Some(claims.exp as i64),
+        iat: Some(claims.iat as i64),
+        nbf: Some(claims.nbf as i64),
+        sub: Some(claims.sub),
+        aud: Some(claims.aud),
+        iss: Some(claims.iss),
+        jti: Some(claims.jti),
+        authorization_details: claims.authorization_details,
+    }))
+}
+
+async fn introspect_refresh_token(
+    state: &AppState,
+    token: &str,
+) -> Result<Option<IntrospectionResponse>, AppError> {
+    let record = sqlx::query_as::<_, RefreshTokenRecord>(
+        "SELECT * FROM oauth_refresh_tokens WHERE token = ?"
+    )
+    .bind(token)
+    .fetch_optional(state.database.pool())
+    .await?
+    .filter(|record| record.expires_at >= Utc::now());
+
+    Ok(record.map(|record| IntrospectionResponse {
+        active: true,
+        scope: record.scopes,
+        client_id: Some(record.client_id),
+        token_type: Some("refresh_token".to_string()),
+        exp: Some(record.expires_at.timestamp()),
+        iat: Some(record.created_at.timestamp()),
+        sub: Some(record.user_id),
+        iss: Some(state.config.issuer.clone()),
+        authorization_details: rar::to_value(record.authorization_details.as_deref()),
+        ..Default::default()
+    }))
+}
+
+// Token revocation (RFC 7009). Opaque access tokens and refresh tokens are
+// deleted; JWT access tokens are revoked by jti in revoked_access_tokens.
+pub async fn revoke(
+    State(state): State<AppState>,
+    Form(payload): Form<RevocationRequest>,
 ) -> Result<ResponseJson<serde_json::Value>, AppError> {
-    let base_url = "http://localhost:8000"; // In practice, use configured base URL
+    let client = authenticate_client(&state, &payload.client_id, &payload.client_secret).await?;
 
-    Ok(ResponseJson(serde_json::json!({
-        "issuer": base_url,
-        "authorization_endpoint": format!("{}/oauth/authorize", base_url),
-        "token_endpoint": format!("{}/oauth/token", base_url),
-        "userinfo_endpoint": format!("{}/oauth/userinfo", base_url),
-        "jwks_uri": format!("{}/.well-known/jwks.json", base_url),
-        "response_types_supported": ["code"],
-        "subject_types_supported": ["public"],
-        "id_token_signing_alg_values_supported": ["HS256"]
-    })))
+    sqlx::query("DELETE FROM oauth_access_tokens WHERE token_hash = ? AND client_id = ?")
+        .bind(hash_token(&payload.token))
+        .bind(&client.id)
+        .execute(state.database.pool())
+        .await?;
+
+    sqlx::query("DELETE FROM oauth_refresh_tokens WHERE token = ? AND client_id = ?")
+        .bind(&payload.token)
+        .bind(&client.id)
+        .execute(state.database.pool())
+        .await?;
+
+    // JWT access tokens cannot be deleted, their jti is listed until they
+    // would have expired anyway
+    if let Ok(claims) = verify_access_token(&payload.token, &state.config) {
+        if claims.client_id == client.id {
+            let now = Utc::now();
+            sqlx::query("DELETE FROM revoked_access_tokens WHERE expires_at < ?")
+                .bind(now)
+                .execute(state.database.pool())
+                .await?;
+
+            sqlx::query("INSERT OR IGNORE INTO revoked_access_tokens (jti, expires_at, created_at) VALUES (?, ?, ?)")
+                .bind(&claims.jti)
+                .bind(DateTime::from_timestamp(claims.exp as i64, 0).unwrap_or(now))
+                .bind(now)
+                .execute(state.database.pool())
+                .await?;
+        }
+    }
+
+    // Unknown or already revoked tokens are not an error (RFC 7009 section 2.2)
+    Ok(ResponseJson(serde_json::json!({})))
+}
+
+pub async fn is_revoked_jwt(state: &AppState, jti: &str) -> Result<bool, AppError> {
+    let (revoked,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM revoked_access_tokens WHERE jti = ?")
+        .bind(jti)
+        .fetch_one(state.database.pool())
+        .await?;
+    Ok(revoked > 0)
 }
 
-fn generate_refresh_token() -> String {
-    let mut rng = rand::thread_rng();
-    let bytes: [u8; 32] = rng.gen();
-    URL_SAFE_NO_PAD.encode(&bytes)
+// Revoke every refresh token and opaque access token issued to clients on
+// the user's behalf, e.g. after a password change
+pub async fn revoke_user_tokens(state: &AppState, user_id: &str) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM oauth_refresh_tokens WHERE user_id = ?")
+        .bind(user_id)
+        .execute(state.database.pool())
+        .await?;
+
+    sqlx::query("DELETE FROM oauth_access_tokens WHERE user_id = ?")
+        .bind(user_id)
+        .execute(state.database.pool())
+        .await?;
+
+    Ok(())
+}
+
+// Revoke everything issued to a client, e.g. when it is disabled
+pub async fn revoke_client_tokens(state: &AppState, client_id: &str) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM oauth_refresh_tokens WHERE client_id = ?")
+        .bind(client_id)
+        .execute(state.database.pool())
+        .await?;
+
+    sqlx::query("DELETE FROM oauth_access_tokens WHERE client_id = ?")
+        .bind(client_id)
+        .execute(state.database.pool())
+        .await?;
+
+    Ok(())
 }
@@ -0,0 +1,670 @@
+use axum::{
+    extract::{ConnectInfo, Json, State},
+    http::HeaderMap,
+    response::Json as ResponseJson,
+};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{Duration, Utc};
+use curve25519_dalek::{
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    scalar::Scalar,
+    traits::IsIdentity,
+};
+use rand::RngCore;
+use ring::{
+    digest::{self, SHA512},
+    hmac,
+};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    auth::finish_first_factor,
+    crypto::{decrypt_secret, encrypt_secret, secrets_match},
+    error::AppError,
+    lockout::LoginAttempt,
+    models::{LoginOutcome, User, UserResponse},
+    sessions::SessionMetadata,
+    verification::send_verification_email,
+    AppState,
+};
+
+// Server side of OPAQUE-3DH (RFC 9807) with ristretto255 and SHA-512. The
+// client runs the matching steps, so the password never leaves it and the
+// server keeps only the registration record: the client's public key, a
+// masking key and the sealed envelope.
+//
+// Messages are exchanged as base64url of their RFC serialization. The
+// credential identifier is the user id, client and server identities are
+// their public keys, and the handshake context is CONTEXT.
+const CONTEXT: &[u8] = b"Idryos OPAQUE";
+const PENDING_EXPIRY_SECONDS: i64 = 300;
+// Unfinished registrations or logins one address may hold at a time
+const MAX_PENDING_PER_IP: i64 = 20;
+
+const ELEMENT_LEN: usize = 32;
+const NONCE_LEN: usize = 32;
+const HASH_LEN: usize = 64; // Also the MAC and KDF output length
+const ENVELOPE_LEN: usize = NONCE_LEN + HASH_LEN;
+const RECORD_LEN: usize = ELEMENT_LEN + HASH_LEN + ENVELOPE_LEN;
+const KE1_LEN: usize = ELEMENT_LEN + NONCE_LEN + ELEMENT_LEN;
+
+// OPRF mode 0 (RFC 9497) for ristretto255-SHA512
+const OPRF_CONTEXT: &[u8] = b"OPRFV1-\x00-ristretto255-SHA512";
+
+#[derive(Debug, Deserialize)]
+pub struct RegistrationStartRequest {
+    pub username: String,
+    pub email: String,
+    pub registration_request: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RegistrationStartResponse {
+    pub registration_id: String,
+    pub registration_response: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegistrationFinishRequest {
+    pub registration_id: String,
+    pub registration_record: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginStartRequest {
+    pub email: String,
+    pub ke1: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoginStartResponse {
+    pub login_id: String,
+    pub ke2: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginFinishRequest {
+    pub login_id: String,
+    pub ke3: String,
+    pub device_name: Option<String>,
+}
+
+// The server's OPRF seed and long-term AKE key pair
+struct ServerSetup {
+    oprf_seed: Vec<u8>,
+    private_key: Scalar,
+    public_key: [u8; ELEMENT_LEN],
+}
+
+pub async fn start_registration(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<RegistrationStartRequest>,
+) -> Result<ResponseJson<RegistrationStartResponse>, AppError> {
+    if payload.username.is_empty() || payload.email.is_empty() {
+        return Err(AppError::Validation("All fields are required".to_string()));
+    }
+    ensure_available(&state, &payload.username, &payload.email).await?;
+    check_pending(&state, "opaque_registrations", addr).await?;
+
+    let blinded = decode_element(&decode(&payload.registration_request, ELEMENT_LEN)?)?;
+    let setup = server_setup(&state).await?;
+
+    // The user id is fixed now, it is the credential identifier the OPRF key
+    // is derived from
+    let user_id = Uuid::new_v4().to_string();
+    let evaluated = (oprf_key(&setup, &user_id) * blinded).compress();
+
+    let registration_id = Uuid::new_v4().to_string();
+    let now = Utc::now();
+    sqlx::query(
+        r#"
+        INSERT INTO opaque_registrations (id, user_id, username, email, client_ip, expires_at, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(&registration_id)
+    .bind(&user_id)
+    .bind(&payload.username)
+    .bind(&payload.email)
+    .bind(addr.ip().to_string())
+    .bind(now + Duration::seconds(PENDING_EXPIRY_SECONDS))
+    .bind(now)
+    .execute(state.database.pool())
+    .await?;
+
+    let response = [evaluated.as_bytes().as_slice(), &setup.public_key].concat();
+    Ok(ResponseJson(RegistrationStartResponse {
+        registration_id,
+        registration_response: URL_SAFE_NO_PAD.encode(response),
+    }))
+}
+
+pub async fn finish_registration(
+    State(state): State<AppState>,
+    Json(payload): Json<RegistrationFinishRequest>,
+) -> Result<ResponseJson<UserResponse>, AppError> {
+    let (user_id, username, email): (String, String, String) = sqlx::query_as(
+        "DELETE FROM opaque_registrations WHERE id = ? AND expires_at > ? RETURNING user_id, username, email"
+    )
+    .bind(&payload.registration_id)
+    .bind(Utc::now())
+    .fetch_optional(state.database.pool())
+    .await?
+    .ok_or_else(|| AppError::Validation("Invalid or expired registration".to_string()))?;
+
+    let record = decode(&payload.registration_record, RECORD_LEN)?;
+    decode_element(&record[..ELEMENT_LEN])?;
+
+    // Checked again, the name or address may have been taken meanwhile
+    ensure_available(&state, &username, &email).await?;
+
+    let now = Utc::now();
+    sqlx::query(
+        r#"
+        INSERT INTO users (id, username, email, password_hash, opaque_record, created_at, updated_at)
+        VALUES (?, ?, ?, '', ?, ?, ?)
+        "#
+    )
+    .bind(&user_id)
+    .bind(&username)
+    .bind(&email)
+    .bind(URL_SAFE_NO_PAD.encode(&record))
+    .bind(now)
+    .bind(now)
+    .execute(state.database.pool())
+    .await?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(&user_id)
+        .fetch_one(state.database.pool())
+        .await?;
+
+    if let Err(e) = send_verification_email(&state, &user).await {
+        warn!("Verification email for user {} failed: {}", user.id, e);
+    }
+
+    Ok(ResponseJson(user.into()))
+}
+
+pub async fn start_login(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<LoginStartRequest>,
+) -> Result<ResponseJson<LoginStartResponse>, AppError> {
+    LoginAttempt::new(&payload.email, addr.ip()).check(&state).await?;
+    check_pending(&state, "opaque_logins", addr).await?;
+
+    let ke1 = decode(&payload.ke1, KE1_LEN)?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+        .bind(&payload.email)
+        .fetch_optional(state.database.pool())
+        .await?;
+
+    let setup = server_setup(&state).await?;
+
+    // Accounts without a record get a fake one, so the response does not
+    // reveal which exist. The OPRF key still depends only on the identifier,
+    // so repeated attempts look consistent.
+    let (user_id, credential_identifier, record) = match user.and_then(|user| Some((user.id, user.opaque_record?))) {
+        Some((user_id, record)) => {
+            let record = URL_SAFE_NO_PAD
+                .decode(record)
+                .map_err(|_| AppError::Internal("Invalid OPAQUE record".to_string()))?;
+            (Some(user_id.clone()), user_id, record)
+        }
+        None => {
+            let mut record = vec![0u8; RECORD_LEN];
+            record[..ELEMENT_LEN].copy_from_slice(RistrettoPoint::mul_base(&random_scalar()).compress().as_bytes());
+            rand::thread_rng().fill_bytes(&mut record[ELEMENT_LEN..ELEMENT_LEN + HASH_LEN]);
+            (None, format!("email:{}", payload.email.trim().to_lowercase()), record)
+        }
+    };
+    let (ke2, expected_client_mac) = respond(
+        &setup,
+        CONTEXT,
+        &credential_identifier,
+        &record,
+        &ke1,
+        &random_bytes::<NONCE_LEN>(),
+        &random_bytes::<NONCE_LEN>(),
+        &random_scalar(),
+    )?;
+
+    let login_id = Uuid::new_v4().to_string();
+    let now = Utc::now();
+    sqlx::query(
+        r#"
+        INSERT INTO opaque_logins (id, user_id, email, expected_client_mac, client_ip, expires_at, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(&login_id)
+    .bind(&user_id)
+    .bind(&payload.email)
+    .bind(URL_SAFE_NO_PAD.encode(&expected_client_mac))
+    .bind(addr.ip().to_string())
+    .bind(now + Duration::seconds(PENDING_EXPIRY_SECONDS))
+    .bind(now)
+    .execute(state.database.pool())
+    .await?;
+
+    Ok(ResponseJson(LoginStartResponse {
+        login_id,
+        ke2: URL_SAFE_NO_PAD.encode(ke2),
+    }))
+}
+
+pub async fn finish_login(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<LoginFinishRequest>,
+) -> Result<ResponseJson<LoginOutcome>, AppError> {
+    // Each handshake is good for one try
+    let (user_id, email, expected_client_mac): (Option<String>, String, String) = sqlx::query_as(
+        "DELETE FROM opaque_logins WHERE id = ? AND expires_at > ? RETURNING user_id, email, expected_client_mac"
+    )
+    .bind(&payload.login_id)
+    .bind(Utc::now())
+    .fetch_optional(state.database.pool())
+    .await?
+    .ok_or_else(|| AppError::Validation("Invalid or expired login".to_string()))?;
+
+    let mut attempt = LoginAttempt::new(&email, addr.ip());
+    attempt.begin(&state).await?;
+
+    let user = match &user_id {
+        Some(user_id) => sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_optional(state.database.pool())
+            .await?,
+        None => None,
+    };
+
+    let user = match user {
+        Some(user) if secrets_match(&payload.ke3, &expected_client_mac) => user,
+        user => {
+            attempt.record_failure(&state, user.as_ref()).await?;
+            return Err(AppError::Authentication("Invalid credentials".to_string()));
+        }
+    };
+    attempt.record_success(&state).await?;
+
+    let metadata = SessionMetadata::from_request(&headers, addr, payload.device_name);
+    let outcome = finish_first_factor(&state, user, &metadata).await?;
+
+    Ok(ResponseJson(outcome))
+}
+
+// The server's KE2 for a KE1 and the client MAC that must come back as KE3.
+// The random inputs are passed in so the computation can be checked against
+// the RFC test vectors.
+#[allow(clippy::too_many_arguments)]
+fn respond(
+    setup: &ServerSetup,
+    context: &[u8],
+    credential_identifier: &str,
+    record: &[u8],
+    ke1: &[u8],
+    masking_nonce: &[u8; NONCE_LEN],
+    server_nonce: &[u8; NONCE_LEN],
+    server_secret_keyshare: &Scalar,
+) -> Result<(Vec<u8>, Vec<u8>), AppError> {
+    let blinded = decode_element(&ke1[..ELEMENT_LEN])?;
+    let client_keyshare = decode_element(&ke1[ELEMENT_LEN + NONCE_LEN..])?;
+    let client_public_key = decode_element(&record[..ELEMENT_LEN])?;
+    let masking_key = &record[ELEMENT_LEN..ELEMENT_LEN + HASH_LEN];
+    let envelope = &record[ELEMENT_LEN + HASH_LEN..];
+
+    // Credential response: the OPRF evaluation plus the server public key
+    // and envelope, masked so only the password holder can unmask them
+    let evaluated = (oprf_key(setup, credential_identifier) * blinded).compress();
+    let pad = expand(
+        masking_key,
+        &[masking_nonce.as_slice(), b"CredentialResponsePad"].concat(),
+        ELEMENT_LEN + ENVELOPE_LEN,
+    );
+    let masked_response: Vec<u8> = [setup.public_key.as_slice(), envelope]
+        .concat()
+        .iter()
+        .zip(&pad)
+        .map(|(a, b)| a ^ b)
+        .collect();
+    let credential_response = [evaluated.as_bytes().as_slice(), masking_nonce, &masked_response].concat();
+
+    // 3DH with the server's fresh key share
+    let server_keyshare = RistrettoPoint::mul_base(server_secret_keyshare).compress();
+
+    let preamble = [
+        b"OPAQUEv1-".as_slice(),
+        &length_prefixed(context),
+        &length_prefixed(&record[..ELEMENT_LEN]),
+        ke1,
+        &length_prefixed(&setup.public_key),
+        &credential_response,
+        server_nonce,
+        server_keyshare.as_bytes(),
+    ]
+    .concat();
+
+    let ikm = [
+        (server_secret_keyshare * client_keyshare).compress().to_bytes(),
+        (setup.private_key * client_keyshare).compress().to_bytes(),
+        (server_secret_keyshare * client_public_key).compress().to_bytes(),
+    ]
+    .concat();
+
+    let prk = extract(&ikm);
+    let preamble_hash = hash(&preamble);
+    let handshake_secret = derive_secret(&prk, "HandshakeSecret", &preamble_hash);
+    let server_mac_key = derive_secret(&handshake_secret, "ServerMAC", b"");
+    let client_mac_key = derive_secret(&handshake_secret, "ClientMAC", b"");
+
+    let server_mac = mac(&server_mac_key, &preamble_hash);
+    let expected_client_mac = mac(&client_mac_key, &hash(&[preamble.as_slice(), &server_mac].concat()));
+
+    let ke2 = [credential_response.as_slice(), server_nonce, server_keyshare.as_bytes(), &server_mac].concat();
+    Ok((ke2, expected_client_mac))
+}
+
+// Drop expired handshakes, then refuse an address holding too many open ones
+async fn check_pending(state: &AppState, table: &str, addr: SocketAddr) -> Result<(), AppError> {
+    let now = Utc::now();
+    sqlx::query(&format!("DELETE FROM {} WHERE expires_at <= ?", table))
+        .bind(now)
+        .execute(state.database.pool())
+        .await?;
+
+    let (pending,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM {} WHERE client_ip = ?", table))
+        .bind(addr.ip().to_string())
+        .fetch_one(state.database.pool())
+        .await?;
+    if pending >= MAX_PENDING_PER_IP {
+        return Err(AppError::RateLimited("Too many unfinished requests, try again later".to_string()));
+    }
+    Ok(())
+}
+
+async fn ensure_available(state: &AppState, username: &str, email: &str) -> Result<(), AppError> {
+    let existing_user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ? OR username = ?")
+        .bind(email)
+        .bind(username)
+        .fetch_optional(state.database.pool())
+        .await?;
+
+    if existing_user.is_some() {
+        return Err(AppError::Validation("User already exists".to_string()));
+    }
+    Ok(())
+}
+
+// Generated on first use and kept encrypted at rest
+async fn server_setup(state: &AppState) -> Result<ServerSetup, AppError> {
+    let context = "opaque-server-setup";
+
+    let select = "SELECT secret_encrypted FROM opaque_server_setup WHERE id = 1";
+    let sealed = match sqlx::query_as::<_, (String,)>(select).fetch_optional(state.database.pool()).await? {
+        Some((sealed,)) => sealed,
+        None => {
+            // Two first requests may race here, the row that lands first wins
+            let mut secret = random_bytes::<64>().to_vec();
+            secret.extend_from_slice(random_scalar().as_bytes());
+            sqlx::query("INSERT OR IGNORE INTO opaque_server_setup (id, secret_encrypted, created_at) VALUES (1, ?, ?)")
+                .bind(encrypt_secret(&state.config, context, &secret)?)
+                .bind(Utc::now())
+                .execute(state.database.pool())
+                .await?;
+
+            let (sealed,): (String,) = sqlx::query_as(select).fetch_one(state.database.pool()).await?;
+            sealed
+        }
+    };
+
+    let secret = decrypt_secret(&state.config, context, &sealed)?;
+    if secret.len() != 96 {
+        return Err(AppError::Internal("Invalid OPAQUE server setup".to_string()));
+    }
+
+    let mut private_key = [0u8; 32];
+    private_key.copy_from_slice(&secret[64..]);
+    let private_key = Option::<Scalar>::from(Scalar::from_canonical_bytes(private_key))
+        .ok_or_else(|| AppError::Internal("Invalid OPAQUE server setup".to_string()))?;
+
+    Ok(setup_from(secret[..64].to_vec(), private_key))
+}
+
+fn setup_from(oprf_seed: Vec<u8>, private_key: Scalar) -> ServerSetup {
+    ServerSetup {
+        oprf_seed,
+        public_key: RistrettoPoint::mul_base(&private_key).compress().to_bytes(),
+        private_key,
+    }
+}
+
+// Per-credential OPRF key, derived so the server stores only the seed
+fn oprf_key(setup: &ServerSetup, credential_identifier: &str) -> Scalar {
+    let seed = expand(
+        &setup.oprf_seed,
+        &[credential_identifier.as_bytes(), b"OprfKey"].concat(),
+        32,
+    );
+    derive_key_pair(&seed, b"OPAQUE-DeriveKeyPair")
+}
+
+// DeriveKeyPair (RFC 9497 section 3.2.1), returning the private key
+fn derive_key_pair(seed: &[u8], info: &[u8]) -> Scalar {
+    let dst = [b"DeriveKeyPair".as_slice(), OPRF_CONTEXT].concat();
+    let derive_input = [seed, &(info.len() as u16).to_be_bytes(), info].concat();
+    for counter in 0..=255u8 {
+        let uniform = expand_message_xmd(&[derive_input.as_slice(), &[counter]].concat(), &dst);
+        let key = Scalar::from_bytes_mod_order_wide(&uniform);
+        if key != Scalar::ZERO {
+            return key;
+        }
+    }
+    unreachable!("a SHA-512 output reduced to zero 256 times")
+}
+
+fn decode(value: &str, len: usize) -> Result<Vec<u8>, AppError> {
+    URL_SAFE_NO_PAD
+        .decode(value)
+        .ok()
+        .filter(|bytes| bytes.len() == len)
+        .ok_or_else(|| AppError::Validation("Malformed OPAQUE message".to_string()))
+}
+
+// Group elements from the client must be valid and not the identity
+fn decode_element(bytes: &[u8]) -> Result<RistrettoPoint, AppError> {
+    CompressedRistretto::from_slice(bytes)
+        .ok()
+        .and_then(|compressed| compressed.decompress())
+        .filter(|point| !point.is_identity())
+        .ok_or_else(|| AppError::Validation("Malformed OPAQUE message".to_string()))
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+fn random_scalar() -> Scalar {
+    Scalar::from_bytes_mod_order_wide(&random_bytes::<64>())
+}
+
+fn length_prefixed(value: &[u8]) -> Vec<u8> {
+    [&(value.len() as u16).to_be_bytes(), value].concat()
+}
+
+fn hash(data: &[u8]) -> Vec<u8> {
+    digest::digest(&SHA512, data).as_ref().to_vec()
+}
+
+fn mac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA512, key), data).as_ref().to_vec()
+}
+
+// HKDF-Extract with an empty salt (RFC 5869)
+fn extract(ikm: &[u8]) -> Vec<u8> {
+    mac(&[], ikm)
+}
+
+// HKDF-Expand (RFC 5869)
+fn expand(prk: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(len);
+    let mut block = Vec::new();
+    let mut counter = 1u8;
+    while output.len() < len {
+        block = mac(prk, &[block.as_slice(), info, &[counter]].concat());
+        output.extend_from_slice(&block);
+        counter += 1;
+    }
+    output.truncate(len);
+    output
+}
+
+// Derive-Secret from RFC 9807 section 6.4.2
+fn derive_secret(secret: &[u8], label: &str, context: &[u8]) -> Vec<u8> {
+    let label = format!("OPAQUE-{}", label);
+    let custom_label = [
+        (HASH_LEN as u16).to_be_bytes().as_slice(),
+        &[label.len() as u8],
+        label.as_bytes(),
+        &[context.len() as u8],
+        context,
+    ]
+    .concat();
+    expand(secret, &custom_label, HASH_LEN)
+}
+
+// expand_message_xmd (RFC 9380 section 5.3.1) with SHA-512, for one 64 byte block
+fn expand_message_xmd(message: &[u8], dst: &[u8]) -> [u8; 64] {
+    let dst_prime = [dst, &[dst.len() as u8]].concat();
+    let b0 = hash(&[[0u8; 128].as_slice(), message, &64u16.to_be_bytes(), &[0], &dst_prime].concat());
+    let b1 = hash(&[b0.as_slice(), &[1], &dst_prime].concat());
+
+    let mut output = [0u8; 64];
+    output.copy_from_slice(&b1);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 9807 appendix C.1.1, OPAQUE-3DH Real Test Vector 1
+    const OPRF_SEED: &str = "f433d0227b0b9dd54f7c4422b600e764e47fb503f1f9a0f0a47c6606b054a7fdc65347f1a08f277e22358bbabe26f823fca82c7848e9a75661f4ec5d5c1989ef";
+    const SERVER_PRIVATE_KEY: &str = "47451a85372f8b3537e249d7b54188091fb18edde78094b43e2ba42b5eb89f0d";
+    const CREDENTIAL_IDENTIFIER: &str = "1234";
+
+    fn unhex(value: &str) -> Vec<u8> {
+        (0..value.len()).step_by(2).map(|i| u8::from_str_radix(&value[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn scalar(value: &str) -> Scalar {
+        Option::from(Scalar::from_canonical_bytes(unhex(value).try_into().unwrap())).unwrap()
+    }
+
+    fn nonce(value: &str) -> [u8; NONCE_LEN] {
+        unhex(value).try_into().unwrap()
+    }
+
+    fn rfc_setup() -> ServerSetup {
+        setup_from(unhex(OPRF_SEED), scalar(SERVER_PRIVATE_KEY))
+    }
+
+    // RFC 9497 appendix A.1.1, OPRF(ristretto255, SHA-512) mode 0
+    #[test]
+    fn oprf_derive_key_pair_and_evaluate() {
+        let key = derive_key_pair(&[0xa3; 32], b"test key");
+        assert_eq!(key, scalar("5ebcea5ee37023ccb9fc2d2019f9d7737be85591ae8652ffa9ef0f4d37063b0e"));
+
+        let blinded = decode_element(&unhex("609a0ae68c15a3cf6903766461307e5c8bb2f95e7e6550e1ffa2dc99e412803c")).unwrap();
+        assert_eq!(
+            (key * blinded).compress().to_bytes().to_vec(),
+            unhex("7ec6578ae5120958eb2db1745758ff379e77cb64fe77b0b2d8cc917ea0869c7e")
+        );
+    }
+
+    #[test]
+    fn registration_response() {
+        let setup = rfc_setup();
+        let blinded = decode_element(&unhex("5059ff249eb1551b7ce4991f3336205bde44a105a032e747d21bf382e75f7a71")).unwrap();
+        let evaluated = (oprf_key(&setup, CREDENTIAL_IDENTIFIER) * blinded).compress();
+
+        assert_eq!(
+            [evaluated.as_bytes().as_slice(), &setup.public_key].concat(),
+            unhex(
+                "7408a268083e03abc7097fc05b587834539065e86fb0c7b6342fcf5e01e5b019\
+                 b2fe7af9f48cc502d016729d2fe25cdd433f2c4bc904660b2a382c9b79df1a78"
+            )
+        );
+    }
+
+    #[test]
+    fn login_key_exchange() {
+        let record = unhex(
+            "76a845464c68a5d2f7e442436bb1424953b17d3e2e289ccbaccafb57ac5c3675\
+             1ac5844383c7708077dea41cbefe2fa15724f449e535dd7dd562e66f5ecfb958\
+             64eadddec9db5874959905117dad40a4524111849799281fefe3c51fa82785c5\
+             ac13171b2f17bc2c74997f0fce1e1f35bec6b91fe2e12dbd323d23ba7a38dfec\
+             634b0f5b96109c198a8027da51854c35bee90d1e1c781806d07d49b76de6a28b\
+             8d9e9b6c93b9f8b64d16dddd9c5bfb5fea48ee8fd2f75012a8b308605cdd8ba5"
+        );
+        let ke1 = unhex(
+            "c4dedb0ba6ed5d965d6f250fbe554cd45cba5dfcce3ce836e4aee778aa3cd44d\
+             da7e07376d6d6f034cfa9bb537d11b8c6b4238c334333d1f0aebb380cae6a6cc\
+             6e29bee50701498605b2c085d7b241ca15ba5c32027dd21ba420b94ce60da326"
+        );
+        let server_secret_keyshare = derive_key_pair(
+            &unhex("05a4f54206eef1ba2f615bc0aa285cb22f26d1153b5b40a1e85ff80da12f982f"),
+            b"OPAQUE-DeriveDiffieHellmanKeyPair",
+        );
+
+        let (ke2, expected_client_mac) = respond(
+            &rfc_setup(),
+            b"OPAQUE-POC",
+            CREDENTIAL_IDENTIFIER,
+            &record,
+            &ke1,
+            &nonce("38fe59af0df2c79f57b8780278f5ae47355fe1f817119041951c80f612fdfc6d"),
+            &nonce("71cd9960ecef2fe0d0f7494986fa3d8b2bb01963537e60efb13981e138e3d4a1"),
+            &server_secret_keyshare,
+        )
+        .unwrap();
+
+        // The credential response, server nonce and key share; the server
+        // MAC that follows is covered by the client MAC
+        assert_eq!(
+            ke2[..ke2.len() - HASH_LEN],
+            unhex(
+                "7e308140890bcde30cbcea28b01ea1ecfbd077cff62c4def8efa075aabcbb471\
+                 38fe59af0df2c79f57b8780278f5ae47355fe1f817119041951c80f612fdfc6d\
+                 d6ec60bcdb26dc455ddf3e718f1020490c192d70dfc7e403981179d8073d1146\
+                 a4f9aa1ced4e4cd984c657eb3b54ced3848326f70331953d91b02535af44d9fe\
+                 dc80188ca46743c52786e0382f95ad85c08f6afcd1ccfbff95e2bdeb015b166c\
+                 6b20b92f832cc6df01e0b86a7efd92c1c804ff865781fa93f2f20b446c8371b6\
+                 71cd9960ecef2fe0d0f7494986fa3d8b2bb01963537e60efb13981e138e3d4a1\
+                 c4f62198a9d6fa9170c42c3c71f1971b29eb1d5d0bd733e40816c91f7912cc4a"
+            )[..]
+        );
+        assert_eq!(
+            expected_client_mac,
+            unhex(
+                "4455df4f810ac31a6748835888564b536e6da5d9944dfea9e34defb9575fe5e2\
+                 661ef61d2ae3929bcf57e53d464113d364365eb7d1a57b629707ca48da18e442"
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_identity_element() {
+        assert!(decode_element(&[0u8; ELEMENT_LEN]).is_err());
+        assert!(decode_element(&[0xff; ELEMENT_LEN]).is_err());
+    }
+}
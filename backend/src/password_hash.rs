@@ -0,0 +1,154 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher as _, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+use crate::{config::Config, error::AppError};
+
+// Password hashes are stored as PHC strings. New hashes use Argon2id with
+// the configured parameters; bcrypt hashes from before the switch still
+// verify and are replaced on the next successful login.
+#[derive(Clone)]
+pub struct PasswordHasher {
+    params: Params,
+    // Hashing is deliberately slow and memory hungry, so it runs on the
+    // blocking pool with a cap on how many hashes are computed at once
+    permits: Arc<Semaphore>,
+    // Verified against when there is no real hash, so unknown accounts take
+    // as long as wrong passwords
+    dummy_hash: Arc<String>,
+}
+
+pub struct Verification {
+    pub valid: bool,
+    pub needs_rehash: bool, // Valid, but not hashed with the current policy
+}
+
+impl PasswordHasher {
+    pub fn from_config(config: &Config) -> Result<Self, AppError> {
+        let params = Params::new(
+            config.argon2_memory_kib,
+            config.argon2_iterations,
+            config.argon2_parallelism,
+            None,
+        )
+        .map_err(|e| AppError::Internal(format!("Invalid Argon2 parameters: {}", e)))?;
+
+        let dummy_hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+            .hash_password(b"dummy password", &SaltString::generate(&mut OsRng))
+            .map_err(|e| AppError::Internal(format!("Password hashing failed: {}", e)))?
+            .to_string();
+
+        Ok(PasswordHasher {
+            params,
+            permits: Arc::new(Semaphore::new(config.password_hash_concurrency.max(1))),
+            dummy_hash: Arc::new(dummy_hash),
+        })
+    }
+
+    pub async fn verify_dummy(&self, password: &str) -> Result<(), AppError> {
+        self.verify(password, &self.dummy_hash).await?;
+        Ok(())
+    }
+
+    pub async fn hash(&self, password: &str) -> Result<String, AppError> {
+        let argon2 = self.argon2();
+        let password = password.to_string();
+
+        self.run_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            argon2
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+                .map_err(|e| AppError::Internal(format!("Password hashing failed: {}", e)))
+        })
+        .await
+    }
+
+    pub async fn verify(&self, password: &str, stored_hash: &str) -> Result<Verification, AppError> {
+        let argon2 = self.argon2();
+        let params = self.params.clone();
+        let password = password.to_string();
+        let stored_hash = stored_hash.to_string();
+
+        self.run_blocking(move || {
+            if is_bcrypt(&stored_hash) {
+                let valid = bcrypt::verify(&password, &stored_hash)
+                    .map_err(|e| AppError::Internal(format!("Password verification failed: {}", e)))?;
+                return Ok(Verification { valid, needs_rehash: valid });
+            }
+
+            let parsed = PasswordHash::new(&stored_hash)
+                .map_err(|e| AppError::Internal(format!("Invalid password hash: {}", e)))?;
+            let valid = argon2.verify_password(password.as_bytes(), &parsed).is_ok();
+
+            let current = parsed.algorithm == Algorithm::Argon2id.ident()
+                && Params::try_from(&parsed).is_ok_and(|stored| {
+                    stored.m_cost() == params.m_cost()
+                        && stored.t_cost() == params.t_cost()
+                        && stored.p_cost() == params.p_cost()
+                });
+
+            Ok(Verification { valid, needs_rehash: valid && !current })
+        })
+        .await
+    }
+
+    // Argon2 hashes from elsewhere are verified with their own parameters,
+    // so only those no costlier than ours are taken in
+    pub fn within_limits(&self, hash: &str) -> bool {
+        PasswordHash::new(hash).is_ok_and(|parsed| {
+            parsed.algorithm.as_str().starts_with("argon2")
+                && Params::try_from(&parsed).is_ok_and(|stored| {
+                    stored.m_cost() <= self.params.m_cost()
+                        && stored.t_cost() <= self.params.t_cost()
+                        && stored.p_cost() <= self.params.p_cost()
+                })
+        })
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    // Also used for other Argon2 work, such as deriving bundle keys
+    pub async fn run_blocking<T, F>(&self, task: F) -> Result<T, AppError>
+    where
+        F: FnOnce() -> Result<T, AppError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|_| AppError::Internal("Password hasher is shut down".to_string()))?;
+
+        tokio::task::spawn_blocking(task)
+            .await
+            .map_err(|e| AppError::Internal(format!("Password hashing task failed: {}", e)))?
+    }
+}
+
+// Modular crypt format used by bcrypt: $2a$, $2b$ or $2y$
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn imported_hashes_may_not_cost_more_than_ours() {
+        let hasher = PasswordHasher::from_config(&crate::test_support::config()).unwrap();
+        let own = hasher.hash("password").await.unwrap();
+        assert!(hasher.within_limits(&own));
+
+        assert!(!hasher.within_limits("$argon2id$v=19$m=4194304,t=1,p=1$c2FsdHNhbHQ$aGFzaGhhc2hoYXNoaGFzaA"));
+        assert!(!hasher.within_limits("$argon2id$v=19$m=64,t=100,p=1$c2FsdHNhbHQ$aGFzaGhhc2hoYXNoaGFzaA"));
+        assert!(!hasher.within_limits("$2b$12$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW"));
+        assert!(!hasher.within_limits("not a hash"));
+    }
+}
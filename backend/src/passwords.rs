@@ -0,0 +1,187 @@
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+    auth::validate_password,
+    auth_user::FirstPartyUser,
+    crypto::{generate_token, hash_token},
+    error::AppError,
+    mailer::{self, Email},
+    models::User,
+    oauth::{find_active_user, revoke_user_tokens},
+    sessions::revoke_all_sessions,
+    AppState,
+};
+
+const RESET_EXPIRY_MINUTES: i64 = 30;
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+// Always accepted, so the response does not reveal whether the address
+// belongs to an account
+pub async fn forgot_password(
+    State(state): State<AppState>,
+    Json(payload): Json<ForgotPasswordRequest>,
+) -> Result<StatusCode, AppError> {
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+        .bind(&payload.email)
+        .fetch_optional(state.database.pool())
+        .await?
+        .filter(|user| user.is_active && user.deletion_scheduled_at.is_none());
+
+    // Everything past the lookup runs after the response, so an existing
+    // account takes no longer to answer than an unknown address
+    if let Some(user) = user {
+        tokio::spawn(async move {
+            if let Err(e) = send_reset_email(&state, &user).await {
+                warn!("Password reset email for user {} failed: {}", user.id, e);
+            }
+        });
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn send_reset_email(state: &AppState, user: &User) -> Result<(), AppError> {
+    if mailer::is_throttled(state, "password_reset_tokens", &user.id).await? {
+        info!("Password reset email for user {} throttled", user.id);
+        return Ok(());
+    }
+
+    let token = generate_token();
+    let now = Utc::now();
+
+    sqlx::query(
+        r#"
+        INSERT INTO password_reset_tokens (token_hash, user_id, expires_at, created_at)
+        VALUES (?, ?, ?, ?)
+        "#
+    )
+    .bind(hash_token(&token))
+    .bind(&user.id)
+    .bind(now + Duration::minutes(RESET_EXPIRY_MINUTES))
+    .bind(now)
+    .execute(state.database.pool())
+    .await?;
+
+    let link = format!("{}/reset-password?token={}", state.config.frontend_url, token);
+    state
+        .mailer
+        .send(Email {
+            to: user.email.clone(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Hello {},\n\nA password reset was requested for your account. Choose a new password here:\n\n{}\n\n\
+                 The link expires in {} minutes and works once. If you did not ask for this, ignore this email.\n",
+                user.username, link, RESET_EXPIRY_MINUTES
+            ),
+        })
+        .await
+}
+
+pub async fn reset_password(
+    State(state): State<AppState>,
+    Json(payload): Json<ResetPasswordRequest>,
+) -> Result<StatusCode, AppError> {
+    validate_password(&payload.new_password)?;
+
+    let token_hash = hash_token(&payload.token);
+    let now = Utc::now();
+
+    // Claim the token first, so it cannot be used twice concurrently
+    let result = sqlx::query(
+        "UPDATE password_reset_tokens SET used_at = ? WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?"
+    )
+    .bind(now)
+    .bind(&token_hash)
+    .bind(now)
+    .execute(state.database.pool())
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::Validation("Invalid or expired reset token".to_string()));
+    }
+
+    let (user_id,): (String,) = sqlx::query_as("SELECT user_id FROM password_reset_tokens WHERE token_hash = ?")
+        .bind(&token_hash)
+        .fetch_one(state.database.pool())
+        .await?;
+    let user = find_active_user(&state, &user_id).await?;
+
+    // Following the emailed link also proves control of the address. An
+    // OPAQUE record would still accept the old password, so it is dropped.
+    sqlx::query(
+        r#"
+        UPDATE users
+        SET password_hash = ?, opaque_record = NULL, updated_at = ?, email_verified_at = COALESCE(email_verified_at, ?)
+        WHERE id = ?
+        "#
+    )
+    .bind(state.password_hasher.hash(&payload.new_password).await?)
+    .bind(now)
+    .bind(now)
+    .bind(&user.id)
+    .execute(state.database.pool())
+    .await?;
+
+    sqlx::query("DELETE FROM password_reset_tokens WHERE user_id = ? AND used_at IS NULL")
+        .bind(&user.id)
+        .execute(state.database.pool())
+        .await?;
+
+    sign_out_everywhere(&state, &user.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn change_password(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<StatusCode, AppError> {
+    let user = auth.user;
+    if user.password_hash.is_empty() {
+        return Err(AppError::Validation("Account signs in with OPAQUE and has no password here".to_string()));
+    }
+
+    let verification = state.password_hasher.verify(&payload.current_password, &user.password_hash).await?;
+    if !verification.valid {
+        return Err(AppError::Authentication("Current password is incorrect".to_string()));
+    }
+    validate_password(&payload.new_password)?;
+
+    sqlx::query("UPDATE users SET password_hash = ?, updated_at = ? WHERE id = ?")
+        .bind(state.password_hasher.hash(&payload.new_password).await?)
+        .bind(Utc::now())
+        .bind(&user.id)
+        .execute(state.database.pool())
+        .await?;
+
+    sign_out_everywhere(&state, &user.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Whoever knew the old password may hold sessions or client grants, so all
+// of them end, including the session the change was made from
+async fn sign_out_everywhere(state: &AppState, user_id: &str) -> Result<(), AppError> {
+    revoke_all_sessions(state, user_id).await?;
+    revoke_user_tokens(state, user_id).await
+}
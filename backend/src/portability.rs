@@ -0,0 +1,971 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use axum::{
+    extract::{ConnectInfo, Json, State},
+    http::StatusCode,
+    response::Json as ResponseJson,
+};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+use reqwest::Url;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    auth::validate_password,
+    auth_user::FirstPartyUser,
+    crypto::{decrypt_secret, decrypt_user_secret, encrypt_secret, encrypt_user_secret, generate_token},
+    did::{is_local_web_did, managed_did_document, managed_key_context, resolve, web_did},
+    error::AppError,
+    lockout::throttle_request,
+    mfa::totp_context,
+    models::{Address, Consent, Session, User, UserResponse, UserTotp, WebAuthnCredential},
+    profile::{
+        is_plausible_email, open_profile, seal_profile, validate_locale, validate_name, validate_optional,
+        validate_phone_number, validate_picture, validate_zoneinfo,
+    },
+    reauth::{self, Reauthentication},
+    verification::send_verification_email,
+    AppState,
+};
+
+// Portable identity bundles. An export holds everything kept about a user,
+// signed by this instance as a compact JWS (EdDSA). With a passphrase the
+// JWS is also encrypted, and only then does it carry secrets: password
+// hash, TOTP seed and managed DID private keys. Other Idryos instances
+// that list the issuer in TRUSTED_IMPORT_ISSUERS check the signature
+// against the key it publishes under /.well-known/idryos-export-keys
+// before importing.
+pub const BUNDLE_FORMAT: &str = "idryos-export/1";
+const BUNDLE_TYP: &str = "idryos-export+jws";
+const SIGNING_KEY_CONTEXT: &str = "export-signing-key";
+const BUNDLE_AAD: &[u8] = b"idryos-export/1";
+const EXPORT_ID_EXPIRY_DAYS: i64 = 7; // How long an export can be used to move the account
+
+// Limits on the key derivation parameters an imported bundle may ask for
+const MAX_KDF_MEMORY_KIB: u32 = 65_536;
+const MAX_KDF_ITERATIONS: u32 = 4;
+const MAX_KDF_PARALLELISM: u32 = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bundle {
+    pub format: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jws: Option<String>, // Signed export, when not encrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted: Option<EncryptedExport>,
+}
+
+// AES-256-GCM over the JWS, keyed with Argon2id of the passphrase
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedExport {
+    pub kdf: String, // "argon2id"
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub salt: String,       // base64url
+    pub nonce: String,      // base64url
+    pub ciphertext: String, // base64url
+}
+
+// JWS payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportData {
+    pub iss: String,
+    pub sub: String,
+    pub iat: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>, // Recorded by the issuer; moving the account there consumes it
+    pub profile: ExportedProfile,
+    pub consents: Vec<ExportedConsent>,
+    pub did: Option<ExportedDid>,
+    pub credentials: ExportedCredentials,
+    pub sessions: Vec<ExportedSession>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedProfile {
+    pub username: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub locale: Option<String>,
+    pub zoneinfo: Option<String>,
+    pub picture: Option<String>,
+    pub phone_number: Option<String>,
+    pub address: Option<Address>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedConsent {
+    pub client_id: String,
+    pub client_name: Option<String>,
+    pub scopes: Option<String>,
+    pub claims: Option<Value>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedDid {
+    pub did: String,
+    pub document: Option<Value>, // As resolved at export time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub managed_key: Option<ExportedManagedKey>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedManagedKey {
+    pub verification_method: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<String>, // PKCS#8, base64url; encrypted bundles only
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub also_known_as: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedCredentials {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>, // PHC string; encrypted bundles only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp: Option<ExportedTotp>,
+    pub webauthn_rp_id: String, // Passkeys only work where this RP ID is served
+    pub webauthn: Vec<ExportedWebAuthnCredential>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedTotp {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>, // base64url; encrypted bundles only
+    pub confirmed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedWebAuthnCredential {
+    pub credential_id: String,
+    pub public_key: String,
+    pub sign_count: i64,
+    pub name: String,
+    pub transports: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedSession {
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportRequest {
+    pub passphrase: Option<String>,
+    // Needed with a passphrase, since the bundle then carries secrets
+    #[serde(flatten)]
+    pub reauthentication: Reauthentication,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportRequest {
+    pub bundle: Bundle,
+    pub passphrase: Option<String>,
+}
+
+pub async fn export_account(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<ExportRequest>,
+) -> Result<ResponseJson<Bundle>, AppError> {
+    if payload.passphrase.is_some() {
+        reauth::confirm(&state, &auth, &payload.reauthentication, addr.ip()).await?;
+    }
+
+    let bundle = export_user(&state, &auth.user, payload.passphrase.as_deref()).await?;
+    Ok(ResponseJson(bundle))
+}
+
+// Creates a new, unverified account from a bundle. Consents and sessions
+// belong to the exporting instance and are not imported.
+pub async fn import_account(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<ImportRequest>,
+) -> Result<(StatusCode, ResponseJson<UserResponse>), AppError> {
+    throttle_request(&state, "import", addr.ip()).await?;
+
+    let user = import_bundle(&state, &payload.bundle, payload.passphrase.as_deref()).await?;
+    Ok((StatusCode::CREATED, ResponseJson(user.into())))
+}
+
+// Public half of the signing key, for instances importing our bundles
+pub async fn export_keys(State(state): State<AppState>) -> Result<ResponseJson<Value>, AppError> {
+    let key_pair = signing_key(&state).await?;
+    let public_key = key_pair.public_key().as_ref();
+
+    Ok(ResponseJson(json!({
+        "keys": [{
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "x": URL_SAFE_NO_PAD.encode(public_key),
+            "kid": key_id(public_key),
+            "alg": "EdDSA",
+            "use": "sig",
+        }]
+    })))
+}
+
+pub async fn export_user(state: &AppState, user: &User, passphrase: Option<&str>) -> Result<Bundle, AppError> {
+    if let Some(passphrase) = passphrase {
+        validate_password(passphrase)?;
+    }
+
+    let data = collect(state, user, passphrase.is_some()).await?;
+    let jws = sign_jws(state, BUNDLE_TYP, &data).await?;
+
+    let bundle = match passphrase {
+        Some(passphrase) => Bundle {
+            format: BUNDLE_FORMAT.to_string(),
+            jws: None,
+            encrypted: Some(encrypt(state, &jws, passphrase).await?),
+        },
+        None => Bundle {
+            format: BUNDLE_FORMAT.to_string(),
+            jws: Some(jws),
+            encrypted: None,
+        },
+    };
+
+    info!("Data of user {} exported", user.id);
+    Ok(bundle)
+}
+
+pub async fn import_bundle(state: &AppState, bundle: &Bundle, passphrase: Option<&str>) -> Result<User, AppError> {
+    let data = open_bundle(state, bundle, passphrase).await?;
+    import_data(state, &data, &Uuid::new_v4().to_string(), false).await
+}
+
+// Creates the account under the given id. did:web identifiers of this
+// instance are only taken in when `rehosted`, i.e. `migration::migrate_in`
+// moved the DID to the new account's own identifier.
+pub async fn import_data(state: &AppState, data: &ExportData, user_id: &str, rehosted: bool) -> Result<User, AppError> {
+    let profile = &validated_profile(&data.profile)?;
+
+    if let Some(did) = data.did.as_ref().map(|did| did.did.as_str()) {
+        if is_local_web_did(&state.config, did) && !(rehosted && did == web_did(&state.config, user_id)) {
+            return Err(AppError::Validation("DIDs of this instance cannot be imported".to_string()));
+        }
+    }
+
+    let (taken,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE LOWER(email) = LOWER(?) OR username = ?")
+        .bind(&profile.email)
+        .bind(&profile.username)
+        .fetch_one(state.database.pool())
+        .await?;
+    if taken > 0 {
+        return Err(AppError::Validation("User already exists".to_string()));
+    }
+
+    let did = data.did.as_ref().map(|did| did.did.as_str());
+    if let Some(did) = did {
+        let (linked,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE did = ?")
+            .bind(did)
+            .fetch_one(state.database.pool())
+            .await?;
+        if linked > 0 {
+            return Err(AppError::Validation("DID is linked to another account".to_string()));
+        }
+    }
+
+    // Hashes made under another policy are upgraded on the next login;
+    // without a usable one the user sets a password through a reset
+    let password_hash = data
+        .credentials
+        .password_hash
+        .clone()
+        .filter(|hash| state.password_hasher.within_limits(hash))
+        .unwrap_or_default();
+
+    let now = Utc::now();
+
+    // Secrets are sealed before the transaction, since that creates the
+    // account's data key
+    let totp = match &data.credentials.totp {
+        Some(ExportedTotp { secret: Some(secret), confirmed_at }) => {
+            let secret = decode(secret)?;
+            let sealed = encrypt_user_secret(&state.config, user_id, &totp_context(user_id), &secret).await?;
+            Some((sealed, *confirmed_at))
+        }
+        _ => None,
+    };
+    let managed_key = match &data.did {
+        Some(ExportedDid {
+            did,
+            managed_key: Some(ExportedManagedKey { verification_method, private_key: Some(private_key), also_known_as }),
+            ..
+        }) => {
+            let pkcs8 = decode(private_key)?;
+            Ed25519KeyPair::from_pkcs8(&pkcs8)
+                .map_err(|_| AppError::Validation("Invalid managed DID key in bundle".to_string()))?;
+            let sealed = encrypt_user_secret(&state.config, user_id, &managed_key_context(did), &pkcs8).await?;
+            let also_known_as = Some(also_known_as)
+                .filter(|also_known_as| !also_known_as.is_empty())
+                .and_then(|also_known_as| serde_json::to_string(also_known_as).ok());
+            Some((did.clone(), verification_method.clone(), sealed, also_known_as))
+        }
+        _ => None,
+    };
+
+    let address = profile.address.as_ref().and_then(|address| serde_json::to_string(address).ok());
+    let [given_name, family_name, phone_number, address] = seal_profile(
+        &state.config,
+        user_id,
+        [profile.given_name.as_deref(), profile.family_name.as_deref(), profile.phone_number.as_deref(), address.as_deref()],
+    )
+    .await?;
+
+    let mut tx = state.database.pool().begin().await?;
+
+    // The email address is verified again here; the exporting instance
+    // cannot vouch for it
+    sqlx::query(
+        r#"
+        INSERT INTO users (id, username, email, password_hash, did, created_at, updated_at,
+                           given_name, family_name, locale, zoneinfo, picture, phone_number, address, profile_sealed)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 1)
+        "#
+    )
+    .bind(user_id)
+    .bind(&profile.username)
+    .bind(&profile.email)
+    .bind(&password_hash)
+    .bind(did)
+    .bind(now)
+    .bind(now)
+    .bind(given_name)
+    .bind(family_name)
+    .bind(&profile.locale)
+    .bind(&profile.zoneinfo)
+    .bind(&profile.picture)
+    .bind(phone_number)
+    .bind(address)
+    .execute(&mut *tx)
+    .await?;
+
+    if let Some((secret_encrypted, confirmed_at)) = &totp {
+        sqlx::query("INSERT INTO user_totp (user_id, secret_encrypted, confirmed_at, created_at) VALUES (?, ?, ?, ?)")
+            .bind(user_id)
+            .bind(secret_encrypted)
+            .bind(confirmed_at)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    if let Some((did, verification_method, private_key_encrypted, also_known_as)) = &managed_key {
+        sqlx::query(
+            r#"
+            INSERT INTO managed_did_keys (did, user_id, verification_method, private_key_encrypted, also_known_as, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#
+        )
+        .bind(did)
+        .bind(user_id)
+        .bind(verification_method)
+        .bind(private_key_encrypted)
+        .bind(also_known_as)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    if data.credentials.webauthn_rp_id == state.config.webauthn_rp_id {
+        for credential in &data.credentials.webauthn {
+            sqlx::query(
+                r#"
+                INSERT OR IGNORE INTO webauthn_credentials
+                    (id, user_id, credential_id, public_key, sign_count, name, transports, created_at, last_used_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+                "#
+            )
+            .bind(Uuid::new_v4().to_string())
+            .bind(user_id)
+            .bind(&credential.credential_id)
+            .bind(&credential.public_key)
+            .bind(credential.sign_count)
+            .bind(&credential.name)
+            .bind(&credential.transports)
+            .bind(credential.created_at)
+            .bind(credential.last_used_at)
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    tx.commit().await?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_one(state.database.pool())
+        .await?;
+
+    if let Err(e) = send_verification_email(state, &user).await {
+        warn!("Verification email for user {} failed: {}", user.id, e);
+    }
+
+    info!("User {} imported from {} (subject {})", user.id, data.iss, data.sub);
+    Ok(user)
+}
+
+// Decrypt when needed and check the exporting instance's signature
+pub async fn open_bundle(state: &AppState, bundle: &Bundle, passphrase: Option<&str>) -> Result<ExportData, AppError> {
+    if bundle.format != BUNDLE_FORMAT {
+        return Err(AppError::Validation("Unsupported bundle format".to_string()));
+    }
+
+    let jws = match (&bundle.encrypted, &bundle.jws) {
+        (Some(encrypted), _) => {
+            let passphrase = passphrase
+                .ok_or_else(|| AppError::Validation("The bundle is encrypted, a passphrase is required".to_string()))?;
+            decrypt(state, encrypted, passphrase).await?
+        }
+        (None, Some(jws)) => jws.clone(),
+        (None, None) => return Err(AppError::Validation("Bundle has no content".to_string())),
+    };
+
+    verify_jws(state, &jws, BUNDLE_TYP).await
+}
+
+// The checks registration and profile updates apply, since the exporting
+// instance may not have made them
+fn validated_profile(profile: &ExportedProfile) -> Result<ExportedProfile, AppError> {
+    let username = profile.username.trim().to_string();
+    if username.is_empty() {
+        return Err(AppError::Validation("username cannot be empty".to_string()));
+    }
+    if !is_plausible_email(&profile.email) {
+        return Err(AppError::Validation("Invalid email address".to_string()));
+    }
+
+    Ok(ExportedProfile {
+        username,
+        email: profile.email.clone(),
+        given_name: validate_name("given_name", profile.given_name.clone())?,
+        family_name: validate_name("family_name", profile.family_name.clone())?,
+        locale: validate_optional(profile.locale.clone(), validate_locale)?,
+        zoneinfo: validate_optional(profile.zoneinfo.clone(), validate_zoneinfo)?,
+        picture: validate_optional(profile.picture.clone(), validate_picture)?,
+        phone_number: validate_optional(profile.phone_number.clone(), validate_phone_number)?,
+        address: profile.address.clone().filter(|address| *address != Address::default()),
+        ..profile.clone()
+    })
+}
+
+async fn collect(state: &AppState, user: &User, include_secrets: bool) -> Result<ExportData, AppError> {
+    let mut user = user.clone();
+    open_profile(&state.config, &mut user).await?;
+    let user = &user;
+
+    let pool = state.database.pool();
+
+    let mut consents = Vec::new();
+    let consent_rows = sqlx::query_as::<_, Consent>("SELECT * FROM oauth_consents WHERE user_id = ?")
+        .bind(&user.id)
+        .fetch_all(pool)
+        .await?;
+    for consent in consent_rows {
+        let client_name: Option<(String,)> = sqlx::query_as("SELECT name FROM oauth_clients WHERE id = ?")
+            .bind(&consent.client_id)
+            .fetch_optional(pool)
+            .await?;
+        consents.push(ExportedConsent {
+            client_name: client_name.map(|(name,)| name),
+            client_id: consent.client_id,
+            scopes: consent.scopes,
+            claims: consent.claims.and_then(|claims| serde_json::from_str(&claims).ok()),
+            created_at: consent.created_at,
+            updated_at: consent.updated_at,
+        });
+    }
+
+    let did = match &user.did {
+        Some(did) => {
+            let document = match managed_did_document(state, did).await? {
+                Some(document) => Some(document),
+                None => resolve(did).await.ok(),
+            };
+            let document = document.and_then(|document| serde_json::to_value(document).ok());
+            let managed_key: Option<(String, String, Option<String>)> = sqlx::query_as(
+                r#"
+                SELECT verification_method, private_key_encrypted, also_known_as
+                FROM managed_did_keys WHERE did = ? AND user_id = ?
+                "#
+            )
+            .bind(did)
+            .bind(&user.id)
+            .fetch_optional(pool)
+            .await?;
+
+            let managed_key = match managed_key {
+                Some((verification_method, sealed, also_known_as)) => {
+                    let private_key = if include_secrets {
+                        let pkcs8 = decrypt_user_secret(&state.config, &user.id, &managed_key_context(did), &sealed).await?;
+                        Some(URL_SAFE_NO_PAD.encode(pkcs8))
+                    } else {
+                        None
+                    };
+                    let also_known_as = also_known_as
+                        .and_then(|also_known_as| serde_json::from_str(&also_known_as).ok())
+                        .unwrap_or_default();
+                    Some(ExportedManagedKey { verification_method, private_key, also_known_as })
+                }
+                None => None,
+            };
+
+            Some(ExportedDid { did: did.clone(), document, managed_key })
+        }
+        None => None,
+    };
+
+    let totp = sqlx::query_as::<_, UserTotp>("SELECT * FROM user_totp WHERE user_id = ? AND confirmed_at IS NOT NULL")
+        .bind(&user.id)
+        .fetch_optional(pool)
+        .await?;
+    let totp = match totp {
+        Some(totp) => {
+            let secret = if include_secrets {
+                let secret =
+                    decrypt_user_secret(&state.config, &user.id, &totp_context(&user.id), &totp.secret_encrypted).await?;
+                Some(URL_SAFE_NO_PAD.encode(secret))
+            } else {
+                None
+            };
+            Some(ExportedTotp { secret, confirmed_at: totp.confirmed_at })
+        }
+        None => None,
+    };
+
+    let webauthn = sqlx::query_as::<_, WebAuthnCredential>("SELECT * FROM webauthn_credentials WHERE user_id = ?")
+        .bind(&user.id)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|credential| ExportedWebAuthnCredential {
+            credential_id: credential.credential_id,
+            public_key: credential.public_key,
+            sign_count: credential.sign_count,
+            name: credential.name,
+            transports: credential.transports,
+            created_at: credential.created_at,
+            last_used_at: credential.last_used_at,
+        })
+        .collect();
+
+    let sessions = sqlx::query_as::<_, Session>("SELECT * FROM sessions WHERE user_id = ? ORDER BY created_at")
+        .bind(&user.id)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|session| ExportedSession {
+            device_name: session.device_name,
+            user_agent: session.user_agent,
+            ip_address: session.ip_address,
+            created_at: session.created_at,
+            last_used_at: session.last_used_at,
+            revoked_at: session.revoked_at,
+        })
+        .collect();
+
+    // Lets `migration::migrate_out` tell receipts for bundles exported here
+    let jti = generate_token();
+    let now = Utc::now();
+    sqlx::query("DELETE FROM account_exports WHERE expires_at <= ?")
+        .bind(now)
+        .execute(pool)
+        .await?;
+    sqlx::query("INSERT INTO account_exports (jti, user_id, expires_at, created_at) VALUES (?, ?, ?, ?)")
+        .bind(&jti)
+        .bind(&user.id)
+        .bind(now + Duration::days(EXPORT_ID_EXPIRY_DAYS))
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+    Ok(ExportData {
+        iss: state.config.issuer.clone(),
+        sub: user.id.clone(),
+        iat: now.timestamp(),
+        jti: Some(jti),
+        profile: ExportedProfile {
+            username: user.username.clone(),
+            email: user.email.clone(),
+            email_verified: user.email_verified_at.is_some(),
+            given_name: user.given_name.clone(),
+            family_name: user.family_name.clone(),
+            locale: user.locale.clone(),
+            zoneinfo: user.zoneinfo.clone(),
+            picture: user.picture.clone(),
+            phone_number: user.phone_number.clone(),
+            address: user.address(),
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+        },
+        consents,
+        did,
+        credentials: ExportedCredentials {
+            password_hash: Some(user.password_hash.clone()).filter(|hash| include_secrets && !hash.is_empty()),
+            totp,
+            webauthn_rp_id: state.config.webauthn_rp_id.clone(),
+            webauthn,
+        },
+        sessions,
+    })
+}
+
+// Compact JWS signed with this instance's export key. Also used for the
+// records of a move to another instance (see `migration`).
+pub async fn sign_jws<T: Serialize>(state: &AppState, typ: &str, payload: &T) -> Result<String, AppError> {
+    let key_pair = signing_key(state).await?;
+
+    let header = json!({ "alg": "EdDSA", "typ": typ, "kid": key_id(key_pair.public_key().as_ref()) });
+    let payload = serde_json::to_vec(payload)
+        .map_err(|e| AppError::Internal(format!("JWS serialization failed: {}", e)))?;
+    let signing_input = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header.to_string()),
+        URL_SAFE_NO_PAD.encode(payload)
+    );
+    let signature = key_pair.sign(signing_input.as_bytes());
+
+    Ok(format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature.as_ref())))
+}
+
+// Checks a JWS from `sign_jws` against the keys of the instance named in
+// its `iss` claim
+pub async fn verify_jws<T: DeserializeOwned>(state: &AppState, jws: &str, typ: &str) -> Result<T, AppError> {
+    let invalid = || AppError::Validation("Invalid signature".to_string());
+
+    let mut parts = jws.split('.');
+    let (Some(header), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid());
+    };
+
+    let header: Value = serde_json::from_slice(&decode(header)?).map_err(|_| invalid())?;
+    if header["alg"] != "EdDSA" || header["typ"] != typ {
+        return Err(invalid());
+    }
+    let kid = header["kid"].as_str().ok_or_else(invalid)?;
+    let claims: Value = serde_json::from_slice(&decode(payload)?).map_err(|_| invalid())?;
+    let (Some(issuer), Some(iat)) = (claims["iss"].as_str(), claims["iat"].as_i64()) else {
+        return Err(invalid());
+    };
+
+    let public_key = issuer_key(state, issuer, kid).await?;
+    let signing_input = &jws[..jws.rfind('.').unwrap_or(0)];
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(signing_input.as_bytes(), &decode(signature)?)
+        .map_err(|_| invalid())?;
+
+    if iat > Utc::now().timestamp() + state.config.jwt_leeway_seconds as i64 {
+        return Err(AppError::Validation("Signed content is issued in the future".to_string()));
+    }
+
+    serde_json::from_value(claims).map_err(|e| AppError::Validation(format!("Invalid signed content: {}", e)))
+}
+
+// Our own key is used directly; trusted issuers publish theirs. Plain HTTP
+// is only used for localhost, as with did:web.
+async fn issuer_key(state: &AppState, issuer: &str, kid: &str) -> Result<Vec<u8>, AppError> {
+    if issuer == state.config.issuer {
+        let key_pair = signing_key(state).await?;
+        let public_key = key_pair.public_key().as_ref().to_vec();
+        if key_id(&public_key) != kid {
+            return Err(AppError::Validation("Unknown bundle signing key".to_string()));
+        }
+        return Ok(public_key);
+    }
+
+    let issuer = issuer.trim_end_matches('/');
+    if !state.config.trusted_import_issuers.iter().any(|trusted| trusted == issuer) {
+        return Err(AppError::Validation("The issuer is not trusted by this instance".to_string()));
+    }
+
+    let url = Url::parse(&format!("{}/.well-known/idryos-export-keys", issuer))
+        .map_err(|_| AppError::Validation("Invalid bundle issuer".to_string()))?;
+    let local = matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"));
+    if url.scheme() != "https" && !(url.scheme() == "http" && local) {
+        return Err(AppError::Validation("Bundle issuer must use https".to_string()));
+    }
+
+    let unavailable = || AppError::Validation("The signing keys of the issuer could not be fetched".to_string());
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(std::time::Duration::from_secs(10))
+        .build()
+        .map_err(|e| AppError::Internal(format!("HTTP client setup failed: {}", e)))?;
+    let keys: Value = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| {
+            warn!("Signing keys of {} could not be fetched: {}", issuer, e);
+            unavailable()
+        })?
+        .json()
+        .await
+        .map_err(|_| unavailable())?;
+
+    let key = keys["keys"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|key| key["kid"] == kid && key["kty"] == "OKP" && key["crv"] == "Ed25519")
+        .and_then(|key| key["x"].as_str())
+        .ok_or_else(|| AppError::Validation("Unknown bundle signing key".to_string()))?;
+    decode(key)
+}
+
+// Generated on first use and kept encrypted at rest
+async fn signing_key(state: &AppState) -> Result<Ed25519KeyPair, AppError> {
+    let stored: Option<(String,)> = sqlx::query_as("SELECT private_key_encrypted FROM export_signing_key WHERE id = 1")
+        .fetch_optional(state.database.pool())
+        .await?;
+
+    let sealed = match stored {
+        Some((sealed,)) => sealed,
+        None => {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                .map_err(|e| AppError::Internal(format!("Key generation failed: {:?}", e)))?;
+            // A concurrent first use may have stored its key in the meantime
+            sqlx::query(
+                "INSERT OR IGNORE INTO export_signing_key (id, private_key_encrypted, created_at) VALUES (1, ?, ?)"
+            )
+            .bind(encrypt_secret(&state.config, SIGNING_KEY_CONTEXT, pkcs8.as_ref())?)
+            .bind(Utc::now())
+            .execute(state.database.pool())
+            .await?;
+
+            let (sealed,): (String,) =
+                sqlx::query_as("SELECT private_key_encrypted FROM export_signing_key WHERE id = 1")
+                    .fetch_one(state.database.pool())
+                    .await?;
+            sealed
+        }
+    };
+    let pkcs8 = decrypt_secret(&state.config, SIGNING_KEY_CONTEXT, &sealed)?;
+    Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| AppError::Internal("Invalid export signing key".to_string()))
+}
+
+fn key_id(public_key: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(digest(&SHA256, public_key))
+}
+
+async fn encrypt(state: &AppState, jws: &str, passphrase: &str) -> Result<EncryptedExport, AppError> {
+    let salt: [u8; 16] = rand::thread_rng().gen();
+    let nonce: [u8; NONCE_LEN] = rand::thread_rng().gen();
+    let (memory_kib, iterations, parallelism) = (
+        state.config.argon2_memory_kib,
+        state.config.argon2_iterations,
+        state.config.argon2_parallelism,
+    );
+
+    let key = derive_key(state, passphrase, &salt, memory_kib, iterations, parallelism).await?;
+    let mut in_out = jws.as_bytes().to_vec();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(BUNDLE_AAD), &mut in_out)
+        .map_err(|_| AppError::Internal("Bundle encryption failed".to_string()))?;
+
+    Ok(EncryptedExport {
+        kdf: "argon2id".to_string(),
+        memory_kib,
+        iterations,
+        parallelism,
+        salt: URL_SAFE_NO_PAD.encode(salt),
+        nonce: URL_SAFE_NO_PAD.encode(nonce),
+        ciphertext: URL_SAFE_NO_PAD.encode(in_out),
+    })
+}
+
+async fn decrypt(state: &AppState, encrypted: &EncryptedExport, passphrase: &str) -> Result<String, AppError> {
+    if encrypted.kdf != "argon2id"
+        || encrypted.memory_kib > MAX_KDF_MEMORY_KIB
+        || encrypted.iterations > MAX_KDF_ITERATIONS
+        || encrypted.parallelism > MAX_KDF_PARALLELISM
+    {
+        return Err(AppError::Validation("Unsupported bundle encryption parameters".to_string()));
+    }
+
+    let key = derive_key(
+        state,
+        passphrase,
+        &decode(&encrypted.salt)?,
+        encrypted.memory_kib,
+        encrypted.iterations,
+        encrypted.parallelism,
+    )
+    .await?;
+    let nonce = Nonce::try_assume_unique_for_key(&decode(&encrypted.nonce)?)
+        .map_err(|_| AppError::Validation("Invalid bundle nonce".to_string()))?;
+
+    let mut in_out = decode(&encrypted.ciphertext)?;
+    let plaintext = key
+        .open_in_place(nonce, Aad::from(BUNDLE_AAD), &mut in_out)
+        .map_err(|_| AppError::Validation("Wrong passphrase or damaged bundle".to_string()))?;
+
+    String::from_utf8(plaintext.to_vec()).map_err(|_| AppError::Validation("Invalid bundle content".to_string()))
+}
+
+// Runs under the password hasher's concurrency limit
+async fn derive_key(
+    state: &AppState,
+    passphrase: &str,
+    salt: &[u8],
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+) -> Result<LessSafeKey, AppError> {
+    let params = Params::new(memory_kib, iterations, parallelism, Some(32))
+        .map_err(|e| AppError::Validation(format!("Invalid key derivation parameters: {}", e)))?;
+    let passphrase = passphrase.to_string();
+    let salt = salt.to_vec();
+
+    let key = state
+        .password_hasher
+        .run_blocking(move || {
+            let mut key = [0u8; 32];
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+                .map(|_| key)
+                .map_err(|e| AppError::Validation(format!("Key derivation failed: {}", e)))
+        })
+        .await?;
+
+    let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| AppError::Internal("Invalid bundle key".to_string()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, AppError> {
+    URL_SAFE_NO_PAD
+        .decode(value)
+        .map_err(|_| AppError::Validation("Invalid base64url in bundle".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Payload {
+        iss: String,
+        iat: i64,
+        value: String,
+    }
+
+    fn payload(iss: &str) -> Payload {
+        Payload { iss: iss.to_string(), iat: Utc::now().timestamp(), value: "hello".to_string() }
+    }
+
+    #[tokio::test]
+    async fn signed_content_verifies_and_keeps_its_type() {
+        let state = test_support::state().await;
+        let jws = sign_jws(&state, BUNDLE_TYP, &payload(&state.config.issuer)).await.unwrap();
+
+        let verified: Payload = verify_jws(&state, &jws, BUNDLE_TYP).await.unwrap();
+        assert_eq!(verified.value, "hello");
+        assert!(verify_jws::<Payload>(&state, &jws, "other+jws").await.is_err());
+
+        // The key is generated once and reused
+        let again = sign_jws(&state, BUNDLE_TYP, &payload(&state.config.issuer)).await.unwrap();
+        assert_eq!(jws.split('.').next(), again.split('.').next());
+    }
+
+    #[tokio::test]
+    async fn tampered_content_is_rejected() {
+        let state = test_support::state().await;
+        let jws = sign_jws(&state, BUNDLE_TYP, &payload(&state.config.issuer)).await.unwrap();
+
+        let mut parts: Vec<String> = jws.split('.').map(str::to_string).collect();
+        let mut forged = payload(&state.config.issuer);
+        forged.value = "forged".to_string();
+        parts[1] = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+        assert!(verify_jws::<Payload>(&state, &parts.join("."), BUNDLE_TYP).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn untrusted_issuers_are_not_contacted() {
+        let state = test_support::state().await;
+        let jws = sign_jws(&state, BUNDLE_TYP, &payload("https://attacker.example")).await.unwrap();
+
+        match verify_jws::<Payload>(&state, &jws, BUNDLE_TYP).await {
+            Err(AppError::Validation(message)) => assert!(message.contains("not trusted"), "{}", message),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn encrypted_bundles_need_the_passphrase() {
+        let state = test_support::state().await;
+        let encrypted = encrypt(&state, "header.payload.signature", "correct horse").await.unwrap();
+
+        assert_eq!(decrypt(&state, &encrypted, "correct horse").await.unwrap(), "header.payload.signature");
+        assert!(decrypt(&state, &encrypted, "wrong horse").await.is_err());
+
+        let costly = EncryptedExport { memory_kib: MAX_KDF_MEMORY_KIB + 1, ..encrypted };
+        assert!(decrypt(&state, &costly, "correct horse").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn exports_name_a_recorded_jti() {
+        let state = test_support::state().await;
+        let user = test_support::user(&state, "user-1").await;
+
+        let data = collect(&state, &user, false).await.unwrap();
+        let (owner,): (String,) = sqlx::query_as("SELECT user_id FROM account_exports WHERE jti = ?")
+            .bind(data.jti.as_deref())
+            .fetch_one(state.database.pool())
+            .await
+            .unwrap();
+        assert_eq!(owner, user.id);
+        assert_eq!(data.credentials.password_hash, None);
+    }
+
+    #[tokio::test]
+    async fn imports_cannot_claim_did_web_identifiers_of_this_instance() {
+        let state = test_support::state().await;
+        let user = test_support::user(&state, "user-1").await;
+        let mut data = collect(&state, &user, false).await.unwrap();
+        data.profile.username = "imported".to_string();
+        data.profile.email = "imported@example.com".to_string();
+        data.did = Some(ExportedDid { did: web_did(&state.config, &user.id), document: None, managed_key: None });
+
+        let user_id = Uuid::new_v4().to_string();
+        assert!(import_data(&state, &data, &user_id, false).await.is_err());
+
+        data.did = None;
+        data.profile.phone_number = Some("not a phone number".to_string());
+        assert!(import_data(&state, &data, &user_id, false).await.is_err());
+
+        data.profile.phone_number = Some("+14155552671".to_string());
+        let imported = import_data(&state, &data, &user_id, false).await.unwrap();
+        assert!(imported.profile_sealed);
+        assert!(imported.email_verified_at.is_none());
+    }
+}
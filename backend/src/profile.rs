@@ -0,0 +1,588 @@
+use axum::{
+    extract::{ConnectInfo, Json, State},
+    http::StatusCode,
+    response::Json as ResponseJson,
+};
+use chrono::{DateTime, Duration, Utc};
+use reqwest::Url;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::net::SocketAddr;
+use tracing::{info, warn};
+
+use crate::{
+    auth_user::FirstPartyUser,
+    config::Config,
+    crypto::{decrypt_user_secret, encrypt_user_secret, generate_token, hash_token},
+    database::Database,
+    error::AppError,
+    mailer::{self, Email},
+    models::{Address, User},
+    oauth::find_active_user,
+    reauth::{self, Reauthentication},
+    AppState,
+};
+
+// The signed-in user's own profile. Profile fields follow the OIDC
+// standard claims (OpenID Connect Core 1.0, section 5.1) and are released
+// to clients through the profile, phone and address scopes.
+const EMAIL_CHANGE_EXPIRY_HOURS: i64 = 24;
+
+const MAX_NAME_LENGTH: usize = 100;
+
+#[derive(Debug, Serialize)]
+pub struct ProfileResponse {
+    pub id: String,
+    pub username: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub pending_email: Option<String>, // Awaiting confirmation from the new address
+    pub did: Option<String>,
+    pub role: String,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub locale: Option<String>,
+    pub zoneinfo: Option<String>,
+    pub picture: Option<String>,
+    pub phone_number: Option<String>,
+    pub address: Option<Address>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// Fields left out are unchanged, null clears them
+#[derive(Debug, Deserialize)]
+pub struct ProfileUpdateRequest {
+    pub username: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub given_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub family_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub locale: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub zoneinfo: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub picture: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub phone_number: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub address: Option<Option<Address>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmailChangeRequest {
+    pub new_email: String,
+    #[serde(flatten)]
+    pub reauthentication: Reauthentication,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmEmailChangeRequest {
+    pub token: String,
+}
+
+// Tells a missing field apart from an explicit null
+pub fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+pub async fn get_profile(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+) -> Result<ResponseJson<ProfileResponse>, AppError> {
+    let profile = profile_response(&state, auth.user).await?;
+    Ok(ResponseJson(profile))
+}
+
+pub async fn update_profile(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Json(payload): Json<ProfileUpdateRequest>,
+) -> Result<ResponseJson<ProfileResponse>, AppError> {
+    let mut user = auth.user;
+    open_profile(&state.config, &mut user).await?;
+
+    if let Some(username) = payload.username {
+        let username = username.trim().to_string();
+        if username.is_empty() {
+            return Err(AppError::Validation("username cannot be empty".to_string()));
+        }
+        let (taken,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE username = ? AND id != ?")
+            .bind(&username)
+            .bind(&user.id)
+            .fetch_one(state.database.pool())
+            .await?;
+        if taken > 0 {
+            return Err(AppError::Validation("Username is already taken".to_string()));
+        }
+        user.username = username;
+    }
+    if let Some(given_name) = payload.given_name {
+        user.given_name = validate_name("given_name", given_name)?;
+    }
+    if let Some(family_name) = payload.family_name {
+        user.family_name = validate_name("family_name", family_name)?;
+    }
+    if let Some(locale) = payload.locale {
+        user.locale = validate_optional(locale, validate_locale)?;
+    }
+    if let Some(zoneinfo) = payload.zoneinfo {
+        user.zoneinfo = validate_optional(zoneinfo, validate_zoneinfo)?;
+    }
+    if let Some(picture) = payload.picture {
+        user.picture = validate_optional(picture, validate_picture)?;
+    }
+    if let Some(phone_number) = payload.phone_number {
+        user.phone_number = validate_optional(phone_number, validate_phone_number)?;
+    }
+    if let Some(address) = payload.address {
+        user.address = address
+            .filter(|address| *address != Address::default())
+            .map(|address| serde_json::to_string(&address))
+            .transpose()
+            .map_err(|e| AppError::Internal(format!("Address serialization failed: {}", e)))?;
+    }
+
+    let [given_name, family_name, phone_number, address] =
+        seal_profile(&state.config, &user.id, opened_fields(&user)).await?;
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users
+        SET username = ?, given_name = ?, family_name = ?, locale = ?, zoneinfo = ?,
+            picture = ?, phone_number = ?, address = ?, profile_sealed = 1, updated_at = ?
+        WHERE id = ?
+        RETURNING *
+        "#
+    )
+    .bind(&user.username)
+    .bind(given_name)
+    .bind(family_name)
+    .bind(&user.locale)
+    .bind(&user.zoneinfo)
+    .bind(&user.picture)
+    .bind(phone_number)
+    .bind(address)
+    .bind(Utc::now())
+    .bind(&user.id)
+    .fetch_one(state.database.pool())
+    .await?;
+
+    let profile = profile_response(&state, user).await?;
+    Ok(ResponseJson(profile))
+}
+
+// The account keeps its current address until the new one is confirmed.
+// The user confirms their identity first, and the current address is told
+// about the request, so a hijacked session cannot quietly move the account
+// elsewhere.
+pub async fn request_email_change(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Json(payload): Json<EmailChangeRequest>,
+) -> Result<StatusCode, AppError> {
+    reauth::confirm(&state, &auth, &payload.reauthentication, addr.ip()).await?;
+
+    let user = auth.user;
+    let new_email = payload.new_email.trim().to_string();
+
+    if !is_plausible_email(&new_email) {
+        return Err(AppError::Validation("Invalid email address".to_string()));
+    }
+    if new_email.eq_ignore_ascii_case(&user.email) {
+        return Err(AppError::Validation("This is already your email address".to_string()));
+    }
+    let (taken,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE LOWER(email) = LOWER(?)")
+        .bind(&new_email)
+        .fetch_one(state.database.pool())
+        .await?;
+    if taken > 0 {
+        return Err(AppError::Validation("Email is already in use".to_string()));
+    }
+    if mailer::is_throttled(&state, "email_change_tokens", &user.id).await? {
+        return Err(AppError::RateLimited("Too many email change requests".to_string()));
+    }
+
+    // Only the latest request can be confirmed. Earlier ones are marked
+    // used rather than deleted, so they still count towards the throttle.
+    let now = Utc::now();
+    sqlx::query("UPDATE email_change_tokens SET used_at = ? WHERE user_id = ? AND used_at IS NULL")
+        .bind(now)
+        .bind(&user.id)
+        .execute(state.database.pool())
+        .await?;
+
+    let token = generate_token();
+    let token_hash = hash_token(&token);
+    sqlx::query(
+        r#"
+        INSERT INTO email_change_tokens (token_hash, user_id, new_email, expires_at, created_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(&token_hash)
+    .bind(&user.id)
+    .bind(&new_email)
+    .bind(now + Duration::hours(EMAIL_CHANGE_EXPIRY_HOURS))
+    .bind(now)
+    .execute(state.database.pool())
+    .await?;
+
+    // A link that was never delivered must not stay confirmable
+    let link = format!("{}/confirm-email-change?token={}", state.config.frontend_url, token);
+    let sent = state
+        .mailer
+        .send(Email {
+            to: new_email.clone(),
+            subject: "Confirm your new email address".to_string(),
+            body: format!(
+                "Hello {},\n\nConfirm that you want to use this address for your account by opening this link:\n\n{}\n\n\
+                 The link expires in {} hours. If you did not ask for this, ignore this email.\n",
+                user.username, link, EMAIL_CHANGE_EXPIRY_HOURS
+            ),
+        })
+        .await;
+    if let Err(e) = sent {
+        sqlx::query("UPDATE email_change_tokens SET used_at = ? WHERE token_hash = ?")
+            .bind(now)
+            .bind(&token_hash)
+            .execute(state.database.pool())
+            .await?;
+        return Err(e);
+    }
+
+    let notified = state
+        .mailer
+        .send(Email {
+            to: user.email.clone(),
+            subject: "Your email address is being changed".to_string(),
+            body: format!(
+                "Hello {},\n\nSomeone asked to change the email address of your account to {}. \
+                 Nothing changes until the new address is confirmed.\n\n\
+                 If this was not you, change your password and sign out of your other sessions.\n",
+                user.username, new_email
+            ),
+        })
+        .await;
+    if let Err(e) = notified {
+        warn!("Email change notice for user {} failed: {}", user.id, e);
+    }
+
+    info!("Email change requested for user {}", user.id);
+    Ok(StatusCode::ACCEPTED)
+}
+
+// Following the link proves control of the new address, so it is verified
+// right away. Links already mailed to the old address stop working.
+pub async fn confirm_email_change(
+    State(state): State<AppState>,
+    Json(payload): Json<ConfirmEmailChangeRequest>,
+) -> Result<ResponseJson<ProfileResponse>, AppError> {
+    let now = Utc::now();
+
+    let (user_id, new_email): (String, String) = sqlx::query_as(
+        r#"
+        UPDATE email_change_tokens SET used_at = ?
+        WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?
+        RETURNING user_id, new_email
+        "#
+    )
+    .bind(now)
+    .bind(hash_token(&payload.token))
+    .bind(now)
+    .fetch_optional(state.database.pool())
+    .await?
+    .ok_or_else(|| AppError::Validation("Invalid or expired email change token".to_string()))?;
+
+    let user = find_active_user(&state, &user_id).await?;
+
+    let (taken,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE LOWER(email) = LOWER(?) AND id != ?")
+        .bind(&new_email)
+        .bind(&user.id)
+        .fetch_one(state.database.pool())
+        .await?;
+    if taken > 0 {
+        return Err(AppError::Validation("Email is already in use".to_string()));
+    }
+
+    let user = sqlx::query_as::<_, User>(
+        "UPDATE users SET email = ?, email_verified_at = ?, updated_at = ? WHERE id = ? RETURNING *"
+    )
+    .bind(&new_email)
+    .bind(now)
+    .bind(now)
+    .bind(&user.id)
+    .fetch_one(state.database.pool())
+    .await?;
+
+    for table in ["email_verification_tokens", "password_reset_tokens", "magic_link_tokens", "account_unlock_tokens"] {
+        sqlx::query(&format!("DELETE FROM {} WHERE user_id = ?", table))
+            .bind(&user.id)
+            .execute(state.database.pool())
+            .await?;
+    }
+
+    info!("Email of user {} changed", user.id);
+    let profile = profile_response(&state, user).await?;
+    Ok(ResponseJson(profile))
+}
+
+async fn profile_response(state: &AppState, mut user: User) -> Result<ProfileResponse, AppError> {
+    open_profile(&state.config, &mut user).await?;
+
+    let pending_email: Option<(String,)> = sqlx::query_as(
+        "SELECT new_email FROM email_change_tokens WHERE user_id = ? AND used_at IS NULL AND expires_at > ?"
+    )
+    .bind(&user.id)
+    .bind(Utc::now())
+    .fetch_optional(state.database.pool())
+    .await?;
+
+    Ok(ProfileResponse {
+        address: user.address(),
+        pending_email: pending_email.map(|(email,)| email),
+        id: user.id,
+        username: user.username,
+        email: user.email,
+        email_verified: user.email_verified_at.is_some(),
+        did: user.did,
+        role: user.role,
+        given_name: user.given_name,
+        family_name: user.family_name,
+        locale: user.locale,
+        zoneinfo: user.zoneinfo,
+        picture: user.picture,
+        phone_number: user.phone_number,
+        created_at: user.created_at,
+        updated_at: user.updated_at,
+    })
+}
+
+// Names, phone number and address are sealed with the user's key like their
+// secrets, so erasing the account shreds them in backups too.
+// `profile_sealed` tells whether a User holds them sealed: rows written
+// before this are sealed at startup, and opening a User clears the flag.
+const SEALED_FIELDS: [&str; 4] = ["given_name", "family_name", "phone_number", "address"];
+
+pub async fn open_profile(config: &Config, user: &mut User) -> Result<(), AppError> {
+    if !user.profile_sealed {
+        return Ok(());
+    }
+
+    let user_id = user.id.clone();
+    let fields = [&mut user.given_name, &mut user.family_name, &mut user.phone_number, &mut user.address];
+    for (name, field) in SEALED_FIELDS.into_iter().zip(fields) {
+        if let Some(sealed) = field.take() {
+            let plaintext = decrypt_user_secret(config, &user_id, &field_context(name, &user_id), &sealed).await?;
+            *field = Some(
+                String::from_utf8(plaintext).map_err(|_| AppError::Internal(format!("Invalid sealed {}", name)))?,
+            );
+        }
+    }
+    user.profile_sealed = false;
+    Ok(())
+}
+
+// Plaintext fields in the order of SEALED_FIELDS, sealed in that order
+pub async fn seal_profile(
+    config: &Config,
+    user_id: &str,
+    fields: [Option<&str>; 4],
+) -> Result<[Option<String>; 4], AppError> {
+    let mut sealed: [Option<String>; 4] = Default::default();
+    for ((name, field), sealed) in SEALED_FIELDS.into_iter().zip(fields).zip(&mut sealed) {
+        if let Some(value) = field {
+            *sealed = Some(encrypt_user_secret(config, user_id, &field_context(name, user_id), value.as_bytes()).await?);
+        }
+    }
+    Ok(sealed)
+}
+
+fn opened_fields(user: &User) -> [Option<&str>; 4] {
+    [
+        user.given_name.as_deref(),
+        user.family_name.as_deref(),
+        user.phone_number.as_deref(),
+        user.address.as_deref(),
+    ]
+}
+
+// Run at startup, before requests are served
+pub async fn seal_existing_profiles(database: &Database, config: &Config) -> Result<(), AppError> {
+    let users = sqlx::query_as::<_, User>(
+        r#"
+        SELECT * FROM users
+        WHERE profile_sealed = 0
+          AND (given_name IS NOT NULL OR family_name IS NOT NULL OR phone_number IS NOT NULL OR address IS NOT NULL)
+        "#
+    )
+    .fetch_all(database.pool())
+    .await?;
+
+    for user in &users {
+        let [given_name, family_name, phone_number, address] = seal_profile(config, &user.id, opened_fields(user)).await?;
+        sqlx::query(
+            r#"
+            UPDATE users SET given_name = ?, family_name = ?, phone_number = ?, address = ?, profile_sealed = 1
+            WHERE id = ? AND profile_sealed = 0
+            "#
+        )
+        .bind(given_name)
+        .bind(family_name)
+        .bind(phone_number)
+        .bind(address)
+        .bind(&user.id)
+        .execute(database.pool())
+        .await?;
+    }
+    if !users.is_empty() {
+        info!("Sealed the profiles of {} users under their user keys", users.len());
+    }
+    Ok(())
+}
+
+fn field_context(field: &str, user_id: &str) -> String {
+    format!("profile:{}:{}", field, user_id)
+}
+
+// Blank values clear the field
+pub fn validate_optional(
+    value: Option<String>,
+    validate: fn(&str) -> Result<(), AppError>,
+) -> Result<Option<String>, AppError> {
+    match value.map(|value| value.trim().to_string()).filter(|value| !value.is_empty()) {
+        Some(value) => {
+            validate(&value)?;
+            Ok(Some(value))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn validate_name(field: &str, value: Option<String>) -> Result<Option<String>, AppError> {
+    let value = value.map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+    if value.as_ref().is_some_and(|value| value.chars().count() > MAX_NAME_LENGTH) {
+        return Err(AppError::Validation(format!("{} is too long", field)));
+    }
+    Ok(value)
+}
+
+// BCP 47 language tag, e.g. "en-US"
+pub fn validate_locale(locale: &str) -> Result<(), AppError> {
+    let mut subtags = locale.split('-');
+    let language = subtags.next().unwrap_or_default();
+    let valid = (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|subtag| (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric()));
+
+    if !valid {
+        return Err(AppError::Validation("locale must be a BCP 47 language tag".to_string()));
+    }
+    Ok(())
+}
+
+// IANA time zone name, e.g. "Europe/Paris"
+pub fn validate_zoneinfo(zoneinfo: &str) -> Result<(), AppError> {
+    let valid = zoneinfo.len() <= 64
+        && (zoneinfo == "UTC" || zoneinfo.contains('/'))
+        && !zoneinfo.starts_with('/')
+        && !zoneinfo.ends_with('/')
+        && zoneinfo
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '_' | '-' | '+'));
+
+    if !valid {
+        return Err(AppError::Validation("zoneinfo must be an IANA time zone name".to_string()));
+    }
+    Ok(())
+}
+
+pub fn validate_picture(picture: &str) -> Result<(), AppError> {
+    let valid = Url::parse(picture).is_ok_and(|url| matches!(url.scheme(), "https" | "http"));
+    if !valid {
+        return Err(AppError::Validation("picture must be an http(s) URL".to_string()));
+    }
+    Ok(())
+}
+
+// E.164, e.g. "+14155552671"
+pub fn validate_phone_number(phone_number: &str) -> Result<(), AppError> {
+    let valid = phone_number
+        .strip_prefix('+')
+        .is_some_and(|digits| (7..=15).contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_digit()));
+
+    if !valid {
+        return Err(AppError::Validation("phone_number must be in E.164 format".to_string()));
+    }
+    Ok(())
+}
+
+pub fn is_plausible_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.chars().any(|c| c.is_whitespace())
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locales_are_bcp_47_tags() {
+        for locale in ["en", "fr-FR", "zh-Hant-TW", "es-419", "de-CH-1996"] {
+            assert!(validate_locale(locale).is_ok(), "{}", locale);
+        }
+        for locale in ["", "e", "english", "en_US", "en-", "fr-FR-toolongsubtag", "12"] {
+            assert!(validate_locale(locale).is_err(), "{}", locale);
+        }
+    }
+
+    #[test]
+    fn other_claims_are_checked() {
+        assert!(validate_zoneinfo("Europe/Paris").is_ok());
+        assert!(validate_zoneinfo("UTC").is_ok());
+        assert!(validate_zoneinfo("Paris").is_err());
+        assert!(validate_zoneinfo("/etc/passwd").is_err());
+        assert!(validate_phone_number("+14155552671").is_ok());
+        assert!(validate_phone_number("14155552671").is_err());
+        assert!(validate_phone_number("+1 415 555 2671").is_err());
+        assert!(validate_picture("https://cdn.example.com/me.png").is_ok());
+        assert!(validate_picture("javascript:alert(1)").is_err());
+        assert!(is_plausible_email("user@example.com"));
+        assert!(!is_plausible_email("user@localhost"));
+        assert!(!is_plausible_email("us er@example.com"));
+        assert_eq!(validate_optional(Some("  ".to_string()), validate_locale).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn sealed_profiles_open_to_the_original_fields() {
+        let state = crate::test_support::state().await;
+        let mut user = crate::test_support::user(&state, "user-1").await;
+        user.given_name = Some("Ada".to_string());
+        user.phone_number = Some("+14155552671".to_string());
+        user.address = Some(r#"{"country":"FR"}"#.to_string());
+        let opened = user.clone();
+
+        let [given_name, family_name, phone_number, address] =
+            seal_profile(&state.config, &user.id, opened_fields(&user)).await.unwrap();
+        assert_ne!(given_name.as_deref(), Some("Ada"));
+        assert_eq!(family_name, None);
+        (user.given_name, user.family_name, user.phone_number, user.address) = (given_name, family_name, phone_number, address);
+        user.profile_sealed = true;
+
+        open_profile(&state.config, &mut user).await.unwrap();
+        assert!(!user.profile_sealed);
+        assert_eq!(opened_fields(&user), opened_fields(&opened));
+    }
+}
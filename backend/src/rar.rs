@@ -0,0 +1,234 @@
+use serde_json::{Map, Value};
+
+use crate::{error::AppError, models::OAuthClient};
+
+// Rich Authorization Requests (RFC 9396). Clients register one JSON schema
+// per authorization details type in `oauth_clients.authorization_details_types`,
+// e.g. {"payment_initiation": {"type": "object", "required": ["amount"], ...}}.
+
+// Parse and validate the `authorization_details` parameter, returning the
+// normalized JSON string to store alongside the grant
+pub fn parse_authorization_details(
+    client: &OAuthClient,
+    raw: Option<&str>,
+) -> Result<Option<String>, AppError> {
+    let Some(raw) = raw.filter(|raw| !raw.trim().is_empty()) else {
+        return Ok(None);
+    };
+
+    let details: Vec<Value> = serde_json::from_str(raw)
+        .map_err(|_| AppError::Validation("invalid_authorization_details: not a JSON array".to_string()))?;
+
+    let schemas = registered_types(client)?;
+    for detail in &details {
+        let detail_type = detail
+            .get("type")
+            .and_then(Value::as_str)
+            .ok_or_else(|| AppError::Validation("invalid_authorization_details: missing type".to_string()))?;
+
+        let schema = schemas.get(detail_type).ok_or_else(|| {
+            AppError::Validation(format!("invalid_authorization_details: unknown type {}", detail_type))
+        })?;
+
+        validate(schema, detail, detail_type)
+            .map_err(|e| AppError::Validation(format!("invalid_authorization_details: {}", e)))?;
+    }
+
+    if details.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(Value::Array(details).to_string()))
+}
+
+// At the token endpoint a client may ask for a subset of what was granted
+// (RFC 9396, section 6.1)
+pub fn narrow_authorization_details(
+    granted: Option<&str>,
+    requested: Option<&str>,
+) -> Result<Option<String>, AppError> {
+    let Some(requested) = requested.filter(|raw| !raw.trim().is_empty()) else {
+        return Ok(granted.map(str::to_string));
+    };
+
+    let requested: Vec<Value> = serde_json::from_str(requested)
+        .map_err(|_| AppError::Validation("invalid_authorization_details: not a JSON array".to_string()))?;
+    let granted: Vec<Value> = match granted {
+        Some(granted) => serde_json::from_str(granted)
+            .map_err(|_| AppError::Internal("Invalid stored authorization details".to_string()))?,
+        None => Vec::new(),
+    };
+
+    if !requested.iter().all(|detail| granted.contains(detail)) {
+        return Err(AppError::Validation("invalid_authorization_details: not granted".to_string()));
+    }
+
+    Ok((!requested.is_empty()).then(|| Value::Array(requested).to_string()))
+}
+
+pub fn to_value(details: Option<&str>) -> Option<Value> {
+    details.and_then(|details| serde_json::from_str(details).ok())
+}
+
+fn registered_types(client: &OAuthClient) -> Result<Map<String, Value>, AppError> {
+    match client.authorization_details_types.as_deref() {
+        Some(raw) => serde_json::from_str(raw)
+            .map_err(|_| AppError::Internal("Invalid authorization details types format".to_string())),
+        None => Ok(Map::new()),
+    }
+}
+
+// Validates a value against the subset of JSON Schema that authorization
+// details types need: type, enum, const, required, properties,
+// additionalProperties, items, min/max for numbers, lengths and item counts
+fn validate(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let Some(schema) = schema.as_object() else {
+        return Ok(());
+    };
+
+    if let Some(expected) = schema.get("type").and_then(Value::as_str) {
+        let matches = match expected {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => true,
+        };
+        if !matches {
+            return Err(format!("{} must be of type {}", path, expected));
+        }
+    }
+
+    if let Some(constant) = schema.get("const") {
+        if constant != value {
+            return Err(format!("{} must be {}", path, constant));
+        }
+    }
+
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            return Err(format!("{} is not an allowed value", path));
+        }
+    }
+
+    if let Some(number) = value.as_f64() {
+        if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+            if number < minimum {
+                return Err(format!("{} must be at least {}", path, minimum));
+            }
+        }
+        if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64) {
+            if number > maximum {
+                return Err(format!("{} must be at most {}", path, maximum));
+            }
+        }
+    }
+
+    if let Some(text) = value.as_str() {
+        let length = text.chars().count() as u64;
+        if schema.get("minLength").and_then(Value::as_u64).is_some_and(|min| length < min) {
+            return Err(format!("{} is too short", path));
+        }
+        if schema.get("maxLength").and_then(Value::as_u64).is_some_and(|max| length > max) {
+            return Err(format!("{} is too long", path));
+        }
+    }
+
+    if let Some(items) = value.as_array() {
+        let count = items.len() as u64;
+        if schema.get("minItems").and_then(Value::as_u64).is_some_and(|min| count < min) {
+            return Err(format!("{} has too few items", path));
+        }
+        if schema.get("maxItems").and_then(Value::as_u64).is_some_and(|max| count > max) {
+            return Err(format!("{} has too many items", path));
+        }
+        if let Some(item_schema) = schema.get("items") {
+            for (index, item) in items.iter().enumerate() {
+                validate(item_schema, item, &format!("{}[{}]", path, index))?;
+            }
+        }
+    }
+
+    if let Some(object) = value.as_object() {
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for field in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(field) {
+                    return Err(format!("{}.{} is required", path, field));
+                }
+            }
+        }
+
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (field, field_value) in object {
+            match properties.and_then(|properties| properties.get(field)) {
+                Some(field_schema) => validate(field_schema, field_value, &format!("{}.{}", path, field))?,
+                // `type` is always allowed, it selects the schema itself
+                None if field == "type" => {}
+                None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                    return Err(format!("{}.{} is not allowed", path, field));
+                }
+                None => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn payment_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["amount", "currency"],
+            "additionalProperties": false,
+            "properties": {
+                "amount": { "type": "number", "minimum": 0.01, "maximum": 10000 },
+                "currency": { "type": "string", "enum": ["EUR", "USD"] },
+                "reference": { "type": "string", "maxLength": 8 },
+                "locations": { "type": "array", "maxItems": 2, "items": { "type": "string" } }
+            }
+        })
+    }
+
+    #[test]
+    fn accepts_details_matching_the_schema() {
+        let detail = json!({ "type": "payment", "amount": 12.5, "currency": "EUR", "locations": ["a", "b"] });
+        assert_eq!(validate(&payment_schema(), &detail, "payment"), Ok(()));
+    }
+
+    #[test]
+    fn reports_the_failing_path() {
+        for (detail, error) in [
+            (json!({ "currency": "EUR" }), "payment.amount is required"),
+            (json!({ "amount": "12", "currency": "EUR" }), "payment.amount must be of type number"),
+            (json!({ "amount": 0, "currency": "EUR" }), "payment.amount must be at least 0.01"),
+            (json!({ "amount": 1, "currency": "GBP" }), "payment.currency is not an allowed value"),
+            (json!({ "amount": 1, "currency": "EUR", "reference": "too long!" }), "payment.reference is too long"),
+            (json!({ "amount": 1, "currency": "EUR", "locations": ["a", 1] }), "payment.locations[1] must be of type string"),
+            (json!({ "amount": 1, "currency": "EUR", "locations": ["a", "b", "c"] }), "payment.locations has too many items"),
+            (json!({ "amount": 1, "currency": "EUR", "payee": "x" }), "payment.payee is not allowed"),
+        ] {
+            assert_eq!(validate(&payment_schema(), &detail, "payment"), Err(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn token_requests_narrow_to_granted_details() {
+        let granted = r#"[{"type":"a","x":1},{"type":"b"}]"#;
+
+        assert_eq!(narrow_authorization_details(Some(granted), None).unwrap().as_deref(), Some(granted));
+        assert_eq!(
+            narrow_authorization_details(Some(granted), Some(r#"[{"type":"b"}]"#)).unwrap().as_deref(),
+            Some(r#"[{"type":"b"}]"#)
+        );
+        assert!(narrow_authorization_details(Some(granted), Some(r#"[{"type":"a","x":2}]"#)).is_err());
+        assert!(narrow_authorization_details(None, Some(r#"[{"type":"a"}]"#)).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use std::net::IpAddr;
+
+use crate::{auth_user::AuthUser, error::AppError, lockout::LoginAttempt, mfa, AppState};
+
+// Accounts with neither a password stored here nor TOTP (OPAQUE, passkey or
+// DID sign-in) confirm by having signed in this recently
+const FRESH_SIGN_IN_MINUTES: i64 = 5;
+
+// Proof asked for before sensitive account changes, on top of the session:
+// a session alone may have been taken over
+#[derive(Debug, Default, Deserialize)]
+pub struct Reauthentication {
+    pub current_password: Option<String>,
+    pub totp_code: Option<String>,
+}
+
+// Guesses count against the same limits as logins
+pub async fn confirm(
+    state: &AppState,
+    auth: &AuthUser,
+    proof: &Reauthentication,
+    ip: IpAddr,
+) -> Result<(), AppError> {
+    let user = &auth.user;
+    let password = proof.current_password.as_deref().filter(|_| !user.password_hash.is_empty());
+    let totp_code = proof.totp_code.as_deref();
+
+    if password.is_none() && totp_code.is_none() {
+        let no_other_proof = user.password_hash.is_empty() && !mfa::totp_enabled(state, &user.id).await?;
+        if no_other_proof && signed_in_recently(state, auth).await? {
+            return Ok(());
+        }
+        return Err(AppError::Authentication("Confirm with your current password or a TOTP code".to_string()));
+    }
+
+    let mut attempt = LoginAttempt::new(&user.email, ip);
+    attempt.begin(state).await?;
+
+    let verified = match (password, totp_code) {
+        (Some(password), _) => state.password_hasher.verify(password, &user.password_hash).await?.valid,
+        (None, Some(code)) => mfa::verify_totp(state, &user.id, code).await?,
+        (None, None) => false,
+    };
+    if !verified {
+        attempt.record_failure(state, Some(user)).await?;
+        return Err(AppError::Authentication("Invalid credentials".to_string()));
+    }
+    attempt.record_success(state).await
+}
+
+async fn signed_in_recently(state: &AppState, auth: &AuthUser) -> Result<bool, AppError> {
+    let Some(session_id) = &auth.session_id else {
+        return Ok(false);
+    };
+
+    let (recent,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(*) FROM sessions WHERE id = ? AND user_id = ? AND revoked_at IS NULL AND created_at > ?"
+    )
+    .bind(session_id)
+    .bind(&auth.user.id)
+    .bind(Utc::now() - Duration::minutes(FRESH_SIGN_IN_MINUTES))
+    .fetch_one(state.database.pool())
+    .await?;
+    Ok(recent > 0)
+}
@@ -0,0 +1,44 @@
+use serde::Serialize;
+
+// Every account has one role, stored on `users.role`. Roles grant a fixed
+// set of permissions; admin API handlers check permissions, not roles.
+pub const ROLE_USER: &str = "user";
+pub const ROLE_SUPPORT: &str = "support";
+pub const ROLE_ADMIN: &str = "admin";
+
+pub const ROLES: &[&str] = &[ROLE_USER, ROLE_SUPPORT, ROLE_ADMIN];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    ViewUsers,
+    ManageUsers, // Disable and enable accounts
+    ManageRoles,
+    ViewSessions,
+    RevokeSessions,
+    ManageClients,
+}
+
+pub fn permissions(role: &str) -> &'static [Permission] {
+    match role {
+        ROLE_ADMIN => &[
+            Permission::ViewUsers,
+            Permission::ManageUsers,
+            Permission::ManageRoles,
+            Permission::ViewSessions,
+            Permission::RevokeSessions,
+            Permission::ManageClients,
+        ],
+        // Helps users with their accounts without touching configuration
+        ROLE_SUPPORT => &[
+            Permission::ViewUsers,
+            Permission::ViewSessions,
+            Permission::RevokeSessions,
+        ],
+        _ => &[],
+    }
+}
+
+pub fn is_known_role(role: &str) -> bool {
+    ROLES.contains(&role)
+}
@@ -0,0 +1,264 @@
+use axum::{
+    extract::{Path, State},
+    http::{header::USER_AGENT, HeaderMap},
+    response::Json as ResponseJson,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::{
+    auth_user::FirstPartyUser,
+    crypto::{generate_token, hash_token},
+    error::AppError,
+    models::Session,
+    AppState,
+};
+
+// Client details recorded with a session so users can recognize it
+#[derive(Debug, Clone, Default)]
+pub struct SessionMetadata {
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+impl SessionMetadata {
+    pub fn from_request(headers: &HeaderMap, addr: SocketAddr, device_name: Option<String>) -> Self {
+        Self {
+            device_name: device_name.filter(|name| !name.trim().is_empty()),
+            user_agent: headers
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            ip_address: Some(addr.ip().to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    pub id: String,
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub current: bool, // The session the request was made with
+}
+
+// Start a session, returning it with its first refresh token
+pub async fn create_session(
+    state: &AppState,
+    user_id: &str,
+    metadata: &SessionMetadata,
+) -> Result<(Session, String), AppError> {
+    let lifetimes = state.config.default_token_lifetimes();
+    let refresh_token = generate_token();
+    let now = Utc::now();
+    let absolute_expires_at = now + lifetimes.refresh_absolute;
+
+    let session = Session {
+        id: Uuid::new_v4().to_string(),
+        user_id: user_id.to_string(),
+        refresh_token_hash: hash_token(&refresh_token),
+        previous_token_hash: None,
+        device_name: metadata.device_name.clone(),
+        user_agent: metadata.user_agent.clone(),
+        ip_address: metadata.ip_address.clone(),
+        created_at: now,
+        last_used_at: now,
+        expires_at: (now + lifetimes.refresh_idle).min(absolute_expires_at),
+        absolute_expires_at,
+        revoked_at: None,
+    };
+
+    sqlx::query(
+        r#"
+        INSERT INTO sessions (id, user_id, refresh_token_hash, device_name, user_agent, ip_address,
+                              created_at, last_used_at, expires_at, absolute_expires_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(&session.id)
+    .bind(&session.user_id)
+    .bind(&session.refresh_token_hash)
+    .bind(&session.device_name)
+    .bind(&session.user_agent)
+    .bind(&session.ip_address)
+    .bind(session.created_at)
+    .bind(session.last_used_at)
+    .bind(session.expires_at)
+    .bind(session.absolute_expires_at)
+    .execute(state.database.pool())
+    .await?;
+
+    Ok((session, refresh_token))
+}
+
+// Exchange a refresh token for a new one. Every token is single use: a
+// rotated-out token showing up again means it leaked, so the whole session
+// is revoked.
+pub async fn rotate_session(
+    state: &AppState,
+    refresh_token: &str,
+    metadata: &SessionMetadata,
+) -> Result<(Session, String), AppError> {
+    let token_hash = hash_token(refresh_token);
+
+    let session = sqlx::query_as::<_, Session>("SELECT * FROM sessions WHERE refresh_token_hash = ?")
+        .bind(&token_hash)
+        .fetch_optional(state.database.pool())
+        .await?;
+
+    let Some(session) = session else {
+        let reused = sqlx::query_as::<_, Session>("SELECT * FROM sessions WHERE previous_token_hash = ?")
+            .bind(&token_hash)
+            .fetch_optional(state.database.pool())
+            .await?;
+        if let Some(session) = reused {
+            revoke_session(state, &session.user_id, &session.id).await?;
+        }
+        return Err(AppError::Authentication("Invalid refresh token".to_string()));
+    };
+
+    let now = Utc::now();
+    if session.revoked_at.is_some() || session.expires_at < now || session.absolute_expires_at < now {
+        return Err(AppError::Authentication("Session expired".to_string()));
+    }
+
+    let lifetimes = state.config.default_token_lifetimes();
+    let new_token = generate_token();
+    let session = Session {
+        refresh_token_hash: hash_token(&new_token),
+        previous_token_hash: Some(token_hash),
+        user_agent: metadata.user_agent.clone().or(session.user_agent),
+        ip_address: metadata.ip_address.clone().or(session.ip_address),
+        last_used_at: now,
+        expires_at: (now + lifetimes.refresh_idle).min(session.absolute_expires_at),
+        ..session
+    };
+
+    // Only one of two concurrent refreshes with the same token can swap it;
+    // the loser presented a token that is already rotated out
+    let result = sqlx::query(
+        r#"
+        UPDATE sessions
+        SET refresh_token_hash = ?, previous_token_hash = ?, user_agent = ?, ip_address = ?,
+            last_used_at = ?, expires_at = ?
+        WHERE id = ? AND refresh_token_hash = ? AND revoked_at IS NULL
+        "#
+    )
+    .bind(&session.refresh_token_hash)
+    .bind(&session.previous_token_hash)
+    .bind(&session.user_agent)
+    .bind(&session.ip_address)
+    .bind(session.last_used_at)
+    .bind(session.expires_at)
+    .bind(&session.id)
+    .bind(&session.previous_token_hash)
+    .execute(state.database.pool())
+    .await?;
+
+    if result.rows_affected() == 0 {
+        revoke_session(state, &session.user_id, &session.id).await?;
+        return Err(AppError::Authentication("Invalid refresh token".to_string()));
+    }
+
+    Ok((session, new_token))
+}
+
+pub async fn revoke_session(state: &AppState, user_id: &str, session_id: &str) -> Result<bool, AppError> {
+    let result = sqlx::query(
+        "UPDATE sessions SET revoked_at = ? WHERE id = ? AND user_id = ? AND revoked_at IS NULL"
+    )
+    .bind(Utc::now())
+    .bind(session_id)
+    .bind(user_id)
+    .execute(state.database.pool())
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn revoke_all_sessions(state: &AppState, user_id: &str) -> Result<u64, AppError> {
+    let result = sqlx::query("UPDATE sessions SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL")
+        .bind(Utc::now())
+        .bind(user_id)
+        .execute(state.database.pool())
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+// Sessions that can still be refreshed, most recently used first
+pub async fn active_sessions(state: &AppState, user_id: &str) -> Result<Vec<Session>, AppError> {
+    let now = Utc::now();
+
+    let sessions = sqlx::query_as::<_, Session>(
+        "SELECT * FROM sessions WHERE user_id = ? AND revoked_at IS NULL ORDER BY last_used_at DESC"
+    )
+    .bind(user_id)
+    .fetch_all(state.database.pool())
+    .await?
+    .into_iter()
+    .filter(|session| session.expires_at > now && session.absolute_expires_at > now)
+    .collect();
+
+    Ok(sessions)
+}
+
+impl SessionResponse {
+    pub fn new(session: Session, current: bool) -> Self {
+        SessionResponse {
+            id: session.id,
+            device_name: session.device_name,
+            user_agent: session.user_agent,
+            ip_address: session.ip_address,
+            created_at: session.created_at,
+            last_used_at: session.last_used_at,
+            expires_at: session.expires_at,
+            current,
+        }
+    }
+}
+
+pub async fn list_sessions(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+) -> Result<ResponseJson<Vec<SessionResponse>>, AppError> {
+    let sessions = active_sessions(&state, &auth.user.id)
+        .await?
+        .into_iter()
+        .map(|session| {
+            let current = auth.session_id.as_deref() == Some(session.id.as_str());
+            SessionResponse::new(session, current)
+        })
+        .collect();
+
+    Ok(ResponseJson(sessions))
+}
+
+pub async fn delete_session(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+) -> Result<ResponseJson<serde_json::Value>, AppError> {
+    if !revoke_session(&state, &auth.user.id, &id).await? {
+        return Err(AppError::NotFound("Session not found".to_string()));
+    }
+
+    Ok(ResponseJson(serde_json::json!({ "revoked": 1 })))
+}
+
+// Sign out everywhere, including the current session
+pub async fn delete_all_sessions(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+) -> Result<ResponseJson<serde_json::Value>, AppError> {
+    let revoked = revoke_all_sessions(&state, &auth.user.id).await?;
+
+    Ok(ResponseJson(serde_json::json!({ "revoked": revoked })))
+}
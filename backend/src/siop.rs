@@ -0,0 +1,400 @@
+use axum::{
+    extract::{ConnectInfo, Json, State},
+    http::HeaderMap,
+    response::Json as ResponseJson,
+};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{Duration, Utc};
+use reqwest::Url;
+use ring::signature::Ed25519KeyPair;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::{
+    auth::finish_first_factor,
+    auth_user::FirstPartyUser,
+    clients::is_loopback,
+    crypto::{decrypt_user_secret, generate_token},
+    did::{authentication_method, managed_key_context, resolve, verify_signature},
+    error::AppError,
+    lockout::LoginAttempt,
+    models::{LoginOutcome, User, UserResponse},
+    oauth::find_active_user,
+    sessions::SessionMetadata,
+    AppState,
+};
+
+// Self-Issued OpenID Provider v2
+// (https://openid.net/specs/openid-connect-self-issued-v2-1_0.html).
+//
+// As a relying party Idryos accepts ID tokens that a wallet signs with the
+// key of a did:key or did:jwk subject, to sign in or to link that DID. As a
+// self-issued provider it signs such tokens for users whose DID keys it
+// manages (DIDs created through /did/create).
+const SELF_ISSUED_ISSUER: &str = "https://self-issued.me/v2";
+const SUBJECT_SYNTAX_TYPES: &[&str] = &["did:key", "did:jwk"];
+const SIGNING_ALGS: &[&str] = &["EdDSA", "ES256"];
+const REQUEST_EXPIRY_SECONDS: i64 = 300;
+
+const PURPOSE_LOGIN: &str = "login";
+const PURPOSE_LINK: &str = "link";
+
+#[derive(Debug, Serialize)]
+pub struct SiopRequestResponse {
+    pub request_id: String,
+    pub authorization_request: String, // openid:// URL for the wallet
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SiopResponseRequest {
+    pub request_id: String, // Comes back from the wallet as `state`
+    pub id_token: String,
+    pub device_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SelfIssuedAuthorizeRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: Option<String>,
+    pub nonce: Option<String>,
+    pub state: Option<String>,
+}
+
+pub async fn start_login(
+    State(state): State<AppState>,
+) -> Result<ResponseJson<SiopRequestResponse>, AppError> {
+    let response = create_request(&state, PURPOSE_LOGIN, None).await?;
+    Ok(ResponseJson(response))
+}
+
+pub async fn finish_login(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<SiopResponseRequest>,
+) -> Result<ResponseJson<LoginOutcome>, AppError> {
+    let (nonce, _) = take_request(&state, &payload.request_id, PURPOSE_LOGIN).await?;
+
+    // Counted against the subject the token claims, as for DID proofs
+    let claimed = claimed_subject(&payload.id_token).unwrap_or_default();
+    let mut attempt = LoginAttempt::new(&claimed, addr.ip());
+    attempt.begin(&state).await?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE did = ?")
+        .bind(&claimed)
+        .fetch_optional(state.database.pool())
+        .await?;
+
+    if let Err(e) = verify_id_token(&state, &payload.id_token, &nonce).await {
+        attempt.record_failure(&state, user.as_ref()).await?;
+        return Err(e);
+    }
+    let user = user.ok_or_else(|| AppError::Authentication("No account is linked to this DID".to_string()))?;
+    attempt.record_success(&state).await?;
+
+    let metadata = SessionMetadata::from_request(&headers, addr, payload.device_name);
+    let outcome = finish_first_factor(&state, user, &metadata).await?;
+
+    Ok(ResponseJson(outcome))
+}
+
+pub async fn start_link(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+) -> Result<ResponseJson<SiopRequestResponse>, AppError> {
+    let user = auth.user;
+
+    let response = create_request(&state, PURPOSE_LINK, Some(&user.id)).await?;
+    Ok(ResponseJson(response))
+}
+
+// Replace the account's DID with the subject of a self-issued ID token
+pub async fn finish_link(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Json(payload): Json<SiopResponseRequest>,
+) -> Result<ResponseJson<UserResponse>, AppError> {
+    let user = auth.user;
+
+    let (nonce, user_id) = take_request(&state, &payload.request_id, PURPOSE_LINK).await?;
+    if user_id.as_deref() != Some(user.id.as_str()) {
+        return Err(AppError::Validation("Invalid or expired request".to_string()));
+    }
+    let did = verify_id_token(&state, &payload.id_token, &nonce).await?;
+
+    let (linked,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE did = ? AND id != ?")
+        .bind(&did)
+        .bind(&user.id)
+        .fetch_one(state.database.pool())
+        .await?;
+    if linked > 0 {
+        return Err(AppError::Validation("DID is linked to another account".to_string()));
+    }
+
+    sqlx::query("UPDATE users SET did = ?, updated_at = ? WHERE id = ?")
+        .bind(&did)
+        .bind(Utc::now())
+        .bind(&user.id)
+        .execute(state.database.pool())
+        .await?;
+
+    let user = find_active_user(&state, &user.id).await?;
+    Ok(ResponseJson(user.into()))
+}
+
+// Static metadata of the self-issued provider Idryos runs for managed DIDs
+pub async fn provider_metadata(State(state): State<AppState>) -> ResponseJson<Value> {
+    ResponseJson(json!({
+        "issuer": SELF_ISSUED_ISSUER,
+        "authorization_endpoint": format!("{}/siop/authorize", state.config.frontend_url),
+        "response_types_supported": ["id_token"],
+        "scopes_supported": ["openid"],
+        "subject_types_supported": ["public"],
+        "subject_syntax_types_supported": ["did:key", "did:web"],
+        "id_token_signing_alg_values_supported": ["EdDSA"],
+        "request_object_signing_alg_values_supported": ["none"],
+    }))
+}
+
+// Called by the first-party frontend once the user has approved the
+// request. Signs a self-issued ID token with the user's managed DID key and
+// returns the redirect carrying it.
+pub async fn authorize(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Json(params): Json<SelfIssuedAuthorizeRequest>,
+) -> Result<ResponseJson<Value>, AppError> {
+    let user = auth.user;
+
+    if params.response_type != "id_token" {
+        return Err(AppError::Validation("response_type must be id_token".to_string()));
+    }
+    if !params.scope.as_deref().unwrap_or_default().split_whitespace().any(|scope| scope == "openid") {
+        return Err(AppError::Validation("scope must include openid".to_string()));
+    }
+    let nonce = params
+        .nonce
+        .as_deref()
+        .ok_or_else(|| AppError::Validation("nonce is required".to_string()))?;
+    // Relying parties are not registered, they are known by their redirect URI
+    if params.client_id != params.redirect_uri {
+        return Err(AppError::Validation("client_id must be the redirect_uri".to_string()));
+    }
+    // The ID token travels in the fragment, so only to an https page or a
+    // wallet listening on loopback
+    let mut redirect_to = Url::parse(&params.redirect_uri)
+        .ok()
+        .filter(|url| url.fragment().is_none())
+        .filter(|url| url.scheme() == "https" || (url.scheme() == "http" && is_loopback(url)))
+        .ok_or_else(|| AppError::Validation("Invalid redirect URI".to_string()))?;
+
+    let did = user
+        .did
+        .as_deref()
+        .ok_or_else(|| AppError::Validation("Account has no DID".to_string()))?;
+    let (verification_method, sealed): (String, String) = sqlx::query_as(
+        "SELECT verification_method, private_key_encrypted FROM managed_did_keys WHERE did = ? AND user_id = ?"
+    )
+    .bind(did)
+    .bind(&user.id)
+    .fetch_optional(state.database.pool())
+    .await?
+    .ok_or_else(|| AppError::Validation("The key of this DID is not managed here".to_string()))?;
+
+    let pkcs8 = decrypt_user_secret(&state.config, &user.id, &managed_key_context(did), &sealed).await?;
+    let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+        .map_err(|_| AppError::Internal("Invalid managed DID key".to_string()))?;
+
+    let now = Utc::now();
+    let header = json!({ "alg": "EdDSA", "typ": "JWT", "kid": verification_method });
+    let claims = json!({
+        "iss": did,
+        "sub": did,
+        "aud": params.client_id,
+        "nonce": nonce,
+        "iat": now.timestamp(),
+        "exp": (now + state.config.default_token_lifetimes().id_token).timestamp(),
+    });
+    let signing_input = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header.to_string()),
+        URL_SAFE_NO_PAD.encode(claims.to_string())
+    );
+    let signature = key_pair.sign(signing_input.as_bytes());
+    let id_token = format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature.as_ref()));
+
+    // Fragment response mode, the default for id_token responses. The
+    // parameters are form encoded like a query, so one is built and moved.
+    let mut response = Url::parse("openid://")
+        .map_err(|_| AppError::Internal("Invalid SIOP response URL".to_string()))?;
+    response.query_pairs_mut().append_pair("id_token", &id_token);
+    if let Some(request_state) = &params.state {
+        response.query_pairs_mut().append_pair("state", request_state);
+    }
+    redirect_to.set_fragment(response.query());
+
+    Ok(ResponseJson(json!({
+        "redirect_to": redirect_to.to_string()
+    })))
+}
+
+async fn create_request(
+    state: &AppState,
+    purpose: &str,
+    user_id: Option<&str>,
+) -> Result<SiopRequestResponse, AppError> {
+    let request_id = Uuid::new_v4().to_string();
+    let nonce = generate_token();
+    let now = Utc::now();
+
+    sqlx::query(
+        r#"
+        INSERT INTO siop_requests (id, nonce, purpose, user_id, expires_at, created_at)
+        VALUES (?, ?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(&request_id)
+    .bind(&nonce)
+    .bind(purpose)
+    .bind(user_id)
+    .bind(now + Duration::seconds(REQUEST_EXPIRY_SECONDS))
+    .bind(now)
+    .execute(state.database.pool())
+    .await?;
+
+    // The wallet returns the token to the frontend page, which posts it back
+    let redirect_uri = relying_party_id(state);
+    let client_metadata = json!({
+        "subject_syntax_types_supported": SUBJECT_SYNTAX_TYPES,
+        "id_token_signing_alg_values_supported": SIGNING_ALGS,
+    });
+
+    let mut request = Url::parse("openid://")
+        .map_err(|_| AppError::Internal("Invalid SIOP request URL".to_string()))?;
+    request
+        .query_pairs_mut()
+        .append_pair("response_type", "id_token")
+        .append_pair("client_id", &redirect_uri)
+        .append_pair("redirect_uri", &redirect_uri)
+        .append_pair("scope", "openid")
+        .append_pair("nonce", &nonce)
+        .append_pair("state", &request_id)
+        .append_pair("response_mode", "fragment")
+        .append_pair("client_metadata", &client_metadata.to_string());
+
+    Ok(SiopRequestResponse {
+        request_id,
+        authorization_request: request.to_string(),
+        expires_in: REQUEST_EXPIRY_SECONDS as u64,
+    })
+}
+
+// Requests are single use, whatever the outcome
+async fn take_request(
+    state: &AppState,
+    request_id: &str,
+    purpose: &str,
+) -> Result<(String, Option<String>), AppError> {
+    sqlx::query_as(
+        "DELETE FROM siop_requests WHERE id = ? AND purpose = ? AND expires_at > ? RETURNING nonce, user_id"
+    )
+    .bind(request_id)
+    .bind(purpose)
+    .bind(Utc::now())
+    .fetch_optional(state.database.pool())
+    .await?
+    .ok_or_else(|| AppError::Validation("Invalid or expired request".to_string()))
+}
+
+// Our client_id when acting as relying party, which is also the redirect URI
+fn relying_party_id(state: &AppState) -> String {
+    format!("{}/siop/callback", state.config.frontend_url.trim_end_matches('/'))
+}
+
+// The unverified `sub` of an ID token
+fn claimed_subject(id_token: &str) -> Option<String> {
+    let claims = id_token.split('.').nth(1)?;
+    let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+    claims.get("sub")?.as_str().map(str::to_string)
+}
+
+// Validate a self-issued ID token (section 11.1) and return its subject DID
+async fn verify_id_token(state: &AppState, id_token: &str, nonce: &str) -> Result<String, AppError> {
+    let invalid = |reason: &str| AppError::Authentication(format!("Invalid ID token: {}", reason));
+
+    let parts: Vec<&str> = id_token.split('.').collect();
+    let [header, claims, signature] = parts.as_slice() else {
+        return Err(invalid("malformed"));
+    };
+    let decode_json = |part: &str| -> Result<Value, AppError> {
+        URL_SAFE_NO_PAD
+            .decode(part)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| invalid("malformed"))
+    };
+    let header = decode_json(header)?;
+    let claims_value = decode_json(claims)?;
+
+    let alg = header.get("alg").and_then(Value::as_str).unwrap_or_default();
+    if !SIGNING_ALGS.contains(&alg) {
+        return Err(invalid("unsupported alg"));
+    }
+
+    // Self-issued: the issuer is the subject, and the subject is a DID
+    let claim = |name: &str| claims_value.get(name).and_then(Value::as_str);
+    let did = claim("sub").ok_or_else(|| invalid("missing sub"))?;
+    if claim("iss") != Some(did) {
+        return Err(invalid("iss must equal sub"));
+    }
+    if !SUBJECT_SYNTAX_TYPES.iter().any(|method| did.starts_with(&format!("{}:", method))) {
+        return Err(invalid("unsupported subject syntax"));
+    }
+
+    let audience = relying_party_id(state);
+    let audience_matches = match claims_value.get("aud") {
+        Some(Value::String(aud)) => *aud == audience,
+        Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience.as_str())),
+        _ => false,
+    };
+    if !audience_matches {
+        return Err(invalid("wrong audience"));
+    }
+    if claim("nonce") != Some(nonce) {
+        return Err(invalid("wrong nonce"));
+    }
+
+    let now = Utc::now().timestamp();
+    let leeway = state.config.jwt_leeway_seconds as i64;
+    let timestamp = |name: &str| claims_value.get(name).and_then(Value::as_i64);
+    if timestamp("exp").filter(|&exp| exp > now - leeway).is_none() {
+        return Err(invalid("expired"));
+    }
+    if timestamp("iat").is_some_and(|iat| iat > now + leeway) {
+        return Err(invalid("issued in the future"));
+    }
+
+    // The signing key must be an authentication key of the subject DID
+    let kid = header
+        .get("kid")
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid("missing kid"))?;
+    if !kid.starts_with('#') && !kid.starts_with(&format!("{}#", did)) {
+        return Err(invalid("kid is not a key of the subject"));
+    }
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| invalid("malformed signature"))?;
+
+    let document = resolve(did).await?;
+    let method = authentication_method(&document, kid)?;
+    let signing_input = &id_token[..id_token.rfind('.').unwrap_or_default()];
+    verify_signature(&method, signing_input.as_bytes(), &signature)?;
+
+    Ok(did.to_string())
+}
@@ -0,0 +1,67 @@
+use std::{path::PathBuf, sync::Arc};
+use uuid::Uuid;
+
+use crate::{
+    config::Config, database::Database, mailer, models::User, password_hash::PasswordHasher, AppContext, AppState,
+};
+
+// Configuration for unit tests: cheap Argon2 parameters and key and outbox
+// directories of their own under the system temp directory
+pub fn config() -> Config {
+    let data_dir = scratch_dir();
+    Config {
+        jwt_secret: "test secret".to_string(),
+        issuer: "https://id.example.com".to_string(),
+        frontend_url: "https://id.example.com".to_string(),
+        user_key_dir: data_dir.join("user-keys").to_string_lossy().into_owned(),
+        mailer: "file".to_string(),
+        mail_from: "Idryos <no-reply@id.example.com>".to_string(),
+        mail_outbox_dir: data_dir.join("outbox").to_string_lossy().into_owned(),
+        webauthn_rp_id: "id.example.com".to_string(),
+        webauthn_origin: "https://id.example.com".to_string(),
+        jwt_leeway_seconds: 60,
+        argon2_memory_kib: 64,
+        argon2_iterations: 1,
+        argon2_parallelism: 1,
+        password_hash_concurrency: 1,
+        ..Default::default()
+    }
+}
+
+// Application state over a fresh in-memory database
+pub async fn state() -> AppState {
+    let config = config();
+    let database = Database::new("sqlite::memory:").await.unwrap();
+    database.migrate().await.unwrap();
+
+    Arc::new(AppContext {
+        mailer: mailer::from_config(&config).unwrap(),
+        password_hasher: PasswordHasher::from_config(&config).unwrap(),
+        database,
+        config,
+    })
+}
+
+// An account without credentials, for rows that reference users
+pub async fn user(state: &AppState, id: &str) -> User {
+    let now = chrono::Utc::now();
+    sqlx::query_as::<_, User>(
+        r#"
+        INSERT INTO users (id, username, email, password_hash, created_at, updated_at)
+        VALUES (?, ?, ?, '', ?, ?)
+        RETURNING *
+        "#
+    )
+    .bind(id)
+    .bind(id)
+    .bind(format!("{}@example.com", id))
+    .bind(now)
+    .bind(now)
+    .fetch_one(state.database.pool())
+    .await
+    .unwrap()
+}
+
+fn scratch_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("idryos-test-{}", Uuid::new_v4()))
+}
@@ -0,0 +1,140 @@
+use axum::{
+    extract::{ConnectInfo, Json, State},
+    http::StatusCode,
+    response::Json as ResponseJson,
+};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use tracing::{info, warn};
+
+use crate::{
+    crypto::{generate_token, hash_token},
+    error::AppError,
+    lockout::throttle_request,
+    mailer::{self, Email},
+    models::{User, UserResponse},
+    AppState,
+};
+
+const VERIFICATION_EXPIRY_HOURS: i64 = 24;
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResendVerificationRequest {
+    pub email: String,
+}
+
+// Issue a verification token for the user's current email and mail the link
+pub async fn send_verification_email(state: &AppState, user: &User) -> Result<(), AppError> {
+    let token = generate_token();
+
+    sqlx::query(
+        r#"
+        INSERT INTO email_verification_tokens (token_hash, user_id, email, expires_at, created_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(hash_token(&token))
+    .bind(&user.id)
+    .bind(&user.email)
+    .bind(Utc::now() + Duration::hours(VERIFICATION_EXPIRY_HOURS))
+    .bind(Utc::now())
+    .execute(state.database.pool())
+    .await?;
+
+    let link = format!("{}/verify-email?token={}", state.config.frontend_url, token);
+    state
+        .mailer
+        .send(Email {
+            to: user.email.clone(),
+            subject: "Verify your email address".to_string(),
+            body: format!(
+                "Hello {},\n\nConfirm your email address by opening this link:\n\n{}\n\n\
+                 The link expires in {} hours. If you did not create an account, ignore this email.\n",
+                user.username, link, VERIFICATION_EXPIRY_HOURS
+            ),
+        })
+        .await
+}
+
+pub async fn verify_email(
+    State(state): State<AppState>,
+    Json(payload): Json<VerifyEmailRequest>,
+) -> Result<ResponseJson<UserResponse>, AppError> {
+    let (user_id, email): (String, String) = sqlx::query_as(
+        "SELECT user_id, email FROM email_verification_tokens WHERE token_hash = ? AND expires_at > ?"
+    )
+    .bind(hash_token(&payload.token))
+    .bind(Utc::now())
+    .fetch_optional(state.database.pool())
+    .await?
+    .ok_or_else(|| AppError::Validation("Invalid or expired verification token".to_string()))?;
+
+    // A token only verifies the address it was sent to
+    let now = Utc::now();
+    let result = sqlx::query("UPDATE users SET email_verified_at = ?, updated_at = ? WHERE id = ? AND email = ?")
+        .bind(now)
+        .bind(now)
+        .bind(&user_id)
+        .bind(&email)
+        .execute(state.database.pool())
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::Validation("Invalid or expired verification token".to_string()));
+    }
+
+    sqlx::query("DELETE FROM email_verification_tokens WHERE user_id = ?")
+        .bind(&user_id)
+        .execute(state.database.pool())
+        .await?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(&user_id)
+        .fetch_one(state.database.pool())
+        .await?;
+
+    Ok(ResponseJson(user.into()))
+}
+
+// Always accepted, so the response does not reveal whether an unverified
+// account exists for the address. Throttled requests for an account are
+// dropped silently for the same reason; only the per-IP limit, which holds
+// whatever the address, is reported.
+pub async fn resend_verification(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<ResendVerificationRequest>,
+) -> Result<StatusCode, AppError> {
+    throttle_request(&state, "verification", addr.ip()).await?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+        .bind(&payload.email)
+        .fetch_optional(state.database.pool())
+        .await?
+        .filter(|user| user.is_active && user.email_verified_at.is_none());
+
+    // Everything past the lookup runs after the response, so an unverified
+    // account takes no longer to answer than an unknown address
+    if let Some(user) = user {
+        tokio::spawn(async move {
+            if let Err(e) = resend_unless_throttled(&state, &user).await {
+                warn!("Verification email for user {} failed: {}", user.id, e);
+            }
+        });
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn resend_unless_throttled(state: &AppState, user: &User) -> Result<(), AppError> {
+    if mailer::is_throttled(state, "email_verification_tokens", &user.id).await? {
+        info!("Verification email for user {} throttled", user.id);
+        return Ok(());
+    }
+    send_verification_email(state, user).await
+}
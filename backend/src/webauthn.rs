@@ -0,0 +1,729 @@
+use axum::{
+    extract::{ConnectInfo, Json, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::Json as ResponseJson,
+};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use ciborium::value::Value as CborValue;
+use ring::{
+    digest::{digest, SHA256},
+    signature::{self, RsaPublicKeyComponents, UnparsedPublicKey},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::{
+    auth::{check_can_sign_in, complete_login},
+    auth_user::FirstPartyUser,
+    crypto::{generate_token, secrets_match},
+    error::AppError,
+    jwt::verify_mfa_token,
+    models::{LoginResponse, User, WebAuthnChallenge, WebAuthnCredential},
+    oauth::find_active_user,
+    sessions::SessionMetadata,
+    AppState,
+};
+
+// Web Authentication Level 2 (https://www.w3.org/TR/webauthn-2/). Only the
+// relying party checks are implemented: attestation is not requested, so
+// attestation statements are not verified.
+const CHALLENGE_EXPIRY_SECONDS: i64 = 300;
+const CEREMONY_TIMEOUT_MS: i64 = CHALLENGE_EXPIRY_SECONDS * 1000;
+
+// COSE algorithm identifiers (RFC 9053)
+const COSE_ALG_ES256: i128 = -7;
+const COSE_ALG_EDDSA: i128 = -8;
+const COSE_ALG_RS256: i128 = -257;
+
+// Authenticator data flags
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+#[derive(Debug, Deserialize)]
+pub struct RegistrationStartRequest {
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegistrationFinishRequest {
+    pub challenge_id: String,
+    pub name: Option<String>,
+    pub credential: RegistrationCredential,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    pub client_data_json: String,
+    pub attestation_object: String,
+    #[serde(default)]
+    pub transports: Vec<String>,
+}
+
+// Without an email or mfa_token the login is username-less: any discoverable
+// passkey for this relying party may answer
+#[derive(Debug, Deserialize)]
+pub struct AuthenticationStartRequest {
+    pub email: Option<String>,
+    pub mfa_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthenticationFinishRequest {
+    pub challenge_id: String,
+    pub credential: AuthenticationCredential,
+    pub mfa_token: Option<String>,
+    pub device_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthenticationCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CeremonyOptionsResponse {
+    pub challenge_id: String,
+    pub options: Value, // Passed to navigator.credentials.create() / get()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameCredentialRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CredentialResponse {
+    pub id: String,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<WebAuthnCredential> for CredentialResponse {
+    fn from(credential: WebAuthnCredential) -> Self {
+        CredentialResponse {
+            id: credential.id,
+            name: credential.name,
+            created_at: credential.created_at,
+            last_used_at: credential.last_used_at,
+        }
+    }
+}
+
+struct ClientData {
+    challenge: String,
+}
+
+struct AuthenticatorData {
+    flags: u8,
+    sign_count: u32,
+    credential_id: Option<Vec<u8>>,
+    public_key: Option<Vec<u8>>, // COSE_Key as sent by the authenticator
+}
+
+pub async fn has_credentials(state: &AppState, user_id: &str) -> Result<bool, AppError> {
+    let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM webauthn_credentials WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_one(state.database.pool())
+        .await?;
+    Ok(count.0 > 0)
+}
+
+pub async fn start_registration(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Json(payload): Json<RegistrationStartRequest>,
+) -> Result<ResponseJson<CeremonyOptionsResponse>, AppError> {
+    let user = auth.user;
+
+    let (challenge_id, challenge) = create_challenge(&state, Some(&user.id), "registration").await?;
+    let existing = find_credentials(&state, &user.id).await?;
+
+    let pub_key_cred_params: Vec<Value> = [COSE_ALG_ES256, COSE_ALG_EDDSA, COSE_ALG_RS256]
+        .iter()
+        .map(|alg| json!({ "type": "public-key", "alg": *alg as i64 }))
+        .collect();
+
+    let options = json!({
+        "challenge": challenge,
+        "rp": { "id": state.config.webauthn_rp_id, "name": state.config.webauthn_rp_name },
+        "user": {
+            "id": URL_SAFE_NO_PAD.encode(user.id.as_bytes()),
+            "name": user.email,
+            "displayName": payload.name.unwrap_or(user.username),
+        },
+        "pubKeyCredParams": pub_key_cred_params,
+        "timeout": CEREMONY_TIMEOUT_MS,
+        "attestation": "none",
+        "authenticatorSelection": { "residentKey": "preferred", "userVerification": "preferred" },
+        "excludeCredentials": credential_descriptors(&existing),
+    });
+
+    Ok(ResponseJson(CeremonyOptionsResponse { challenge_id, options }))
+}
+
+pub async fn finish_registration(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Json(payload): Json<RegistrationFinishRequest>,
+) -> Result<(StatusCode, ResponseJson<CredentialResponse>), AppError> {
+    let user = auth.user;
+
+    let challenge = take_challenge(&state, &payload.challenge_id, "registration").await?;
+    if challenge.user_id.as_deref() != Some(user.id.as_str()) {
+        return Err(AppError::Validation("Challenge was issued to another user".to_string()));
+    }
+
+    let response = &payload.credential.response;
+    let client_data = parse_client_data(&state, &response.client_data_json, "webauthn.create")?;
+    check_challenge(&challenge, &client_data)?;
+
+    let attestation_object = decode(&response.attestation_object)?;
+    let auth_data = parse_attestation_object(&attestation_object)?;
+    let auth_data = parse_authenticator_data(&state, &auth_data)?;
+    if auth_data.flags & FLAG_USER_PRESENT == 0 {
+        return Err(AppError::Validation("User presence is required".to_string()));
+    }
+
+    let (Some(credential_id), Some(public_key)) = (auth_data.credential_id, auth_data.public_key) else {
+        return Err(AppError::Validation("Attested credential data is missing".to_string()));
+    };
+    let credential_id = URL_SAFE_NO_PAD.encode(credential_id);
+    if credential_id != payload.credential.id {
+        return Err(AppError::Validation("Credential ID mismatch".to_string()));
+    }
+
+    // Reject keys we could never verify an assertion with
+    CosePublicKey::parse(&public_key)?;
+
+    let name = payload
+        .name
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| "Passkey".to_string());
+    let transports = serde_json::to_string(&response.transports)
+        .map_err(|e| AppError::Internal(format!("Transport serialization failed: {}", e)))?;
+
+    let credential = WebAuthnCredential {
+        id: Uuid::new_v4().to_string(),
+        user_id: user.id,
+        credential_id,
+        public_key: URL_SAFE_NO_PAD.encode(public_key),
+        sign_count: auth_data.sign_count as i64,
+        name,
+        transports: Some(transports),
+        created_at: Utc::now(),
+        last_used_at: None,
+    };
+
+    sqlx::query(
+        r#"
+        INSERT INTO webauthn_credentials (id, user_id, credential_id, public_key, sign_count, name, transports, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(&credential.id)
+    .bind(&credential.user_id)
+    .bind(&credential.credential_id)
+    .bind(&credential.public_key)
+    .bind(credential.sign_count)
+    .bind(&credential.name)
+    .bind(&credential.transports)
+    .bind(credential.created_at)
+    .execute(state.database.pool())
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::Database(ref db) if db.is_unique_violation() => {
+            AppError::Validation("Credential is already registered".to_string())
+        }
+        e => AppError::Database(e),
+    })?;
+
+    Ok((StatusCode::CREATED, ResponseJson(credential.into())))
+}
+
+pub async fn start_authentication(
+    State(state): State<AppState>,
+    Json(payload): Json<AuthenticationStartRequest>,
+) -> Result<ResponseJson<CeremonyOptionsResponse>, AppError> {
+    let user_id = match (payload.mfa_token.as_deref(), payload.email.as_deref()) {
+        (Some(mfa_token), _) => Some(verify_mfa_token(mfa_token, &state.config)?),
+        (None, Some(email)) => sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+            .bind(email)
+            .fetch_optional(state.database.pool())
+            .await?
+            .map(|user| user.id),
+        (None, None) => None,
+    };
+
+    // Unknown emails get a challenge without credentials rather than an
+    // error, so the endpoint does not reveal which accounts exist
+    let credentials = match &user_id {
+        Some(user_id) => find_credentials(&state, user_id).await?,
+        None => Vec::new(),
+    };
+    let (challenge_id, challenge) = create_challenge(&state, user_id.as_deref(), "authentication").await?;
+
+    let options = json!({
+        "challenge": challenge,
+        "rpId": state.config.webauthn_rp_id,
+        "timeout": CEREMONY_TIMEOUT_MS,
+        "userVerification": if payload.mfa_token.is_some() { "discouraged" } else { "required" },
+        "allowCredentials": credential_descriptors(&credentials),
+    });
+
+    Ok(ResponseJson(CeremonyOptionsResponse { challenge_id, options }))
+}
+
+// Completes a passkey login, or the second factor of a password login when
+// an mfa_token is given. A passkey alone must have verified the user (PIN or
+// biometrics); as a second factor presence is enough.
+pub async fn finish_authentication(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<AuthenticationFinishRequest>,
+) -> Result<ResponseJson<LoginResponse>, AppError> {
+    let challenge = take_challenge(&state, &payload.challenge_id, "authentication").await?;
+
+    let second_factor_for = match payload.mfa_token.as_deref() {
+        Some(mfa_token) => Some(verify_mfa_token(mfa_token, &state.config)?),
+        None => None,
+    };
+
+    let credential = sqlx::query_as::<_, WebAuthnCredential>(
+        "SELECT * FROM webauthn_credentials WHERE credential_id = ?"
+    )
+    .bind(&payload.credential.id)
+    .fetch_optional(state.database.pool())
+    .await?
+    .ok_or_else(|| AppError::Authentication("Unknown credential".to_string()))?;
+
+    let expected_user = second_factor_for.as_deref().or(challenge.user_id.as_deref());
+    if expected_user.is_some_and(|user_id| user_id != credential.user_id) {
+        return Err(AppError::Authentication("Unknown credential".to_string()));
+    }
+
+    let response = &payload.credential.response;
+    if let Some(user_handle) = &response.user_handle {
+        if decode(user_handle)? != credential.user_id.as_bytes() {
+            return Err(AppError::Authentication("User handle mismatch".to_string()));
+        }
+    }
+
+    let client_data_json = decode(&response.client_data_json)?;
+    let client_data = parse_client_data(&state, &response.client_data_json, "webauthn.get")?;
+    check_challenge(&challenge, &client_data)?;
+
+    let raw_auth_data = decode(&response.authenticator_data)?;
+    let auth_data = parse_authenticator_data(&state, &raw_auth_data)?;
+    if auth_data.flags & FLAG_USER_PRESENT == 0 {
+        return Err(AppError::Authentication("User presence is required".to_string()));
+    }
+    if second_factor_for.is_none() && auth_data.flags & FLAG_USER_VERIFIED == 0 {
+        return Err(AppError::Authentication("User verification is required".to_string()));
+    }
+
+    // The signature covers authenticatorData || SHA-256(clientDataJSON)
+    let mut signed = raw_auth_data.clone();
+    signed.extend_from_slice(digest(&SHA256, &client_data_json).as_ref());
+    CosePublicKey::parse(&decode(&credential.public_key)?)?
+        .verify(&signed, &decode(&response.signature)?)?;
+
+    // A counter that does not advance suggests a cloned authenticator.
+    // Authenticators without a counter always report zero.
+    let sign_count = auth_data.sign_count as i64;
+    if sign_count != 0 && sign_count <= credential.sign_count {
+        return Err(AppError::Authentication("Credential counter did not advance".to_string()));
+    }
+
+    sqlx::query("UPDATE webauthn_credentials SET sign_count = ?, last_used_at = ? WHERE id = ?")
+        .bind(sign_count)
+        .bind(Utc::now())
+        .bind(&credential.id)
+        .execute(state.database.pool())
+        .await?;
+
+    let user = find_active_user(&state, &credential.user_id).await?;
+    check_can_sign_in(&user)?;
+    let metadata = SessionMetadata::from_request(&headers, addr, payload.device_name);
+    Ok(ResponseJson(complete_login(&state, user, &metadata).await?))
+}
+
+pub async fn list_credentials(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+) -> Result<ResponseJson<Vec<CredentialResponse>>, AppError> {
+    let credentials = find_credentials(&state, &auth.user.id).await?;
+
+    Ok(ResponseJson(credentials.into_iter().map(Into::into).collect()))
+}
+
+pub async fn rename_credential(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+    Json(payload): Json<RenameCredentialRequest>,
+) -> Result<StatusCode, AppError> {
+    let name = payload.name.trim();
+    if name.is_empty() {
+        return Err(AppError::Validation("Name is required".to_string()));
+    }
+
+    let result = sqlx::query("UPDATE webauthn_credentials SET name = ? WHERE id = ? AND user_id = ?")
+        .bind(name)
+        .bind(&id)
+        .bind(&auth.user.id)
+        .execute(state.database.pool())
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("Credential not found".to_string()));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_credential(
+    State(state): State<AppState>,
+    FirstPartyUser(auth): FirstPartyUser,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let result = sqlx::query("DELETE FROM webauthn_credentials WHERE id = ? AND user_id = ?")
+        .bind(&id)
+        .bind(&auth.user.id)
+        .execute(state.database.pool())
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("Credential not found".to_string()));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn find_credentials(state: &AppState, user_id: &str) -> Result<Vec<WebAuthnCredential>, AppError> {
+    Ok(sqlx::query_as::<_, WebAuthnCredential>(
+        "SELECT * FROM webauthn_credentials WHERE user_id = ? ORDER BY created_at"
+    )
+    .bind(user_id)
+    .fetch_all(state.database.pool())
+    .await?)
+}
+
+fn credential_descriptors(credentials: &[WebAuthnCredential]) -> Vec<Value> {
+    credentials
+        .iter()
+        .map(|credential| {
+            let transports: Vec<String> = credential
+                .transports
+                .as_deref()
+                .and_then(|raw| serde_json::from_str(raw).ok())
+                .unwrap_or_default();
+            json!({ "type": "public-key", "id": credential.credential_id, "transports": transports })
+        })
+        .collect()
+}
+
+async fn create_challenge(
+    state: &AppState,
+    user_id: Option<&str>,
+    ceremony: &str,
+) -> Result<(String, String), AppError> {
+    let id = Uuid::new_v4().to_string();
+    let challenge = generate_token();
+
+    sqlx::query(
+        r#"
+        INSERT INTO webauthn_challenges (id, user_id, challenge, ceremony, expires_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(&id)
+    .bind(user_id)
+    .bind(&challenge)
+    .bind(ceremony)
+    .bind(Utc::now() + Duration::seconds(CHALLENGE_EXPIRY_SECONDS))
+    .execute(state.database.pool())
+    .await?;
+
+    Ok((id, challenge))
+}
+
+// Challenges are deleted on first use, whether the ceremony succeeds or not
+async fn take_challenge(state: &AppState, id: &str, ceremony: &str) -> Result<WebAuthnChallenge, AppError> {
+    let challenge = sqlx::query_as::<_, WebAuthnChallenge>(
+        "SELECT * FROM webauthn_challenges WHERE id = ? AND ceremony = ?"
+    )
+    .bind(id)
+    .bind(ceremony)
+    .fetch_optional(state.database.pool())
+    .await?
+    .ok_or_else(|| AppError::Validation("Unknown challenge".to_string()))?;
+
+    sqlx::query("DELETE FROM webauthn_challenges WHERE id = ?")
+        .bind(id)
+        .execute(state.database.pool())
+        .await?;
+
+    if challenge.expires_at < Utc::now() {
+        return Err(AppError::Validation("Challenge expired".to_string()));
+    }
+
+    Ok(challenge)
+}
+
+fn check_challenge(challenge: &WebAuthnChallenge, client_data: &ClientData) -> Result<(), AppError> {
+    if !secrets_match(&challenge.challenge, &client_data.challenge) {
+        return Err(AppError::Validation("Challenge mismatch".to_string()));
+    }
+    Ok(())
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, AppError> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|_| AppError::Validation("Invalid base64url value".to_string()))
+}
+
+// Checks type and origin of the collected client data (WebAuthn, section 7.1)
+fn parse_client_data(state: &AppState, encoded: &str, expected_type: &str) -> Result<ClientData, AppError> {
+    let client_data: Value = serde_json::from_slice(&decode(encoded)?)
+        .map_err(|_| AppError::Validation("Invalid clientDataJSON".to_string()))?;
+
+    if client_data.get("type").and_then(Value::as_str) != Some(expected_type) {
+        return Err(AppError::Validation("Unexpected ceremony type".to_string()));
+    }
+    if client_data.get("origin").and_then(Value::as_str) != Some(state.config.webauthn_origin.as_str()) {
+        return Err(AppError::Validation("Unexpected origin".to_string()));
+    }
+
+    let challenge = client_data
+        .get("challenge")
+        .and_then(Value::as_str)
+        .ok_or_else(|| AppError::Validation("Missing challenge".to_string()))?;
+
+    Ok(ClientData { challenge: challenge.trim_end_matches('=').to_string() })
+}
+
+// Returns the authenticator data from a CBOR attestation object
+fn parse_attestation_object(bytes: &[u8]) -> Result<Vec<u8>, AppError> {
+    let value: CborValue = ciborium::de::from_reader(bytes)
+        .map_err(|_| AppError::Validation("Invalid attestation object".to_string()))?;
+
+    cbor_map_get(&value, &CborValue::Text("authData".to_string()))
+        .and_then(CborValue::as_bytes)
+        .cloned()
+        .ok_or_else(|| AppError::Validation("Attestation object has no authData".to_string()))
+}
+
+// Authenticator data layout (WebAuthn, section 6.1): rpIdHash (32), flags (1),
+// signCount (4), then attested credential data when the AT flag is set
+fn parse_authenticator_data(state: &AppState, bytes: &[u8]) -> Result<AuthenticatorData, AppError> {
+    let invalid = || AppError::Validation("Invalid authenticator data".to_string());
+    if bytes.len() < 37 {
+        return Err(invalid());
+    }
+
+    let rp_id_hash = digest(&SHA256, state.config.webauthn_rp_id.as_bytes());
+    if &bytes[..32] != rp_id_hash.as_ref() {
+        return Err(AppError::Validation("Credential is scoped to another relying party".to_string()));
+    }
+
+    let flags = bytes[32];
+    let sign_count = u32::from_be_bytes([bytes[33], bytes[34], bytes[35], bytes[36]]);
+
+    let mut credential_id = None;
+    let mut public_key = None;
+    if flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0 {
+        // aaguid (16), credentialIdLength (2), credentialId, credentialPublicKey
+        let rest = bytes.get(37 + 16..).ok_or_else(invalid)?;
+        let length = u16::from_be_bytes([*rest.first().ok_or_else(invalid)?, *rest.get(1).ok_or_else(invalid)?]) as usize;
+        let id = rest.get(2..2 + length).ok_or_else(invalid)?;
+
+        // The key is a single CBOR item, possibly followed by extensions
+        let mut key_bytes = &rest[2 + length..];
+        let before = key_bytes.len();
+        let _: CborValue = ciborium::de::from_reader(&mut key_bytes).map_err(|_| invalid())?;
+        let consumed = before - key_bytes.len();
+
+        credential_id = Some(id.to_vec());
+        public_key = Some(rest[2 + length..2 + length + consumed].to_vec());
+    }
+
+    Ok(AuthenticatorData { flags, sign_count, credential_id, public_key })
+}
+
+fn cbor_map_get<'a>(map: &'a CborValue, key: &CborValue) -> Option<&'a CborValue> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v)
+}
+
+// The credential public key formats we can verify assertions with
+enum CosePublicKey {
+    Es256(Vec<u8>),          // Uncompressed P-256 point
+    EdDsa(Vec<u8>),          // Ed25519 public key
+    Rs256(Vec<u8>, Vec<u8>), // RSA modulus and exponent
+}
+
+impl CosePublicKey {
+    fn parse(bytes: &[u8]) -> Result<Self, AppError> {
+        let unsupported = || AppError::Validation("Unsupported credential public key".to_string());
+        let key: CborValue = ciborium::de::from_reader(bytes).map_err(|_| unsupported())?;
+
+        let int = |label: i64| cbor_map_get(&key, &CborValue::Integer(label.into()));
+        let bytes_at = |label: i64| int(label).and_then(CborValue::as_bytes).cloned().ok_or_else(unsupported);
+        let alg = int(3)
+            .and_then(CborValue::as_integer)
+            .map(i128::from)
+            .ok_or_else(unsupported)?;
+
+        match alg {
+            COSE_ALG_ES256 => {
+                let (x, y) = (bytes_at(-2)?, bytes_at(-3)?);
+                if x.len() != 32 || y.len() != 32 {
+                    return Err(unsupported());
+                }
+                let mut point = vec![0x04];
+                point.extend_from_slice(&x);
+                point.extend_from_slice(&y);
+                Ok(CosePublicKey::Es256(point))
+            }
+            COSE_ALG_EDDSA => Ok(CosePublicKey::EdDsa(bytes_at(-2)?)),
+            COSE_ALG_RS256 => Ok(CosePublicKey::Rs256(bytes_at(-1)?, bytes_at(-2)?)),
+            _ => Err(unsupported()),
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature_bytes: &[u8]) -> Result<(), AppError> {
+        let result = match self {
+            CosePublicKey::Es256(point) => UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point)
+                .verify(message, signature_bytes),
+            CosePublicKey::EdDsa(key) => UnparsedPublicKey::new(&signature::ED25519, key)
+                .verify(message, signature_bytes),
+            CosePublicKey::Rs256(n, e) => RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature_bytes),
+        };
+        result.map_err(|_| AppError::Authentication("Invalid signature".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
+
+    fn cbor(value: &CborValue) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(value, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn cose_key(entries: Vec<(i64, CborValue)>) -> Vec<u8> {
+        cbor(&CborValue::Map(entries.into_iter().map(|(label, value)| (CborValue::Integer(label.into()), value)).collect()))
+    }
+
+    fn ed25519_cose_key(public_key: &[u8]) -> Vec<u8> {
+        cose_key(vec![
+            (1, CborValue::Integer(1.into())),
+            (3, CborValue::Integer((-8).into())),
+            (-1, CborValue::Integer(6.into())),
+            (-2, CborValue::Bytes(public_key.to_vec())),
+        ])
+    }
+
+    #[test]
+    fn verifies_with_ed25519_cose_keys() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let key = CosePublicKey::parse(&ed25519_cose_key(key_pair.public_key().as_ref())).unwrap();
+
+        let signature = key_pair.sign(b"message");
+        assert!(key.verify(b"message", signature.as_ref()).is_ok());
+        assert!(key.verify(b"other message", signature.as_ref()).is_err());
+    }
+
+    #[test]
+    fn verifies_with_es256_cose_keys() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+        let point = key_pair.public_key().as_ref();
+
+        let key = CosePublicKey::parse(&cose_key(vec![
+            (1, CborValue::Integer(2.into())),
+            (3, CborValue::Integer((-7).into())),
+            (-1, CborValue::Integer(1.into())),
+            (-2, CborValue::Bytes(point[1..33].to_vec())),
+            (-3, CborValue::Bytes(point[33..].to_vec())),
+        ]))
+        .unwrap();
+
+        let signature = key_pair.sign(&rng, b"message").unwrap();
+        assert!(key.verify(b"message", signature.as_ref()).is_ok());
+        assert!(key.verify(b"other message", signature.as_ref()).is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_cose_keys() {
+        let es384 = cose_key(vec![(1, CborValue::Integer(2.into())), (3, CborValue::Integer((-35).into()))]);
+        assert!(CosePublicKey::parse(&es384).is_err());
+        assert!(CosePublicKey::parse(&cose_key(vec![(3, CborValue::Integer((-8).into()))])).is_err());
+        assert!(CosePublicKey::parse(b"not cbor").is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_attested_credential_data() {
+        let state = crate::test_support::state().await;
+        let public_key = ed25519_cose_key(&[9u8; 32]);
+        let extensions = cbor(&CborValue::Map(vec![(CborValue::Text("credProps".to_string()), CborValue::Bool(true))]));
+
+        let mut auth_data = digest(&SHA256, state.config.webauthn_rp_id.as_bytes()).as_ref().to_vec();
+        auth_data.push(FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL_DATA | 0x80);
+        auth_data.extend_from_slice(&7u32.to_be_bytes());
+        auth_data.extend_from_slice(&[0u8; 16]);
+        auth_data.extend_from_slice(&3u16.to_be_bytes());
+        auth_data.extend_from_slice(b"abc");
+        auth_data.extend_from_slice(&public_key);
+        auth_data.extend_from_slice(&extensions);
+
+        let attestation_object = cbor(&CborValue::Map(vec![
+            (CborValue::Text("fmt".to_string()), CborValue::Text("none".to_string())),
+            (CborValue::Text("attStmt".to_string()), CborValue::Map(Vec::new())),
+            (CborValue::Text("authData".to_string()), CborValue::Bytes(auth_data.clone())),
+        ]));
+        assert_eq!(parse_attestation_object(&attestation_object).unwrap(), auth_data);
+
+        let parsed = parse_authenticator_data(&state, &auth_data).unwrap();
+        assert_eq!(parsed.sign_count, 7);
+        assert_eq!(parsed.credential_id.as_deref(), Some(&b"abc"[..]));
+        assert_eq!(parsed.public_key, Some(public_key));
+
+        auth_data[0] ^= 1;
+        assert!(parse_authenticator_data(&state, &auth_data).is_err());
+        assert!(parse_authenticator_data(&state, &auth_data[..36]).is_err());
+    }
+}